@@ -1,5 +1,7 @@
 use std::path;
 
+use common::proto::handshake;
+
 /// Command-line arguments for the client.
 #[derive(clap::Parser)]
 pub struct ClientArgs {
@@ -8,11 +10,48 @@ pub struct ClientArgs {
     #[clap(flatten)]
     pub common: common::cli::Args,
 
+    /// How to connect to the server, a WebSocket server listens on its own address.
+    #[clap(long, value_enum, default_value_t = Transport::Tcp)]
+    pub transport: Transport,
+
+    /// Ping the server every this many seconds. Server's idle timeout is respected if it's shorter.
+    #[clap(long, default_value_t = 30)]
+    pub keepalive: u64,
+
+    /// Consider the connection dead if the server doesn't answer a keepalive ping in this many seconds.
+    #[clap(long, default_value_t = 10)]
+    pub keepalive_timeout: u64,
+
+    #[clap(flatten)]
+    pub limits: LimitsArgs,
+
     #[cfg(feature = "mtls")]
     #[clap(flatten)]
     pub mtls: MtlsArgs,
 }
 
+/// Limits on frames received from the server. The connection is closed if the server sends a
+/// larger one.
+#[derive(clap::Parser, Debug, Clone)]
+pub struct LimitsArgs {
+    /// Maximum size of a frame from the server other than a downloaded file chunk in bytes.
+    #[clap(long, default_value_t = handshake::DEFAULT_MAX_CONTROL_FRAME_SIZE)]
+    pub max_control_frame_size: u64,
+
+    /// Maximum size of a frame carrying a chunk of a downloaded file in bytes.
+    #[clap(long, default_value_t = handshake::DEFAULT_MAX_CHUNK_FRAME_SIZE)]
+    pub max_chunk_frame_size: u64,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Frames are sent directly over the connection.
+    Tcp,
+    /// Frames are sent in binary WebSocket messages.
+    #[cfg(feature = "ws")]
+    Ws,
+}
+
 #[cfg(feature = "mtls")]
 #[derive(clap::Parser)]
 pub struct MtlsArgs {
@@ -20,15 +59,19 @@ pub struct MtlsArgs {
     #[clap(long, default_value = "localhost")]
     pub cert_domain: String,
 
-    /// Path to the client's certificate.
+    /// Path to the client's certificate chain in PEM or a PKCS#12 bundle with its key.
     #[clap(long, default_value = "../ssl/client1.crt")]
     pub cert: path::PathBuf,
 
-    /// Path to the client's private key.
+    /// Path to the client's private key in PEM, unused with a PKCS#12 bundle.
     #[clap(long, default_value = "../ssl/client1.key")]
     pub key: path::PathBuf,
 
     /// Path to the CA certificate.
     #[clap(long, default_value = "../ssl/ca.crt")]
     pub ca_cert: path::PathBuf,
+
+    /// Connect without TLS, e.g. to a plaintext listener of the server.
+    #[clap(long)]
+    pub plaintext: bool,
 }
@@ -1,12 +1,52 @@
 use std::path;
 
-#[derive(Debug)]
+/// How many messages `.history` fetches if the user doesn't say.
+const DEFAULT_HISTORY_LEN: u32 = 20;
+
+#[derive(Debug, PartialEq)]
 pub enum Command {
     File(path::PathBuf),
     Image(path::PathBuf),
+    /// Continue an upload that was interrupted, its ID and path to the file.
+    Resume(String, path::PathBuf),
+    /// Download file of a message, its ID and optionally where to save it.
+    Get(String, Option<path::PathBuf>),
+    /// Show messages, how many, where in the history, optionally only from which user and only in
+    /// which room.
+    History(u32, HistoryAnchor, Option<String>, Option<String>),
     Message(String),
+    /// Text message only for the user with the nickname.
+    Direct(String, String),
+    /// Start receiving messages sent to the room.
+    Join(String),
+    /// Stop receiving messages sent to the room.
+    Leave(String),
+    /// Send a text message, file or image to the room instead of to everyone.
+    InRoom(String, Box<Command>),
+    /// Send a text message, file or image as a reply to the message with the ID.
+    Reply(String, Box<Command>),
+    /// Replace text of a message the user sent, its ID and the new text.
+    Edit(String, String),
+    /// Remove a message the user sent by its ID.
+    Retract(String),
     AnnounceNickname(String),
+    /// Show who is online, idle or offline.
+    Who,
+    /// Measure round-trip time to the server.
+    Ping,
     Quit,
+    /// Command was given wrong arguments, how to use it.
+    Usage(&'static str),
+}
+
+/// Which messages `.history` shows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoryAnchor {
+    Latest,
+    /// Messages sent before the one with the ID.
+    Before(String),
+    /// Messages sent after the one with the ID.
+    After(String),
 }
 
 impl<T> From<T> for Command
@@ -20,6 +60,14 @@ where
             return Self::Quit;
         }
 
+        if s == ".ping" {
+            return Self::Ping;
+        }
+
+        if s == ".who" {
+            return Self::Who;
+        }
+
         if let Some(suffix) = s.strip_prefix(".file ") {
             return Self::File(path::PathBuf::from(suffix));
         }
@@ -28,6 +76,71 @@ where
             return Self::Image(path::PathBuf::from(suffix));
         }
 
+        if let Some(suffix) = s.strip_prefix(".resume ") {
+            if let Some((upload, filepath)) = suffix.split_once(' ') {
+                return Self::Resume(upload.to_string(), path::PathBuf::from(filepath));
+            }
+        }
+
+        if let Some(suffix) = s.strip_prefix(".get ") {
+            return match suffix.split_once(' ') {
+                Some((message, dest)) => {
+                    Self::Get(message.to_string(), Some(path::PathBuf::from(dest)))
+                }
+                None => Self::Get(suffix.to_string(), None),
+            };
+        }
+
+        if s == ".history" || s.starts_with(".history ") {
+            return parse_history(&s[".history".len()..]);
+        }
+
+        if let Some(suffix) = s.strip_prefix(".msg ") {
+            return match suffix.split_once(' ') {
+                Some((nickname, text)) => Self::Direct(nickname.to_string(), text.to_string()),
+                None => Self::Usage(".msg <nickname> <text>"),
+            };
+        }
+
+        if let Some(suffix) = s.strip_prefix(".edit ") {
+            return match suffix.split_once(' ') {
+                Some((message, text)) => Self::Edit(message.to_string(), text.to_string()),
+                None => Self::Usage(".edit <message-id> <text>"),
+            };
+        }
+
+        if let Some(message) = s.strip_prefix(".retract ") {
+            return Self::Retract(message.to_string());
+        }
+
+        if let Some(suffix) = s.strip_prefix(".reply ") {
+            let Some((message, command)) = suffix.split_once(' ') else {
+                return Self::Usage(".reply <message-id> <text|.file|.image>");
+            };
+
+            return match Self::from(command) {
+                usage @ Self::Usage(_) => usage,
+                command => Self::Reply(message.to_string(), Box::new(command)),
+            };
+        }
+
+        if let Some(room) = s.strip_prefix(".join ") {
+            return Self::Join(room_name(room));
+        }
+
+        if let Some(room) = s.strip_prefix(".leave ") {
+            return Self::Leave(room_name(room));
+        }
+
+        if let Some(suffix) = s.strip_prefix('#') {
+            if let Some((room, command)) = suffix.split_once(' ') {
+                return match Self::from(command) {
+                    usage @ Self::Usage(_) => usage,
+                    command => Self::InRoom(room.to_string(), Box::new(command)),
+                };
+            }
+        }
+
         if let Some(nickname) = s.strip_prefix(".nick ") {
             return Self::AnnounceNickname(nickname.to_string());
         }
@@ -35,3 +148,125 @@ where
         Self::Message(s.to_string())
     }
 }
+
+const HISTORY_USAGE: &str = ".history [#room] [n] [from <user>] [before|after <message-id>]";
+
+/// Nicknames can be numbers too, so the user is only taken after `from`.
+fn parse_history(args: &str) -> Command {
+    let mut args = args.split_whitespace().peekable();
+
+    let room = args.next_if(|arg| arg.starts_with('#')).map(room_name);
+    let len = args
+        .next_if(|arg| arg.parse::<u32>().is_ok())
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_HISTORY_LEN);
+
+    let mut user = None;
+    let mut anchor = HistoryAnchor::Latest;
+    while let Some(arg) = args.next() {
+        let Some(value) = args.next().map(ToString::to_string) else {
+            return Command::Usage(HISTORY_USAGE);
+        };
+
+        match arg {
+            "from" if user.is_none() => user = Some(value),
+            "before" if anchor == HistoryAnchor::Latest => anchor = HistoryAnchor::Before(value),
+            "after" if anchor == HistoryAnchor::Latest => anchor = HistoryAnchor::After(value),
+            _ => return Command::Usage(HISTORY_USAGE),
+        }
+    }
+
+    Command::History(len, anchor, user, room)
+}
+
+/// Rooms are written as `#room`, the server knows them without the `#`.
+fn room_name(room: &str) -> String {
+    room.trim().trim_start_matches('#').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(len: u32, anchor: HistoryAnchor, user: Option<&str>, room: Option<&str>) -> Command {
+        Command::History(
+            len,
+            anchor,
+            user.map(ToString::to_string),
+            room.map(ToString::to_string),
+        )
+    }
+
+    #[test]
+    fn test_parse_usage() {
+        assert_eq!(
+            Command::from(".msg bob"),
+            Command::Usage(".msg <nickname> <text>")
+        );
+        assert_eq!(
+            Command::from(".edit 67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            Command::Usage(".edit <message-id> <text>")
+        );
+        assert_eq!(
+            Command::from(".reply 67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            Command::Usage(".reply <message-id> <text|.file|.image>")
+        );
+        assert_eq!(
+            Command::from("#ops .msg bob"),
+            Command::Usage(".msg <nickname> <text>")
+        );
+        assert_eq!(
+            Command::from(".msg bob hi there"),
+            Command::Direct("bob".to_string(), "hi there".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_history() {
+        let id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+        assert_eq!(
+            Command::from(".history"),
+            history(DEFAULT_HISTORY_LEN, HistoryAnchor::Latest, None, None)
+        );
+        assert_eq!(
+            Command::from(".history 5"),
+            history(5, HistoryAnchor::Latest, None, None)
+        );
+        assert_eq!(
+            Command::from(".history from 5"),
+            history(DEFAULT_HISTORY_LEN, HistoryAnchor::Latest, Some("5"), None)
+        );
+        assert_eq!(
+            Command::from(&format!(".history #ops 10 from bob before {id}")),
+            history(
+                10,
+                HistoryAnchor::Before(id.to_string()),
+                Some("bob"),
+                Some("ops")
+            )
+        );
+        assert_eq!(
+            Command::from(&format!(".history after {id} from bob")),
+            history(
+                DEFAULT_HISTORY_LEN,
+                HistoryAnchor::After(id.to_string()),
+                Some("bob"),
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_history_usage() {
+        for line in [
+            ".history bob",
+            ".history 5 from",
+            ".history before",
+            ".history before a after b",
+            ".history from a from b",
+        ] {
+            assert_eq!(Command::from(line), Command::Usage(HISTORY_USAGE), "{line}");
+        }
+    }
+}
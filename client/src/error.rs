@@ -1,8 +1,10 @@
+use common::proto::{request::RequestId, response};
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("{0}")]
+    #[error("{0:#}")]
     Soft(anyhow::Error),
-    #[error("{0}")]
+    #[error("{0:#}")]
     Hard(anyhow::Error),
 }
 
@@ -14,4 +16,19 @@ impl Error {
     pub fn hard<E: Into<anyhow::Error>>(error: E) -> Self {
         Self::Hard(error.into())
     }
+
+    /// Server answered request `id` with `error`.
+    pub fn server(id: RequestId, error: response::Error) -> Self {
+        Self::Soft(anyhow::Error::new(error).context(format!("Request {id} failed")))
+    }
+
+    /// Whether the server said that sending the request again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Soft(err) => err
+                .downcast_ref::<response::Error>()
+                .is_some_and(|err| err.retryable),
+            Self::Hard(_) => false,
+        }
+    }
 }
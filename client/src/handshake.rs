@@ -0,0 +1,35 @@
+use common::proto::{self, handshake};
+
+/// Capabilities this client implements.
+pub fn supported_capabilities() -> handshake::Capabilities {
+    [
+        handshake::Capability::Streaming,
+        handshake::Capability::Push,
+        handshake::Capability::ResumableUploads,
+        handshake::Capability::Downloads,
+        handshake::Capability::History,
+        handshake::Capability::DirectMessages,
+        handshake::Capability::Rooms,
+        handshake::Capability::Edits,
+        handshake::Capability::Replies,
+        handshake::Capability::Presence,
+    ]
+    .into()
+}
+
+/// Introduce the client to the server. Must be called before sending any other message.
+/// Server's reply may take up to `max_frame_size` bytes.
+pub async fn handshake<S>(conn: &mut S, max_frame_size: u64) -> anyhow::Result<handshake::Welcome>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    proto::Payload::new(handshake::Hello::new(supported_capabilities()))
+        .write_to(conn)
+        .await?;
+
+    let reply = proto::Payload::<handshake::Reply>::read_from(conn, max_frame_size)
+        .await?
+        .into_inner();
+
+    Result::from(reply).map_err(|err| anyhow::Error::new(err).context("Server refused handshake"))
+}
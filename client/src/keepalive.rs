@@ -0,0 +1,47 @@
+use std::time;
+
+use common::proto;
+
+use crate::{Error, Session};
+
+/// Pings the server every `interval` so that it doesn't close the connection as idle.
+/// Fails if the server doesn't respond within `timeout`, the connection is most likely dead.
+pub async fn keepalive(
+    session: Session,
+    interval: time::Duration,
+    timeout: time::Duration,
+) -> Result<(), Error> {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // First tick completes immediately, there's no need to ping right after connecting.
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let rtt = tokio::time::timeout(timeout, ping(&session))
+            .await
+            .map_err(|_| {
+                Error::Hard(anyhow::anyhow!(
+                    "Server didn't respond to ping in {timeout:?}"
+                ))
+            })??;
+
+        tracing::debug!("Keepalive ping took {rtt:?}");
+    }
+}
+
+/// Sends a ping and returns the round-trip time.
+pub async fn ping(session: &Session) -> Result<time::Duration, Error> {
+    let start = tokio::time::Instant::now();
+    let request = session.request(proto::request::Message::Ping).await?;
+    let id = request.id();
+
+    match request.response().await? {
+        proto::response::Message::Pong => Ok(start.elapsed()),
+        proto::response::Message::Err(err) => Err(Error::server(id, err)),
+        message => Err(Error::Hard(anyhow::anyhow!(
+            "Unexpected response to request {id}: {message:?}"
+        ))),
+    }
+}
@@ -1,8 +1,9 @@
 use std::io;
 
 use clap::Parser;
+use tokio::io::AsyncBufReadExt;
 
-use common::proto;
+use common::{cli::Address, proto};
 
 mod args;
 use args::ClientArgs;
@@ -16,9 +17,25 @@ pub(crate) use error::Error;
 mod send_file;
 pub(crate) use send_file::send_stream_file;
 
+mod receive_file;
+pub(crate) use receive_file::receive_download;
+
 mod send_command;
 pub(crate) use send_command::handle_command_should_exit;
 
+mod handshake;
+pub(crate) use handshake::handshake;
+
+mod session;
+pub(crate) use session::Session;
+
+mod keepalive;
+
+mod retry;
+
+/// Commands whose requests haven't completed yet.
+pub(crate) type CommandTasks = tokio::task::JoinSet<Result<(), Error>>;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     common::tracing::init()?;
@@ -28,13 +45,49 @@ async fn main() -> anyhow::Result<()> {
 
     tracing::info!("Connected to {}", args.common.server_address);
 
-    let announce_nick_cmd = Command::AnnounceNickname(args.nickname);
-    // For some reason using `anyhow::Result::Ok(announce_nick_cmd)` doesn't work - Rust cannot infer the error type E.
-    let iter_cmds = std::iter::once(Result::<_, anyhow::Error>::Ok(announce_nick_cmd));
-    let iter_cmds = iter_cmds.chain(read_commands(io::stdin().lock()));
-
-    for cmd in iter_cmds {
-        match handle_command_should_exit(&mut conn, cmd).await {
+    let welcome = handshake(&mut conn, args.limits.max_control_frame_size).await?;
+    tracing::info!(
+        "Server speaks protocol {} with capabilities {:?}",
+        welcome.version,
+        welcome.capabilities
+    );
+
+    let (session, mut connection_task) = Session::start(conn, args.limits.clone());
+
+    let mut keepalive_task = tokio::spawn(keepalive::keepalive(
+        session.clone(),
+        keepalive_interval(&args, &welcome.limits),
+        std::time::Duration::from_secs(args.keepalive_timeout),
+    ));
+
+    let mut tasks = CommandTasks::new();
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    let mut announce_nick_cmd = Some(Command::AnnounceNickname(args.nickname));
+
+    loop {
+        let cmd = match announce_nick_cmd.take() {
+            Some(cmd) => Ok(cmd),
+            None => tokio::select! {
+                cmd = read_command(&mut lines) => match cmd {
+                    Some(cmd) => cmd,
+                    None => break,
+                },
+                Some(result) = tasks.join_next() => {
+                    check_command_result(result)?;
+                    continue;
+                }
+                result = &mut keepalive_task => {
+                    check_command_result(result)?;
+                    anyhow::bail!("Keepalive stopped unexpectedly");
+                }
+                result = &mut connection_task => {
+                    result??;
+                    anyhow::bail!("Connection closed");
+                }
+            },
+        };
+
+        match handle_command_should_exit(&session, cmd, &welcome, &mut tasks).await {
             Ok(true) => {
                 tracing::info!("Exiting...");
                 break;
@@ -43,65 +96,143 @@ async fn main() -> anyhow::Result<()> {
                 tracing::info!("Command sent");
             }
             Err(Error::Soft(err)) => {
-                tracing::warn!("Non-fatal error: {err}");
-                continue;
+                tracing::warn!("Non-fatal error: {err:#}");
             }
             Err(Error::Hard(err)) => {
-                tracing::error!("Exiting due to: {err}");
+                tracing::error!("Exiting due to: {err:#}");
                 return Err(err);
             }
         }
 
-        tracing::info!("Waiting for response...");
-        let response = proto::Payload::<proto::response::Message>::read_from(&mut conn)
-            .await
-            .map_err(Error::hard)?
-            .into_inner();
-
-        match response {
-            proto::response::Message::Ok => {
-                tracing::info!("Request was successful");
-            }
-            proto::response::Message::Err(error) => {
-                tracing::error!("Server responded with an error: {error}");
-            }
+        while let Some(result) = tasks.try_join_next() {
+            check_command_result(result)?;
         }
     }
 
-    use tokio::io::AsyncWriteExt;
-    conn.shutdown().await?;
+    tracing::info!("Waiting for {} unfinished requests...", tasks.len());
+    while let Some(result) = tasks.join_next().await {
+        check_command_result(result)?;
+    }
+
+    // Connection is closed once the last session handle is dropped.
+    keepalive_task.abort();
+    let _ = keepalive_task.await;
+    drop(session);
+    connection_task.await??;
 
     Ok(())
 }
 
-fn read_commands<R: io::BufRead>(reader: R) -> impl Iterator<Item = anyhow::Result<Command>> {
-    let lines = io::BufRead::lines(reader);
+fn keepalive_interval(args: &ClientArgs, limits: &proto::handshake::Limits) -> std::time::Duration {
+    let interval = match limits.idle_timeout_secs {
+        // Leave enough time for the ping to arrive.
+        Some(idle_timeout) => args.keepalive.min(idle_timeout / 2),
+        None => args.keepalive,
+    };
+
+    std::time::Duration::from_secs(interval.max(1))
+}
+
+async fn read_command<R>(lines: &mut tokio::io::Lines<R>) -> Option<anyhow::Result<Command>>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let line = lines.next_line().await.transpose()?;
 
-    lines.map(|line| Ok(Command::from(line?)))
+    Some(line.map(Command::from).map_err(anyhow::Error::from))
+}
+
+fn check_command_result(
+    result: Result<Result<(), Error>, tokio::task::JoinError>,
+) -> anyhow::Result<()> {
+    match result {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(Error::Soft(err))) => {
+            tracing::warn!("Non-fatal error: {err:#}");
+            Ok(())
+        }
+        Ok(Err(Error::Hard(err))) => {
+            tracing::error!("Exiting due to: {err:#}");
+            Err(err)
+        }
+        Err(err) => Err(err.into()),
+    }
 }
 
 async fn create_connection(
     args: &ClientArgs,
-) -> anyhow::Result<impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin> {
-    let conn = tokio::net::TcpStream::connect(args.common.server_address).await?;
+) -> anyhow::Result<impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static> {
+    let conn = match &args.common.server_address {
+        Address::Tcp(address) => {
+            tokio_util::either::Either::Left(connect_tcp(args, *address).await?)
+        }
+        // Server identifies local clients by their user, they don't use TLS
+        Address::Unix(path) => tokio_util::either::Either::Right(connect_unix(path).await?),
+    };
+
+    #[cfg(feature = "ws")]
+    let conn = match args.transport {
+        args::Transport::Tcp => tokio_util::either::Either::Left(conn),
+        args::Transport::Ws => {
+            let (conn, _) = common::ws::client_async(ws_url(args), conn).await?;
+            tokio_util::either::Either::Right(common::ws::WsStream::new(conn))
+        }
+    };
+
+    Ok(conn)
+}
+
+#[cfg_attr(not(feature = "mtls"), allow(unused_variables))]
+async fn connect_tcp(
+    args: &ClientArgs,
+    address: std::net::SocketAddr,
+) -> anyhow::Result<impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static> {
+    let conn = tokio::net::TcpStream::connect(address).await?;
 
     #[cfg(feature = "mtls")]
-    let conn = {
+    let conn = if args.mtls.plaintext {
+        tokio_util::either::Either::Left(conn)
+    } else {
         let connector = create_connector(args)?;
         let domain = rustls_pki_types::ServerName::try_from(args.mtls.cert_domain.clone())
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?
             .to_owned();
 
-        connector.connect(domain, conn).await?
+        tokio_util::either::Either::Right(connector.connect(domain, conn).await?)
     };
 
     Ok(conn)
 }
 
+#[cfg(unix)]
+async fn connect_unix(path: &std::path::Path) -> anyhow::Result<tokio::net::UnixStream> {
+    Ok(tokio::net::UnixStream::connect(path).await?)
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_: &std::path::Path) -> anyhow::Result<tokio::net::TcpStream> {
+    anyhow::bail!("Unix domain sockets aren't supported on this platform")
+}
+
+#[cfg(feature = "ws")]
+fn ws_url(args: &ClientArgs) -> String {
+    let address = match &args.common.server_address {
+        Address::Tcp(address) => address,
+        Address::Unix(_) => return "ws://localhost/".to_string(),
+    };
+
+    #[cfg(feature = "mtls")]
+    if !args.mtls.plaintext {
+        return format!("wss://{}:{}/", args.mtls.cert_domain, address.port());
+    }
+
+    format!("ws://{address}/")
+}
+
 #[cfg(feature = "mtls")]
 fn create_connector(args: &ClientArgs) -> anyhow::Result<tokio_rustls::TlsConnector> {
-    let certs = common::tls::load_certs(&args.mtls.cert)?;
-    let priv_key = common::tls::load_keys(&args.mtls.key)?;
+    let password = common::tls::KeyPassword::default();
+    let (certs, priv_key) = common::tls::load_identity(&args.mtls.cert, &args.mtls.key, &password)?;
     let roots_store = common::tls::load_root_certs(&args.mtls.ca_cert)?;
 
     let config = rustls::ClientConfig::builder()
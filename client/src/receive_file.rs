@@ -0,0 +1,121 @@
+use std::path;
+
+use common::proto::{self, request::MessageId, response};
+
+use crate::{session::InFlightStream, Error};
+
+/// Receives the file requested by `request` and saves it into `dest`, or into the current
+/// directory under the file's name if `dest` isn't given or is a directory.
+///
+/// The file is deleted if it doesn't match the length and SHA-256 announced by the server.
+pub async fn receive_download(
+    mut request: InFlightStream,
+    message: MessageId,
+    dest: Option<path::PathBuf>,
+) -> Result<(), Error> {
+    let id = request.id();
+
+    let info = match request.next().await? {
+        response::Message::Download(info) => info,
+        response::Message::Err(err) => return Err(Error::server(id, err)),
+        message => {
+            return Err(Error::Hard(anyhow::anyhow!(
+                "Unexpected response to request {id}: {message:?}"
+            )))
+        }
+    };
+
+    let filepath = destination(dest, &info.filename).await?;
+    tracing::info!(
+        "Downloading {} ({}) into {}",
+        info.filename,
+        human_bytes::human_bytes(info.length as f64),
+        filepath.display()
+    );
+
+    if let Err(err) = receive_into(&mut request, &filepath, &info).await {
+        if let Err(remove_err) = tokio::fs::remove_file(&filepath).await {
+            tracing::warn!("Failed to remove {}: {remove_err}", filepath.display());
+        }
+
+        return Err(err);
+    }
+
+    println!("Downloaded {message} into {}", filepath.display());
+
+    Ok(())
+}
+
+async fn receive_into(
+    request: &mut InFlightStream,
+    filepath: &path::Path,
+    info: &response::FileInfo,
+) -> Result<(), Error> {
+    use sha2::Digest;
+    use tokio::io::AsyncWriteExt;
+
+    let id = request.id();
+    let mut file = tokio::fs::File::create(filepath)
+        .await
+        .map_err(Error::soft)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut received = 0u64;
+
+    loop {
+        match request.next().await? {
+            response::Message::Chunk(proto::request::StreamedFile::Payload(data)) => {
+                hasher.update(&data);
+                file.write_all(&data).await.map_err(Error::soft)?;
+                received += data.len() as u64;
+            }
+            response::Message::Chunk(proto::request::StreamedFile::End(_)) => break,
+            response::Message::Chunk(proto::request::StreamedFile::Abort) => {
+                return Err(Error::Soft(anyhow::anyhow!("Server aborted download {id}")));
+            }
+            response::Message::Err(err) => return Err(Error::server(id, err)),
+            message => {
+                return Err(Error::Hard(anyhow::anyhow!(
+                    "Unexpected response to request {id}: {message:?}"
+                )))
+            }
+        }
+    }
+
+    file.flush().await.map_err(Error::soft)?;
+
+    if received != info.length {
+        return Err(Error::Soft(anyhow::anyhow!(
+            "Expected {} bytes but received {received} bytes",
+            info.length
+        )));
+    }
+
+    let computed = hasher.finalize();
+    if computed.as_slice() != info.hash {
+        return Err(Error::Soft(anyhow::anyhow!(
+            "File hash mismatch, server announced {}, downloaded file has {}",
+            hex::encode(&info.hash),
+            hex::encode(computed)
+        )));
+    }
+
+    Ok(())
+}
+
+/// Path to save a downloaded file to. Only the last component of the server's filename is used
+/// so that the file can't end up outside of the chosen directory.
+async fn destination(dest: Option<path::PathBuf>, filename: &str) -> Result<path::PathBuf, Error> {
+    let dir = match dest {
+        Some(dest) if !tokio::fs::metadata(&dest).await.is_ok_and(|m| m.is_dir()) => {
+            return Ok(dest)
+        }
+        Some(dest) => dest,
+        None => path::PathBuf::from("."),
+    };
+
+    let filename = path::Path::new(filename)
+        .file_name()
+        .ok_or_else(|| Error::Soft(anyhow::anyhow!("Invalid filename {filename:?}")))?;
+
+    Ok(dir.join(filename))
+}
@@ -0,0 +1,37 @@
+use std::{future::Future, time};
+
+use crate::Error;
+
+/// How many times an operation is attempted at most.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Wait before the first retry, doubled for every following one.
+const INITIAL_BACKOFF: time::Duration = time::Duration::from_millis(500);
+
+/// Awaits `first` and, while it fails with an error the server marked as retryable, tries
+/// `again` up to [`MAX_ATTEMPTS`] times in total.
+pub async fn with_retries<T, F, A, G>(first: F, mut again: A) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+    A: FnMut() -> G,
+    G: Future<Output = Result<T, Error>>,
+{
+    let mut result = first.await;
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 2..=MAX_ATTEMPTS {
+        match &result {
+            Err(err) if err.is_retryable() => {
+                tracing::warn!("{err}, retrying in {backoff:?} ({attempt}/{MAX_ATTEMPTS})");
+            }
+            _ => return result,
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+
+        result = again().await;
+    }
+
+    result
+}
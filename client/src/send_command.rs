@@ -1,17 +1,81 @@
 use std::path;
 
-use common::proto;
+use common::proto::{
+    self, handshake,
+    request::{HistoryPosition, HistoryQuery, MessageId, UploadId, UploadKind},
+    response,
+};
 
-use crate::{send_stream_file, Command, Error};
+use crate::{
+    command::HistoryAnchor, keepalive::ping, receive_download, retry, send_stream_file,
+    session::InFlight, Command, CommandTasks, Error, Session,
+};
 
-pub async fn handle_command_should_exit<S>(
-    conn: &mut S,
+/// Sends request for `cmd` to the server. The response, and the file transfer if the command
+/// sends a file, are awaited in a task added to `tasks` so that other commands can be sent
+/// in the meantime.
+pub async fn handle_command_should_exit(
+    session: &Session,
     cmd: anyhow::Result<Command>,
-) -> Result<bool, Error>
-where
-    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
-{
+    welcome: &handshake::Welcome,
+    tasks: &mut CommandTasks,
+) -> Result<bool, Error> {
     let cmd = cmd.map_err(Error::hard)?;
+    let rooms = welcome.capabilities.contains(&handshake::Capability::Rooms);
+    let replies = welcome
+        .capabilities
+        .contains(&handshake::Capability::Replies);
+
+    let (room, cmd) = match cmd {
+        Command::Usage(usage) => {
+            return Err(Error::Soft(anyhow::anyhow!("Usage: {usage}")));
+        }
+        Command::InRoom(_, _) | Command::Join(_) | Command::Leave(_) if !rooms => {
+            return Err(Error::Soft(anyhow::Error::msg(
+                "Server doesn't support rooms",
+            )));
+        }
+        Command::InRoom(room, cmd) => match *cmd {
+            cmd @ (Command::Message(_)
+            | Command::File(_)
+            | Command::Image(_)
+            | Command::Reply(_, _)) => (Some(room), cmd),
+            _ => {
+                return Err(Error::Soft(anyhow::Error::msg(
+                    "Only messages, files and images can be sent to a room",
+                )))
+            }
+        },
+        cmd => (None, cmd),
+    };
+
+    let (reply_to, cmd) = match cmd {
+        Command::Reply(_, _) if !replies => {
+            return Err(Error::Soft(anyhow::Error::msg(
+                "Server doesn't support replies",
+            )));
+        }
+        Command::Reply(message, cmd) => {
+            let message = MessageId::parse_str(&message).map_err(Error::soft)?;
+            match *cmd {
+                cmd @ (Command::Message(_) | Command::File(_) | Command::Image(_)) => {
+                    (Some(message), cmd)
+                }
+                _ => {
+                    return Err(Error::Soft(anyhow::Error::msg(
+                        "Only messages, files and images can be replies",
+                    )))
+                }
+            }
+        }
+        cmd => (None, cmd),
+    };
+
+    let max_chunk_len =
+        proto::request::StreamedFile::max_chunk_len(welcome.limits.max_chunk_frame_size);
+    let resumable = welcome
+        .capabilities
+        .contains(&handshake::Capability::ResumableUploads);
 
     let mut file_to_send = Option::<path::PathBuf>::None;
 
@@ -21,57 +85,370 @@ where
         }
         Command::File(filepath) => {
             let basename = extract_basename(&filepath).map_err(Error::hard)?;
-            let metadata = tokio::fs::metadata(&filepath).await.map_err(Error::soft)?;
-
-            if !metadata.is_file() {
-                return Err(Error::Soft(anyhow::Error::msg("Only files are supported")));
-            }
-
-            let file_size = metadata.len();
+            let file_size = get_file_size(&filepath).await?;
             file_to_send = Some(filepath);
 
             tracing::debug!("File size: {}", human_bytes::human_bytes(file_size as f64));
-            proto::request::Message::FileStream(basename, file_size)
+            upload_request(UploadKind::File, basename, file_size, resumable)
         }
         Command::Image(filepath) => {
             let basename = extract_basename(&filepath).map_err(Error::hard)?;
-            let metadata = tokio::fs::metadata(&filepath).await.map_err(Error::soft)?;
+            let file_size = get_file_size(&filepath).await?;
 
-            if !metadata.is_file() {
-                return Err(Error::Soft(anyhow::Error::msg("Only files are supported")));
-            } else if !basename.ends_with(".png") {
+            if !basename.ends_with(".png") {
                 return Err(Error::Soft(anyhow::Error::msg(
                     "Only .png images are supported",
                 )));
             }
 
-            let file_size = metadata.len();
             file_to_send = Some(filepath);
 
             tracing::debug!("Image size: {}", human_bytes::human_bytes(file_size as f64));
-            proto::request::Message::ImageStream(basename, file_size)
+            upload_request(UploadKind::Image, basename, file_size, resumable)
+        }
+        Command::Resume(upload, filepath) => {
+            if !resumable {
+                return Err(Error::Soft(anyhow::Error::msg(
+                    "Server doesn't support resumable uploads",
+                )));
+            }
+
+            let upload = UploadId::parse_str(&upload).map_err(Error::soft)?;
+            let request = session
+                .request(proto::request::Message::QueryUpload(upload))
+                .await?;
+            let session = session.clone();
+
+            tasks.spawn(async move {
+                let first = async {
+                    let status = expect_upload(request).await?;
+                    resume_upload(&session, status, &filepath, max_chunk_len).await
+                };
+
+                retry::with_retries(first, || {
+                    continue_upload(&session, upload, &filepath, max_chunk_len)
+                })
+                .await
+            });
+
+            return Ok(false);
+        }
+        Command::Get(message, dest) => {
+            if !welcome
+                .capabilities
+                .contains(&handshake::Capability::Downloads)
+            {
+                return Err(Error::Soft(anyhow::Error::msg(
+                    "Server doesn't support downloads",
+                )));
+            }
+
+            let message = MessageId::parse_str(&message).map_err(Error::soft)?;
+            let request = session
+                .request_stream(proto::request::Message::Download(message))
+                .await?;
+
+            tasks.spawn(receive_download(request, message, dest));
+
+            return Ok(false);
+        }
+        Command::History(limit, anchor, nickname, room) => {
+            if !welcome
+                .capabilities
+                .contains(&handshake::Capability::History)
+            {
+                return Err(Error::Soft(anyhow::Error::msg(
+                    "Server doesn't support history",
+                )));
+            }
+
+            let position = match anchor {
+                HistoryAnchor::Latest => HistoryPosition::Latest,
+                HistoryAnchor::Before(message) => {
+                    HistoryPosition::Before(MessageId::parse_str(&message).map_err(Error::soft)?)
+                }
+                HistoryAnchor::After(message) => {
+                    HistoryPosition::After(MessageId::parse_str(&message).map_err(Error::soft)?)
+                }
+            };
+            let query = HistoryQuery {
+                limit,
+                position,
+                nickname,
+                room,
+            };
+            let request = session
+                .request(proto::request::Message::History(query))
+                .await?;
+
+            tasks.spawn(async move {
+                for message in expect_history(request).await? {
+                    println!("{message}");
+                }
+
+                Ok(())
+            });
+
+            return Ok(false);
+        }
+        Command::Who => {
+            if !welcome
+                .capabilities
+                .contains(&handshake::Capability::Presence)
+            {
+                return Err(Error::Soft(anyhow::Error::msg(
+                    "Server doesn't support presence",
+                )));
+            }
+
+            let request = session.request(proto::request::Message::Who).await?;
+
+            tasks.spawn(async move {
+                for user in expect_presence(request).await? {
+                    println!("{user}");
+                }
+
+                Ok(())
+            });
+
+            return Ok(false);
+        }
+        Command::Ping => {
+            let session = session.clone();
+
+            tasks.spawn(async move {
+                let rtt = ping(&session).await?;
+                println!("Pong from server in {rtt:?}");
+
+                Ok(())
+            });
+
+            return Ok(false);
         }
         Command::Message(msg) => proto::request::Message::Text(msg),
+        Command::Direct(nickname, text) => {
+            if !welcome
+                .capabilities
+                .contains(&handshake::Capability::DirectMessages)
+            {
+                return Err(Error::Soft(anyhow::Error::msg(
+                    "Server doesn't support direct messages",
+                )));
+            }
+
+            proto::request::Message::Direct(nickname, text)
+        }
+        Command::Edit(_, _) | Command::Retract(_)
+            if !welcome.capabilities.contains(&handshake::Capability::Edits) =>
+        {
+            return Err(Error::Soft(anyhow::Error::msg(
+                "Server doesn't support editing messages",
+            )));
+        }
+        Command::Edit(message, text) => {
+            let message = MessageId::parse_str(&message).map_err(Error::soft)?;
+            proto::request::Message::Edit(message, text)
+        }
+        Command::Retract(message) => {
+            let message = MessageId::parse_str(&message).map_err(Error::soft)?;
+            proto::request::Message::Retract(message)
+        }
+        Command::Join(room) => proto::request::Message::Join(room),
+        Command::Leave(room) => proto::request::Message::Leave(room),
+        Command::InRoom(..) | Command::Reply(..) | Command::Usage(_) => {
+            unreachable!("room commands, replies and usage errors are handled above")
+        }
         Command::AnnounceNickname(nick) => proto::request::Message::AnnounceNickname(nick),
     };
+    let message = match reply_to {
+        Some(reply_to) => proto::request::Message::Reply(reply_to, Box::new(message)),
+        None => message,
+    };
+    let message = match room {
+        Some(room) => proto::request::Message::Room(room, Box::new(message)),
+        None => message,
+    };
 
-    let mut bytes_sent = proto::Payload::new(message)
-        .write_to(conn)
-        .await
-        .map_err(Error::hard)?;
+    let request = session.request(message.clone()).await?;
+    let session = session.clone();
 
-    if let Some(filename) = file_to_send {
-        bytes_sent += send_stream_file(conn, &filename).await?;
-    }
+    tasks.spawn(async move {
+        let session = &session;
+        let message = &message;
+
+        match file_to_send {
+            Some(filepath) if resumable => {
+                let status = retry::with_retries(expect_upload(request), || async {
+                    expect_upload(session.request(message.clone()).await?).await
+                })
+                .await?;
+                println!(
+                    "Uploading {} as {}, use `.resume {} <path>` to continue if interrupted",
+                    filepath.display(),
+                    status.id,
+                    status.id,
+                );
+
+                let upload = status.id;
+                retry::with_retries(
+                    resume_upload(session, status, &filepath, max_chunk_len),
+                    || continue_upload(session, upload, &filepath, max_chunk_len),
+                )
+                .await
+            }
+            Some(filepath) => {
+                let send = |request: InFlight| async {
+                    send_stream_file(session, request.id(), &filepath, 0, max_chunk_len).await?;
+                    expect_ok(request).await
+                };
 
-    tracing::debug!(
-        "Sent a total of {} bytes",
-        human_bytes::human_bytes(bytes_sent as f64)
-    );
+                retry::with_retries(send(request), || async {
+                    send(session.request(message.clone()).await?).await
+                })
+                .await
+            }
+            None => {
+                retry::with_retries(expect_ok(request), || async {
+                    expect_ok(session.request(message.clone()).await?).await
+                })
+                .await
+            }
+        }
+    });
 
     Ok(false)
 }
 
+fn upload_request(
+    kind: UploadKind,
+    basename: String,
+    file_size: u64,
+    resumable: bool,
+) -> proto::request::Message {
+    match (kind, resumable) {
+        (kind, true) => proto::request::Message::CreateUpload(kind, basename, file_size),
+        (UploadKind::File, false) => proto::request::Message::FileStream(basename, file_size),
+        (UploadKind::Image, false) => proto::request::Message::ImageStream(basename, file_size),
+    }
+}
+
+/// Asks the server how much of `upload` it has persisted and sends the rest.
+async fn continue_upload(
+    session: &Session,
+    upload: UploadId,
+    filepath: &path::Path,
+    max_chunk_len: u64,
+) -> Result<(), Error> {
+    let request = session
+        .request(proto::request::Message::QueryUpload(upload))
+        .await?;
+    let status = expect_upload(request).await?;
+
+    resume_upload(session, status, filepath, max_chunk_len).await
+}
+
+/// Sends the rest of an upload starting at the offset the server has persisted.
+async fn resume_upload(
+    session: &Session,
+    status: response::UploadStatus,
+    filepath: &path::Path,
+    max_chunk_len: u64,
+) -> Result<(), Error> {
+    let file_size = get_file_size(filepath).await?;
+    if file_size != status.size {
+        return Err(Error::Soft(anyhow::anyhow!(
+            "Upload {} expects {} bytes but the file has {file_size} bytes",
+            status.id,
+            status.size,
+        )));
+    }
+
+    tracing::info!("Sending upload {} from {} bytes", status.id, status.offset);
+
+    let request = session
+        .request(proto::request::Message::ResumeUpload(
+            status.id,
+            status.offset,
+        ))
+        .await?;
+    send_stream_file(
+        session,
+        request.id(),
+        filepath,
+        status.offset,
+        max_chunk_len,
+    )
+    .await?;
+
+    expect_ok(request).await
+}
+
+/// Awaits success of `request`. If the server stored a message, its ID is printed.
+async fn expect_ok(request: InFlight) -> Result<(), Error> {
+    let id = request.id();
+
+    match request.response().await? {
+        response::Message::Ok => {
+            tracing::info!("Request {id} was successful");
+            Ok(())
+        }
+        response::Message::Stored(receipt) => {
+            println!("Sent as {} at {}", receipt.id, receipt.timestamp);
+            Ok(())
+        }
+        response::Message::Err(err) => Err(Error::server(id, err)),
+        message => Err(Error::Hard(anyhow::anyhow!(
+            "Unexpected response to request {id}: {message:?}"
+        ))),
+    }
+}
+
+async fn expect_upload(request: InFlight) -> Result<response::UploadStatus, Error> {
+    let id = request.id();
+
+    match request.response().await? {
+        response::Message::Upload(status) => Ok(status),
+        response::Message::Err(err) => Err(Error::server(id, err)),
+        message => Err(Error::Hard(anyhow::anyhow!(
+            "Unexpected response to request {id}: {message:?}"
+        ))),
+    }
+}
+
+async fn expect_history(request: InFlight) -> Result<Vec<response::StoredMessage>, Error> {
+    let id = request.id();
+
+    match request.response().await? {
+        response::Message::History(messages) => Ok(messages),
+        response::Message::Err(err) => Err(Error::server(id, err)),
+        message => Err(Error::Hard(anyhow::anyhow!(
+            "Unexpected response to request {id}: {message:?}"
+        ))),
+    }
+}
+
+async fn expect_presence(request: InFlight) -> Result<Vec<response::UserPresence>, Error> {
+    let id = request.id();
+
+    match request.response().await? {
+        response::Message::Presence(users) => Ok(users),
+        response::Message::Err(err) => Err(Error::server(id, err)),
+        message => Err(Error::Hard(anyhow::anyhow!(
+            "Unexpected response to request {id}: {message:?}"
+        ))),
+    }
+}
+
+async fn get_file_size(filepath: &path::Path) -> Result<u64, Error> {
+    let metadata = tokio::fs::metadata(filepath).await.map_err(Error::soft)?;
+
+    if !metadata.is_file() {
+        return Err(Error::Soft(anyhow::Error::msg("Only files are supported")));
+    }
+
+    Ok(metadata.len())
+}
+
 fn extract_basename(filepath: &path::Path) -> anyhow::Result<String> {
     let basename = filepath
         .file_name()
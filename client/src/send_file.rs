@@ -1,48 +1,63 @@
 use std::path;
 
-use common::proto;
+use common::proto::{self, request::StreamId};
 
-use crate::Error;
+use crate::{Error, Session};
 
-pub async fn send_stream_file<S>(conn: &mut S, filepath: &path::Path) -> Result<usize, Error>
-where
-    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
-{
+// Chunks are small enough for any reasonable server limit while not being too chatty.
+const DEFAULT_CHUNK_LEN: u64 = 4096;
+
+/// Sends file from byte `offset` as chunks of transfer `stream` of at most `max_chunk_len` bytes.
+/// Returns the number of bytes of the file sent. If the file can't be read midway, the transfer
+/// is aborted.
+///
+/// SHA-256 of the whole file, including the part before `offset`, is sent with the end of the
+/// transfer so that the server can verify it received the same file.
+pub async fn send_stream_file(
+    session: &Session,
+    stream: StreamId,
+    filepath: &path::Path,
+    offset: u64,
+    max_chunk_len: u64,
+) -> Result<usize, Error> {
+    use sha2::Digest;
     use tokio::io::AsyncReadExt;
 
-    let mut reader = tokio::fs::File::open(&filepath)
-        .await
-        .map(tokio::io::BufReader::new)
-        .map_err(Error::soft)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut reader = match open_at(filepath, offset, &mut hasher).await {
+        Ok(reader) => reader,
+        Err(err) => return abort(session, stream, err).await,
+    };
 
-    let mut buf = vec![0; 4096];
-    let mut bytes_sent = 0;
+    let chunk_len = DEFAULT_CHUNK_LEN.min(max_chunk_len).max(1);
+    let mut buf = vec![0; chunk_len.try_into().map_err(Error::hard)?];
     let mut bytes_file = 0;
 
     let start = tokio::time::Instant::now();
 
     loop {
-        let bytes_read = reader.read(&mut buf).await.map_err(Error::soft)?;
+        let bytes_read = match reader.read(&mut buf).await {
+            Ok(bytes_read) => bytes_read,
+            Err(err) => return abort(session, stream, err).await,
+        };
 
         if bytes_read == 0 {
             break;
         }
 
+        hasher.update(&buf[..bytes_read]);
         let message = proto::request::StreamedFile::Payload(buf[..bytes_read].to_vec());
 
         bytes_file += bytes_read;
-        bytes_sent += proto::Payload::new(message)
-            .write_to(conn)
-            .await
-            .map_err(Error::hard)?;
+        session.send_chunk(stream, message).await?;
 
-        tracing::debug!("Sent {bytes_sent} bytes, chunk size was {bytes_read}");
+        tracing::debug!("Sent {bytes_file} bytes, chunk size was {bytes_read}");
     }
 
-    proto::Payload::new(proto::request::StreamedFile::End)
-        .write_to(conn)
-        .await
-        .map_err(Error::hard)?;
+    let hash = hasher.finalize().to_vec();
+    session
+        .send_chunk(stream, proto::request::StreamedFile::End(Some(hash)))
+        .await?;
 
     tracing::debug!("Sent the end of file marker");
     let speed = bytes_file as f64 / start.elapsed().as_secs_f64();
@@ -53,5 +68,42 @@ where
         human_bytes::human_bytes(speed),
     );
 
-    Ok(bytes_sent)
+    Ok(bytes_file)
+}
+
+// Opens file and reads it up to `offset`, feeding what was read into `hasher`.
+async fn open_at(
+    filepath: &path::Path,
+    offset: u64,
+    hasher: &mut sha2::Sha256,
+) -> std::io::Result<tokio::io::BufReader<tokio::fs::File>> {
+    use sha2::Digest;
+    use tokio::io::AsyncBufReadExt;
+
+    let mut reader = tokio::io::BufReader::new(tokio::fs::File::open(filepath).await?);
+    let mut remaining = offset;
+
+    while remaining > 0 {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let len = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        hasher.update(&buf[..len]);
+        reader.consume(len);
+        remaining -= len as u64;
+    }
+
+    Ok(reader)
+}
+
+async fn abort(session: &Session, stream: StreamId, err: std::io::Error) -> Result<usize, Error> {
+    session
+        .send_chunk(stream, proto::request::StreamedFile::Abort)
+        .await?;
+
+    Err(Error::soft(err))
 }
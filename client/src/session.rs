@@ -0,0 +1,359 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic, Arc, Mutex},
+};
+
+use common::proto::{
+    codec::PayloadCodec,
+    request::{self, RequestId, StreamId},
+    response,
+};
+use futures::{SinkExt, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::{args::LimitsArgs, Error};
+
+// Enough to keep the connection busy while a file is being read from disk.
+const OUTGOING_QUEUE_SIZE: usize = 32;
+
+// Same reasoning as for `OUTGOING_QUEUE_SIZE`, but for a file being written to disk.
+const STREAM_QUEUE_SIZE: usize = 32;
+
+type PendingRequests = Arc<Mutex<HashMap<RequestId, Waiting>>>;
+
+/// Where to deliver what the server sends in response to a request.
+enum Waiting {
+    Response(oneshot::Sender<response::Message>),
+    /// Request is answered by several frames, e.g. a download.
+    Stream(mpsc::Sender<response::Message>),
+}
+
+/// Handle for sending requests to the server. Can be cloned and used from several tasks at once,
+/// e.g. to send a text message while a file is being uploaded.
+///
+/// Responses are routed back to the requests that produced them using request IDs.
+#[derive(Clone)]
+pub struct Session {
+    next_id: Arc<atomic::AtomicU64>,
+    outgoing: mpsc::Sender<request::Frame>,
+    pending: PendingRequests,
+}
+
+/// Request that has been sent to the server and awaits a response.
+pub struct InFlight {
+    id: RequestId,
+    response: oneshot::Receiver<response::Message>,
+}
+
+impl InFlight {
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Wait for the server to respond.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection is closed before the response arrives.
+    pub async fn response(self) -> Result<response::Message, Error> {
+        self.response.await.map_err(|_| {
+            Error::hard(anyhow::Error::msg(
+                "Connection closed before server responded",
+            ))
+        })
+    }
+}
+
+/// Request that has been sent to the server and is answered by several frames.
+pub struct InFlightStream {
+    id: RequestId,
+    messages: mpsc::Receiver<response::Message>,
+}
+
+impl InFlightStream {
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Wait for the next frame. The stream ends after [`response::Message::Err`] or
+    /// [`response::Message::Chunk`] carrying the end of a transfer.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection is closed before the stream ends.
+    pub async fn next(&mut self) -> Result<response::Message, Error> {
+        self.messages.recv().await.ok_or_else(|| {
+            Error::hard(anyhow::Error::msg(
+                "Connection closed before server finished responding",
+            ))
+        })
+    }
+}
+
+impl Session {
+    /// Start handling `conn` in a background task. The task finishes once all clones of the session
+    /// are dropped, or the connection is closed, e.g. because the server sent a frame exceeding
+    /// `limits`.
+    pub fn start<S>(
+        conn: S,
+        limits: LimitsArgs,
+    ) -> (Self, tokio::task::JoinHandle<anyhow::Result<()>>)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let (outgoing, receiver) = mpsc::channel(OUTGOING_QUEUE_SIZE);
+        let pending = PendingRequests::default();
+
+        let session = Self {
+            next_id: Arc::new(atomic::AtomicU64::new(0)),
+            outgoing,
+            pending: pending.clone(),
+        };
+
+        let handle = tokio::spawn(async move {
+            let result = run(conn, &limits, receiver, &pending).await;
+            // Wake up everybody still waiting for a response.
+            pending.lock().expect("poisoned lock").clear();
+
+            result
+        });
+
+        (session, handle)
+    }
+
+    /// Send a request to the server.
+    pub async fn request(&self, message: request::Message) -> Result<InFlight, Error> {
+        let (sender, response) = oneshot::channel();
+        let id = self
+            .send_request(message, Waiting::Response(sender))
+            .await?;
+
+        Ok(InFlight { id, response })
+    }
+
+    /// Send a request the server answers with several frames, e.g. a download.
+    pub async fn request_stream(&self, message: request::Message) -> Result<InFlightStream, Error> {
+        let (sender, messages) = mpsc::channel(STREAM_QUEUE_SIZE);
+        let id = self.send_request(message, Waiting::Stream(sender)).await?;
+
+        Ok(InFlightStream { id, messages })
+    }
+
+    async fn send_request(
+        &self,
+        message: request::Message,
+        waiting: Waiting,
+    ) -> Result<RequestId, Error> {
+        let id = self.next_id.fetch_add(1, atomic::Ordering::Relaxed);
+
+        self.pending
+            .lock()
+            .expect("poisoned lock")
+            .insert(id, waiting);
+        self.send(request::Frame::Request { id, message }).await?;
+
+        Ok(id)
+    }
+
+    /// Send a chunk of file transfer started by request `stream`.
+    pub async fn send_chunk(
+        &self,
+        stream: StreamId,
+        chunk: request::StreamedFile,
+    ) -> Result<(), Error> {
+        self.send(request::Frame::Chunk { stream, chunk }).await
+    }
+
+    async fn send(&self, frame: request::Frame) -> Result<(), Error> {
+        self.outgoing
+            .send(frame)
+            .await
+            .map_err(|_| Error::hard(anyhow::Error::msg("Connection is closed")))
+    }
+}
+
+/// Reads from and writes to `conn` at the same time. Neither waits for the other, otherwise e.g.
+/// a download during an upload would block the connection as soon as both directions are full.
+async fn run<S>(
+    conn: S,
+    limits: &LimitsArgs,
+    outgoing: mpsc::Receiver<request::Frame>,
+    pending: &PendingRequests,
+) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    // Codec only enforces the larger of the limits, the specific one is checked after decoding.
+    let max_frame_size = limits
+        .max_control_frame_size
+        .max(limits.max_chunk_frame_size);
+    // Split below the codec so that the size of each received frame can still be checked.
+    let (reader, writer) = tokio::io::split(conn);
+    let reader = FramedRead::new(reader, PayloadCodec::new(max_frame_size));
+    let writer = FramedWrite::new(writer, PayloadCodec::<response::Frame>::new(max_frame_size));
+
+    tokio::select! {
+        result = write(writer, outgoing) => result,
+        result = read(reader, limits, pending) => result,
+    }
+}
+
+/// Writes frames sent through the session until all its clones are dropped.
+async fn write<W>(
+    mut conn: FramedWrite<W, PayloadCodec<response::Frame>>,
+    mut outgoing: mpsc::Receiver<request::Frame>,
+) -> anyhow::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    while let Some(frame) = outgoing.recv().await {
+        conn.feed(frame).await?;
+        // Flush once for everything that's queued up.
+        while let Ok(frame) = outgoing.try_recv() {
+            conn.feed(frame).await?;
+        }
+        SinkExt::<request::Frame>::flush(&mut conn).await?;
+    }
+
+    SinkExt::<request::Frame>::close(&mut conn).await?;
+
+    Ok(())
+}
+
+/// Routes frames from the server to whoever is waiting for them.
+async fn read<R>(
+    mut conn: FramedRead<R, PayloadCodec<response::Frame>>,
+    limits: &LimitsArgs,
+    pending: &PendingRequests,
+) -> anyhow::Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    while let Some(frame) = conn.next().await {
+        let frame = frame?;
+
+        check_frame_size(&frame, conn.decoder().last_frame_size(), limits)?;
+        route(pending, frame).await;
+    }
+
+    anyhow::bail!("Server closed the connection")
+}
+
+fn check_frame_size(frame: &response::Frame, size: u64, limits: &LimitsArgs) -> anyhow::Result<()> {
+    let limit = match frame.message {
+        response::Message::Chunk(_) => limits.max_chunk_frame_size,
+        _ => limits.max_control_frame_size,
+    };
+
+    if size > limit {
+        anyhow::bail!("Server sent a frame of {size} bytes, the limit is {limit} bytes");
+    }
+
+    Ok(())
+}
+
+async fn route(pending: &PendingRequests, frame: response::Frame) {
+    if let response::Message::Event(event) = frame.message {
+        // Events are for the user, not for the logs.
+        println!("{event}");
+        return;
+    }
+
+    let waiting = frame
+        .id
+        .and_then(|id| take_waiting(pending, id, &frame.message));
+
+    // Nobody might be waiting for the response anymore, that's fine.
+    match waiting {
+        Some(Waiting::Response(waiting)) => {
+            let _ = waiting.send(frame.message);
+            return;
+        }
+        Some(Waiting::Stream(waiting)) => {
+            // Slows down reading from the connection if the stream isn't consumed fast enough.
+            let _ = waiting.send(frame.message).await;
+            return;
+        }
+        None => {}
+    }
+
+    match frame.message {
+        response::Message::Err(error) => {
+            tracing::error!("Server responded with an error: {error}");
+        }
+        message => {
+            tracing::warn!("Unexpected response to request {:?}: {message:?}", frame.id);
+        }
+    }
+}
+
+/// Who is waiting for `message` sent in response to request `id`. Streams are kept until
+/// their last message.
+fn take_waiting(
+    pending: &PendingRequests,
+    id: RequestId,
+    message: &response::Message,
+) -> Option<Waiting> {
+    let mut pending = pending.lock().expect("poisoned lock");
+
+    let last = !matches!(
+        message,
+        response::Message::Download(_)
+            | response::Message::Chunk(request::StreamedFile::Payload(_))
+    );
+
+    match pending.get(&id)? {
+        Waiting::Stream(sender) if !last => Some(Waiting::Stream(sender.clone())),
+        _ => pending.remove(&id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::proto::handshake;
+
+    #[tokio::test]
+    async fn test_read_while_writing() {
+        let (conn, server) = tokio::io::duplex(1024);
+        let limits = LimitsArgs {
+            max_control_frame_size: handshake::DEFAULT_MAX_CONTROL_FRAME_SIZE,
+            max_chunk_frame_size: handshake::DEFAULT_MAX_CHUNK_FRAME_SIZE,
+        };
+        let (session, _task) = Session::start(conn, limits);
+        let mut server = tokio_util::codec::Framed::new(
+            server,
+            PayloadCodec::<request::Frame>::new(handshake::DEFAULT_MAX_CHUNK_FRAME_SIZE),
+        );
+
+        // Neither frame fits into the pipe, the server only reads once its event is written
+        let data = vec![0u8; 16 * 1024];
+        let chunk = request::StreamedFile::Payload(data.clone());
+        session.send_chunk(1, chunk).await.unwrap();
+        let event = response::Event::Text {
+            from: None,
+            text: "x".repeat(16 * 1024),
+            room: None,
+            reply_to: None,
+        };
+
+        let received = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            server
+                .send(&response::Frame::new(None, event))
+                .await
+                .unwrap();
+            server.next().await.unwrap().unwrap()
+        })
+        .await
+        .expect("connection is blocked");
+
+        assert!(matches!(
+            received,
+            request::Frame::Chunk {
+                stream: 1,
+                chunk: request::StreamedFile::Payload(received),
+            } if received == data
+        ));
+    }
+}
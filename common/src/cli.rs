@@ -1,18 +1,50 @@
-use std::{net, str::FromStr};
+use std::{fmt, net, path, str::FromStr};
 
 pub const DEFAULT_SERVER_ADDR: net::SocketAddr =
     net::SocketAddr::new(net::IpAddr::V4(net::Ipv4Addr::LOCALHOST), 11_111);
 
+/// Scheme of addresses of Unix domain sockets, e.g. `unix:/run/chat.sock`.
+pub const UNIX_SCHEME: &str = "unix:";
+
 /// Command-line arguments for both client and server. It contains only one argument - server address.
 /// Server uses it to bind to a specific address, while client uses it to connect to the server.
 #[derive(clap::Parser, Debug, Clone)]
 pub struct Args {
-    /// Server address to bind to or connect to.
-    #[arg(index = 1, value_parser(parse_socket_addr), default_value_t = DEFAULT_SERVER_ADDR)]
-    pub server_address: net::SocketAddr,
+    /// Server address to bind to or connect to, `unix:<path>` for a Unix domain socket.
+    #[arg(index = 1, value_parser(parse_address), default_value_t = Address::Tcp(DEFAULT_SERVER_ADDR))]
+    pub server_address: Address,
+}
+
+/// Address of the server, either a TCP socket or a Unix domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(net::SocketAddr),
+    Unix(path::PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{addr}"),
+            Address::Unix(path) => write!(f, "{UNIX_SCHEME}{}", path.display()),
+        }
+    }
+}
+
+/// Parses `unix:<path>` as a Unix domain socket, anything else as in [`parse_socket_addr`].
+pub fn parse_address(arg: &str) -> anyhow::Result<Address> {
+    match arg.strip_prefix(UNIX_SCHEME) {
+        Some("") => anyhow::bail!("path of the Unix socket is missing"),
+        Some(path) => Ok(Address::Unix(path::PathBuf::from(path))),
+        None => parse_socket_addr(arg).map(Address::Tcp),
+    }
 }
 
 pub fn parse_socket_addr(arg: &str) -> anyhow::Result<net::SocketAddr> {
+    if arg.starts_with(UNIX_SCHEME) {
+        anyhow::bail!("only TCP addresses are supported here");
+    }
+
     let s = if let Some(suffix) = arg.strip_prefix("localhost") {
         format!("{localhost}{suffix}", localhost = net::Ipv4Addr::LOCALHOST)
     } else {
@@ -21,3 +53,22 @@ pub fn parse_socket_addr(arg: &str) -> anyhow::Result<net::SocketAddr> {
 
     net::SocketAddr::from_str(&s).map_err(anyhow::Error::from)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            parse_address("localhost:1234").unwrap(),
+            Address::Tcp("127.0.0.1:1234".parse().unwrap())
+        );
+        assert_eq!(
+            parse_address("unix:/run/chat.sock").unwrap(),
+            Address::Unix("/run/chat.sock".into())
+        );
+        assert!(parse_address("unix:").is_err());
+        assert!(parse_socket_addr("unix:/run/chat.sock").is_err());
+    }
+}
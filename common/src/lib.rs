@@ -3,3 +3,5 @@ pub mod proto;
 #[cfg(feature = "tls")]
 pub mod tls;
 pub mod tracing;
+#[cfg(feature = "ws")]
+pub mod ws;
@@ -0,0 +1,185 @@
+//! [`tokio_util::codec`] implementation of the [`super::Payload`] wire format so that connections can be
+//! used as [`futures::Stream`]s and [`futures::Sink`]s of messages via [`tokio_util::codec::Framed`].
+//!
+//! Works with any `AsyncRead + AsyncWrite` stream, e.g. `tokio::net::TcpStream` or `tokio_rustls` streams.
+
+use std::marker::PhantomData;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use super::{Len, ReadError, WriteError};
+
+const LEN_SIZE: usize = std::mem::size_of::<Len>();
+
+/// Decodes payloads of type `In` and encodes payloads of any serializable type.
+///
+/// Frames larger than [`PayloadCodec::max_frame_size`] are rejected with [`ReadError::FrameTooLarge`]
+/// as soon as their length is known, without buffering them. The limit can be changed at any time,
+/// e.g. when the peer is expected to start sending file chunks.
+///
+/// Errors are fatal for the connection - after the first one, [`tokio_util::codec::Framed`] stops
+/// yielding items.
+#[derive(Debug)]
+pub struct PayloadCodec<In> {
+    max_frame_size: u64,
+    last_frame_size: u64,
+    _marker: PhantomData<fn() -> In>,
+}
+
+impl<In> PayloadCodec<In> {
+    pub fn new(max_frame_size: u64) -> Self {
+        Self {
+            max_frame_size,
+            last_frame_size: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn max_frame_size(&self) -> u64 {
+        self.max_frame_size
+    }
+
+    pub fn set_max_frame_size(&mut self, max_frame_size: u64) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Size of the most recently decoded frame, excluding the length prefix. Useful for applying
+    /// different limits to different kinds of messages after they're decoded.
+    pub fn last_frame_size(&self) -> u64 {
+        self.last_frame_size
+    }
+}
+
+impl<In> Clone for PayloadCodec<In> {
+    fn clone(&self) -> Self {
+        Self::new(self.max_frame_size)
+    }
+}
+
+impl<In: serde::de::DeserializeOwned> tokio_util::codec::Decoder for PayloadCodec<In> {
+    type Item = In;
+    type Error = ReadError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(len_bytes) = src.get(..LEN_SIZE) else {
+            src.reserve(LEN_SIZE - src.len());
+            return Ok(None);
+        };
+
+        // Cannot fail, slice has exactly the right length.
+        let size = Len::from_be_bytes(len_bytes.try_into().expect("length prefix"));
+        if size > self.max_frame_size {
+            return Err(ReadError::FrameTooLarge {
+                size,
+                limit: self.max_frame_size,
+            });
+        }
+
+        let len: usize = size.try_into().map_err(|_| ReadError::Size(size))?;
+        let frame_len = LEN_SIZE + len;
+
+        if src.len() < frame_len {
+            // Avoid reallocating for each partial read of a large frame.
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LEN_SIZE);
+        let payload = src.split_to(len);
+        self.last_frame_size = size;
+
+        serde_cbor::from_slice(&payload)
+            .map(Some)
+            .map_err(ReadError::from)
+    }
+}
+
+impl<In, T: serde::Serialize> tokio_util::codec::Encoder<T> for PayloadCodec<In> {
+    type Error = WriteError;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+
+        // Length is patched once the payload is encoded directly into `dst`.
+        dst.put_bytes(0, LEN_SIZE);
+        if let Err(err) = serde_cbor::to_writer((&mut *dst).writer(), &item) {
+            dst.truncate(start);
+            return Err(err.into());
+        }
+
+        let len: Len = (dst.len() - start - LEN_SIZE).try_into()?;
+        dst[start..start + LEN_SIZE].copy_from_slice(&len.to_be_bytes());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::*;
+    use crate::proto::{request, Payload};
+
+    #[tokio::test]
+    async fn test_same_wire_format_as_payload() {
+        let message = request::Message::Text("hello".to_string());
+
+        let mut wire = vec![];
+        Payload::new(&message).write_to(&mut wire).await.unwrap();
+
+        let mut codec = PayloadCodec::<request::Message>::new(u64::MAX);
+        let mut buf = BytesMut::new();
+        codec.encode(&message, &mut buf).unwrap();
+
+        assert_eq!(buf.as_ref(), wire.as_slice());
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(message));
+        assert_eq!(codec.last_frame_size(), wire.len() as u64 - LEN_SIZE as u64);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_partial_frames() {
+        let mut codec = PayloadCodec::<String>::new(u64::MAX);
+        let mut wire = BytesMut::new();
+        codec.encode("first", &mut wire).unwrap();
+        codec.encode("second", &mut wire).unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut decoded = vec![];
+
+        for byte in wire {
+            buf.put_u8(byte);
+
+            if let Some(item) = codec.decode(&mut buf).unwrap() {
+                decoded.push(item);
+            }
+        }
+
+        assert_eq!(decoded, ["first", "second"]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let mut codec = PayloadCodec::<String>::new(u64::MAX);
+        let mut buf = BytesMut::new();
+        codec.encode("hello", &mut buf).unwrap();
+
+        codec.set_max_frame_size(1);
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(ReadError::FrameTooLarge { limit: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_huge_length_is_not_reserved() {
+        let mut codec = PayloadCodec::<String>::new(1024);
+        let mut buf = BytesMut::from(&u64::MAX.to_be_bytes()[..]);
+
+        assert!(codec.decode(&mut buf).is_err());
+        assert!(buf.capacity() < 1024);
+    }
+}
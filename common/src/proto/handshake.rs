@@ -0,0 +1,246 @@
+//! Mandatory exchange at the start of every connection.
+//!
+//! 1. Client sends [`Hello`] with its protocol version and capabilities it supports.
+//! 2. Server answers with [`Reply::Welcome`] containing its version, capabilities supported
+//!    by both sides and limits the client has to respect, or [`Reply::Err`] if the client
+//!    is incompatible. In the latter case, the server closes the connection.
+//!
+//! Only after a successful handshake can the client send [`crate::proto::request::Message`]s.
+
+use std::collections::BTreeSet;
+
+use super::response;
+
+/// Version of the protocol implemented by this crate.
+///
+/// Bump `minor` when adding backwards compatible features (usually guarded by a [`Capability`])
+/// and `major` when changing existing messages.
+pub const PROTOCOL_VERSION: Version = Version { major: 5, minor: 7 };
+
+/// Protocol version. Peers are compatible if their major versions are equal.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Version {
+    pub fn is_compatible_with(&self, other: &Version) -> bool {
+        self.major == other.major
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Optional protocol feature a peer may support.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub enum Capability {
+    /// Files and images can be sent in chunks using [`crate::proto::request::StreamedFile`].
+    Streaming,
+    /// Payloads can be compressed.
+    Compression,
+    /// Server can send messages to the client without a prior request.
+    Push,
+    /// Server supports uploads that can be resumed after reconnecting.
+    ResumableUploads,
+    /// Files and images can be downloaded using [`crate::proto::request::Message::Download`].
+    Downloads,
+    /// Stored messages can be fetched using [`crate::proto::request::Message::History`].
+    History,
+    /// Users can send each other direct messages, see [`crate::proto::request::Message::Direct`].
+    DirectMessages,
+    /// Clients can join rooms and send messages to them, see
+    /// [`crate::proto::request::Message::Room`].
+    Rooms,
+    /// Clients can edit and retract messages they sent, see
+    /// [`crate::proto::request::Message::Edit`] and [`crate::proto::request::Message::Retract`].
+    Edits,
+    /// Text messages and files can reply to other messages, see
+    /// [`crate::proto::request::Message::Reply`].
+    Replies,
+    /// Clients can ask who is online, see [`crate::proto::request::Message::Who`].
+    Presence,
+    /// Capability introduced by a newer version of the protocol that this peer doesn't know.
+    #[serde(other)]
+    Unknown,
+}
+
+pub type Capabilities = BTreeSet<Capability>;
+
+/// Default for [`Limits::max_control_frame_size`]. Non-streamed files are sent in a single frame
+/// so it needs to be fairly large.
+pub const DEFAULT_MAX_CONTROL_FRAME_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Default for [`Limits::max_chunk_frame_size`].
+pub const DEFAULT_MAX_CHUNK_FRAME_SIZE: u64 = 1024 * 1024;
+
+/// Default for [`Limits::idle_timeout_secs`].
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 120;
+
+/// Limits on frames a peer is willing to receive. Server advertises its limits to the client
+/// during the handshake. Sizes are in bytes and exclude the length prefix of [`crate::proto::Payload`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Limits {
+    /// Maximum size of a frame carrying a message other than a file chunk.
+    pub max_control_frame_size: u64,
+    /// Maximum size of a frame carrying [`crate::proto::request::StreamedFile`].
+    pub max_chunk_frame_size: u64,
+    /// Server closes connections that don't send a complete frame for this many seconds.
+    /// Clients that want to stay connected should send [`crate::proto::request::Message::Ping`]
+    /// more often. `None` if the server doesn't say.
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_control_frame_size: DEFAULT_MAX_CONTROL_FRAME_SIZE,
+            max_chunk_frame_size: DEFAULT_MAX_CHUNK_FRAME_SIZE,
+            idle_timeout_secs: Some(DEFAULT_IDLE_TIMEOUT_SECS),
+        }
+    }
+}
+
+/// First message client sends after connecting.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Hello {
+    pub version: Version,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// Hello for this crate's protocol version.
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+}
+
+/// Server's answer to a successful [`Hello`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Welcome {
+    pub version: Version,
+    /// Capabilities supported by both the client and the server.
+    pub capabilities: Capabilities,
+    pub limits: Limits,
+}
+
+/// Server's answer to [`Hello`].
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Reply {
+    Welcome(Welcome),
+    Err(response::Error),
+}
+
+impl From<Result<Welcome, response::Error>> for Reply {
+    fn from(result: Result<Welcome, response::Error>) -> Self {
+        match result {
+            Ok(welcome) => Reply::Welcome(welcome),
+            Err(err) => Reply::Err(err),
+        }
+    }
+}
+
+impl From<Reply> for Result<Welcome, response::Error> {
+    fn from(reply: Reply) -> Self {
+        match reply {
+            Reply::Welcome(welcome) => Ok(welcome),
+            Reply::Err(err) => Err(err),
+        }
+    }
+}
+
+/// Decide how the server answers `hello`, given what the server supports.
+///
+/// # Errors
+///
+/// Returns [`response::ErrorCode::Incompatible`] if protocol versions aren't compatible.
+pub fn negotiate(
+    hello: &Hello,
+    supported: &Capabilities,
+    limits: &Limits,
+) -> Result<Welcome, response::Error> {
+    if !PROTOCOL_VERSION.is_compatible_with(&hello.version) {
+        return Err(response::Error::incompatible(
+            PROTOCOL_VERSION,
+            hello.version,
+        ));
+    }
+
+    let capabilities = hello
+        .capabilities
+        .intersection(supported)
+        .filter(|capability| **capability != Capability::Unknown)
+        .copied()
+        .collect();
+
+    Ok(Welcome {
+        version: PROTOCOL_VERSION,
+        capabilities,
+        limits: limits.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_intersects_capabilities() {
+        let hello = Hello::new([Capability::Streaming, Capability::Push].into());
+        let supported = [Capability::Streaming, Capability::Compression].into();
+
+        let welcome = negotiate(&hello, &supported, &Limits::default()).unwrap();
+
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+        assert_eq!(welcome.capabilities, [Capability::Streaming].into());
+    }
+
+    #[test]
+    fn test_negotiate_ignores_unknown_capabilities() {
+        let hello = Hello::new([Capability::Unknown].into());
+        let supported = [Capability::Unknown].into();
+
+        let welcome = negotiate(&hello, &supported, &Limits::default()).unwrap();
+
+        assert!(welcome.capabilities.is_empty());
+    }
+
+    #[test]
+    fn test_negotiate_newer_minor() {
+        let mut hello = Hello::new(Capabilities::new());
+        hello.version.minor += 1;
+
+        assert!(negotiate(&hello, &Capabilities::new(), &Limits::default()).is_ok());
+    }
+
+    #[test]
+    fn test_negotiate_incompatible_major() {
+        let mut hello = Hello::new(Capabilities::new());
+        hello.version.major += 1;
+
+        let err = negotiate(&hello, &Capabilities::new(), &Limits::default()).unwrap_err();
+
+        assert_eq!(err.code, response::ErrorCode::Incompatible);
+        assert!(!err.retryable);
+    }
+
+    #[test]
+    fn test_unknown_capability_deserializes() {
+        let wire = serde_cbor::to_vec(&"FromTheFuture").unwrap();
+        let capability: Capability = serde_cbor::from_slice(&wire).unwrap();
+
+        assert_eq!(capability, Capability::Unknown);
+    }
+}
@@ -1,10 +1,38 @@
 use std::io;
 
+pub mod codec;
+pub mod handshake;
 pub mod request;
 pub mod response;
 
 type Len = u64;
 
+/// Error when reading a [`Payload`].
+#[derive(Debug, thiserror::Error)]
+pub enum ReadError {
+    /// Peer announced a payload larger than the reader is willing to accept.
+    /// Since the payload hasn't been read, the stream is no longer usable.
+    #[error("frame of {size} bytes exceeds the limit of {limit} bytes")]
+    FrameTooLarge { size: u64, limit: u64 },
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("frame of {0} bytes doesn't fit into memory")]
+    Size(u64),
+    #[error("failed to decode payload: {0}")]
+    Decode(#[from] serde_cbor::Error),
+}
+
+/// Error when writing a payload using [`codec::PayloadCodec`].
+#[derive(Debug, thiserror::Error)]
+pub enum WriteError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("failed to encode payload: {0}")]
+    Encode(#[from] serde_cbor::Error),
+    #[error("payload doesn't fit into length prefix: {0}")]
+    Size(#[from] std::num::TryFromIntError),
+}
+
 /// Use for sending types across network. The format of the payload is as follows:
 ///
 /// 1. 8 bytes representing the length of the payload in big-endian format.
@@ -37,7 +65,7 @@ impl<T> AsMut<T> for Payload<T> {
 }
 
 impl<T: serde::Serialize> Payload<T> {
-    /// Write into a writer.
+    /// Write into a writer and flush it.
     ///
     /// # Errors
     ///
@@ -52,6 +80,7 @@ impl<T: serde::Serialize> Payload<T> {
 
         output.write_all(&len.to_be_bytes()).await?;
         output.write_all(&payload).await?;
+        output.flush().await?;
 
         let bytes_sent = payload.len() + std::mem::size_of::<Len>();
 
@@ -60,20 +89,29 @@ impl<T: serde::Serialize> Payload<T> {
 }
 
 impl<T: serde::de::DeserializeOwned> Payload<T> {
-    /// Read from a reader.
+    /// Read from a reader. Payloads larger than `max_size` bytes are rejected before
+    /// any memory is allocated for them.
     ///
     /// # Errors
     ///
-    /// If the deserialization fails, an error is returned.
+    /// If the payload is too large or the deserialization fails, an error is returned.
     /// This can happen e.g. if your architecture's `usize` cannot contain `u64`.
-    pub async fn read_from<R>(input: &mut R) -> anyhow::Result<Self>
+    pub async fn read_from<R>(input: &mut R, max_size: u64) -> Result<Self, ReadError>
     where
         R: tokio::io::AsyncReadExt + Unpin,
     {
         let mut len_bytes = [0u8; std::mem::size_of::<Len>()];
         input.read_exact(&mut len_bytes).await?;
 
-        let len: usize = Len::from_be_bytes(len_bytes).try_into()?;
+        let size = Len::from_be_bytes(len_bytes);
+        if size > max_size {
+            return Err(ReadError::FrameTooLarge {
+                size,
+                limit: max_size,
+            });
+        }
+
+        let len: usize = size.try_into().map_err(|_| ReadError::Size(size))?;
         let mut payload = vec![0u8; len];
         input.read_exact(&mut payload).await?;
 
@@ -81,7 +119,7 @@ impl<T: serde::de::DeserializeOwned> Payload<T> {
 
         serde_cbor::from_reader(cursor)
             .map(Self)
-            .map_err(anyhow::Error::from)
+            .map_err(ReadError::from)
     }
 }
 
@@ -97,12 +135,55 @@ mod utils {
         Payload(&input_msg).write_to(&mut wire).await.unwrap();
 
         let mut cursor = io::Cursor::new(wire);
-        let output_msg = Payload::<T>::read_from(&mut cursor).await.unwrap();
+        let output_msg = Payload::<T>::read_from(&mut cursor, u64::MAX)
+            .await
+            .unwrap();
 
         assert_eq!(output_msg.into_inner(), input_msg);
     }
 }
 
+#[cfg(test)]
+mod limit_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frame_at_limit() {
+        let mut wire = vec![];
+        let size = Payload::new("hello").write_to(&mut wire).await.unwrap() - 8;
+
+        let mut cursor = io::Cursor::new(wire);
+        let result = Payload::<String>::read_from(&mut cursor, size as u64).await;
+
+        assert_eq!(result.unwrap().into_inner(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_large() {
+        let mut wire = vec![];
+        let size = Payload::new("hello").write_to(&mut wire).await.unwrap() - 8;
+
+        let mut cursor = io::Cursor::new(wire);
+        let result = Payload::<String>::read_from(&mut cursor, size as u64 - 1).await;
+
+        assert!(matches!(result, Err(ReadError::FrameTooLarge { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_huge_length_is_not_allocated() {
+        let mut cursor = io::Cursor::new(u64::MAX.to_be_bytes().to_vec());
+        let result = Payload::<String>::read_from(&mut cursor, 1024).await;
+
+        assert!(matches!(
+            result,
+            Err(ReadError::FrameTooLarge {
+                size: u64::MAX,
+                limit: 1024
+            })
+        ));
+    }
+}
+
 #[cfg(test)]
 mod request_tests {
     use futures::Future;
@@ -117,6 +198,59 @@ mod request_tests {
         assert_roundtrip_succeeds(input_msg).await;
     }
 
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let input_msg = Frame::Request {
+            id: 1,
+            message: Message::FileStream("a.txt".to_string(), 3),
+        };
+        assert_roundtrip_succeeds(input_msg).await;
+
+        let input_msg = Frame::Chunk {
+            stream: 1,
+            chunk: StreamedFile::Payload(vec![1, 2, 3]),
+        };
+        assert_roundtrip_succeeds(input_msg).await;
+
+        let input_msg = Frame::Chunk {
+            stream: 1,
+            chunk: StreamedFile::End(Some(vec![0xab; 32])),
+        };
+        assert_roundtrip_succeeds(input_msg).await;
+    }
+
+    #[tokio::test]
+    async fn test_upload_roundtrip() {
+        let upload = UploadId::from_u128(42);
+
+        assert_roundtrip_succeeds(Message::CreateUpload(
+            UploadKind::Image,
+            "cat.png".to_string(),
+            1024,
+        ))
+        .await;
+        assert_roundtrip_succeeds(Message::QueryUpload(upload)).await;
+        assert_roundtrip_succeeds(Message::ResumeUpload(upload, 512)).await;
+    }
+
+    #[tokio::test]
+    async fn test_chunk_overhead() {
+        let data = vec![u8::MAX; 4096];
+        let chunk = Frame::Chunk {
+            stream: u64::MAX,
+            chunk: StreamedFile::Payload(data.clone()),
+        };
+
+        let mut wire = vec![];
+        let bytes_sent = crate::proto::Payload::new(chunk)
+            .write_to(&mut wire)
+            .await
+            .unwrap();
+        let frame_size = (bytes_sent - 8) as u64;
+
+        assert!(frame_size <= data.len() as u64 + StreamedFile::PAYLOAD_OVERHEAD);
+    }
+
     fn async_prop_test(f: impl Future<Output = ()> + Send + 'static) {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_time()
@@ -146,14 +280,14 @@ mod request_tests {
         #[test]
         fn test_file_roundtrip(filename in ".+", payload in proptest::arbitrary::any::<Vec<u8>>()) {
             async_prop_test(async {
-                assert_roundtrip_succeeds(Message::File(filename, payload)).await;
+                assert_roundtrip_succeeds(Message::File(filename, payload, None)).await;
             });
         }
 
         #[test]
         fn test_image_roundtrip(filename in ".+", payload in proptest::arbitrary::any::<Vec<u8>>()) {
             async_prop_test(async {
-                assert_roundtrip_succeeds(Message::Image(filename, payload)).await;
+                assert_roundtrip_succeeds(Message::Image(filename, payload, Some(vec![0xab; 32]))).await;
             });
         }
     }
@@ -171,6 +305,101 @@ mod response_tests {
 
     #[tokio::test]
     async fn test_error() {
-        assert_roundtrip_succeeds(Message::Err(Error::unspecified("oops"))).await;
+        assert_roundtrip_succeeds(Message::Err(Error::internal("oops"))).await;
+    }
+
+    #[tokio::test]
+    async fn test_frame() {
+        assert_roundtrip_succeeds(Frame::new(Some(7), Message::Ok)).await;
+        assert_roundtrip_succeeds(Frame::new(None, Error::internal("oops"))).await;
+    }
+
+    #[tokio::test]
+    async fn test_stored() {
+        let receipt = Receipt {
+            id: uuid::Uuid::from_u128(42),
+            timestamp: chrono::Utc::now(),
+        };
+
+        assert_roundtrip_succeeds(Frame::new(Some(3), Message::Stored(receipt))).await;
+    }
+
+    #[tokio::test]
+    async fn test_presence() {
+        let alice = UserPresence {
+            nickname: "alice".to_string(),
+            status: PresenceStatus::Idle,
+            last_seen: chrono::Utc::now(),
+        };
+
+        assert_roundtrip_succeeds(Frame::new(Some(4), Message::Presence(vec![alice]))).await;
+    }
+
+    #[tokio::test]
+    async fn test_error_retryable() {
+        let busy = Error::new(ErrorCode::UploadBusy);
+        assert!(busy.retryable);
+        assert_roundtrip_succeeds(Message::Err(busy.with_retryable(false))).await;
+        assert!(!Error::new(ErrorCode::HashMismatch).retryable);
+    }
+
+    #[test]
+    fn test_unknown_error_code_deserializes() {
+        let wire = serde_cbor::to_vec(&"FromTheFuture").unwrap();
+        let code: ErrorCode = serde_cbor::from_slice(&wire).unwrap();
+
+        assert_eq!(code, ErrorCode::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_event() {
+        let event = Event::File {
+            from: Some("alice".to_string()),
+            filename: "cat.png".to_string(),
+            mime: Some("image/png".to_string()),
+            length: 1024,
+            room: Some("general".to_string()),
+            reply_to: Some(uuid::Uuid::from_u128(7)),
+        };
+
+        assert_roundtrip_succeeds(Frame::new(None, event)).await;
+        assert_roundtrip_succeeds(Frame::new(
+            None,
+            Event::Text {
+                from: None,
+                text: "hi".to_string(),
+                room: None,
+                reply_to: None,
+            },
+        ))
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod handshake_tests {
+    use super::handshake::*;
+    use super::utils::assert_roundtrip_succeeds;
+
+    #[tokio::test]
+    async fn test_hello() {
+        let hello = Hello::new([Capability::Streaming, Capability::Push].into());
+
+        assert_roundtrip_succeeds(hello).await;
+    }
+
+    #[tokio::test]
+    async fn test_welcome() {
+        let welcome = Welcome {
+            version: PROTOCOL_VERSION,
+            capabilities: [Capability::Streaming].into(),
+            limits: Limits {
+                max_control_frame_size: 1024,
+                max_chunk_frame_size: 512,
+                idle_timeout_secs: Some(60),
+            },
+        };
+
+        assert_roundtrip_succeeds(Reply::Welcome(welcome)).await;
     }
 }
@@ -1,34 +1,173 @@
-/// Represents a message client sends to server.
+/// Identifies a request within a connection. Chosen by the client, server uses it to correlate
+/// responses with requests. IDs shouldn't be reused while a request is in flight.
+pub type RequestId = u64;
+
+/// Identifies a file transfer within a connection. It's the [`RequestId`] of the
+/// [`Message::FileStream`] or [`Message::ImageStream`] request that started the transfer.
+pub type StreamId = RequestId;
+
+/// Identifies a resumable upload. Issued by the server, unlike [`RequestId`] it outlives
+/// the connection so the upload can be continued after reconnecting.
+pub type UploadId = uuid::Uuid;
+
+/// Identifies a stored text message, file or image. Issued by the server and shown in the web UI.
+pub type MessageId = uuid::Uuid;
+
+/// Envelope of every frame client sends to server after the handshake.
+///
+/// Chunks of several file transfers and other requests can be interleaved on one connection.
 #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Frame {
+    /// A new request.
+    Request { id: RequestId, message: Message },
+    /// Chunk of a file transfer started by request `stream`.
+    Chunk {
+        stream: StreamId,
+        chunk: StreamedFile,
+    },
+}
+
+/// Represents a message client sends to server.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Message {
-    /// Filename and file data.
-    File(String, Vec<u8>),
+    /// Filename, file data and optionally SHA-256 of the data computed by the client.
+    File(
+        String,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+    ),
     /// Filename and how many bytes will be sent as file data.
     /// Filesize is represented as [`u64`] instead of [`usize`] to make it platform-independent.
     FileStream(String, u64),
-    /// Filename and image data.
-    Image(String, Vec<u8>),
+    /// Filename, image data and optionally SHA-256 of the data computed by the client.
+    Image(
+        String,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(with = "serde_bytes")] Option<Vec<u8>>,
+    ),
     /// Filename and how many bytes will be sent as image data.
     /// Filesize is represented as [`u64`] instead of [`usize`] to make it platform-independent.
     ImageStream(String, u64),
     /// Text message.
     Text(String),
+    /// Text message only for the user with the given nickname, they receive it as
+    /// [`super::response::Event::Direct`].
+    Direct(String, String),
     /// Tell the server the client's nickname.
     AnnounceNickname(String),
+    /// Start a resumable upload of a file or an image with a filename and size in bytes.
+    /// Server responds with [`super::response::Message::Upload`] carrying the upload's ID.
+    /// No data is sent with this request, use [`Message::ResumeUpload`] to send it.
+    CreateUpload(UploadKind, String, u64),
+    /// Ask how many bytes of an upload the server has persisted.
+    /// Server responds with [`super::response::Message::Upload`].
+    QueryUpload(UploadId),
+    /// Send data of an upload starting at byte offset, 0 for uploads that have just been created.
+    /// The offset can't exceed the one reported by [`Message::QueryUpload`]. Data is sent the same
+    /// way as for [`Message::FileStream`], the ID of this request is the stream ID.
+    ResumeUpload(UploadId, u64),
+    /// Check that the server is alive, it responds with [`super::response::Message::Pong`].
+    /// Also keeps the connection from being closed as idle.
+    Ping,
+    /// Download the file or image attached to a message. Server responds with
+    /// [`super::response::Message::Download`] followed by the file's data in
+    /// [`super::response::Message::Chunk`]s.
+    Download(MessageId),
+    /// Fetch stored messages. Server responds with [`super::response::Message::History`].
+    History(HistoryQuery),
+    /// Join a room to receive messages sent to it. Joining a room the client is already in
+    /// does nothing. Rooms exist as long as someone sends messages to them.
+    Join(String),
+    /// Stop receiving messages sent to a room.
+    Leave(String),
+    /// Send a message to a room the client has joined instead of to everyone. Only
+    /// [`Message::Text`], [`Message::File`], [`Message::Image`], [`Message::FileStream`],
+    /// [`Message::ImageStream`] and [`Message::CreateUpload`] can be sent to a room, as well as
+    /// [`Message::Reply`] wrapping one of them. The server responds as if they were sent on their
+    /// own. Resumed uploads go to the room they were created for.
+    Room(String, Box<Message>),
+    /// Send a message as a reply to a stored message. The same messages as with [`Message::Room`]
+    /// can be replies. The replied message must have been sent to the same room, or to everyone if
+    /// the reply is, and can't be a direct message. Resumed uploads reply to the message they were
+    /// created for.
+    Reply(MessageId, Box<Message>),
+    /// Replace the text of a text message the client sent. The previous text is kept by the server.
+    Edit(MessageId, String),
+    /// Retract a message, or a file or an image, the client sent. It's no longer shown but the
+    /// server keeps it.
+    Retract(MessageId),
+    /// Ask which users are online, idle or offline. Server responds with
+    /// [`super::response::Message::Presence`].
+    Who,
+}
+
+/// Which stored messages to fetch.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HistoryQuery {
+    /// Maximum number of messages, server may return fewer.
+    pub limit: u32,
+    pub position: HistoryPosition,
+    /// Only messages sent by this nickname.
+    pub nickname: Option<String>,
+    /// Only messages sent to this room, otherwise only messages sent to everyone. The client must
+    /// have joined the room.
+    #[serde(default)]
+    pub room: Option<String>,
+}
+
+/// Where the fetched messages are in the history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum HistoryPosition {
+    /// Newest messages.
+    Latest,
+    /// Messages sent right before the given message.
+    Before(MessageId),
+    /// Messages sent right after the given message.
+    After(MessageId),
+}
+
+impl HistoryPosition {
+    /// Message the position is relative to.
+    pub fn anchor(&self) -> Option<MessageId> {
+        match self {
+            Self::Latest => None,
+            Self::Before(id) | Self::After(id) => Some(*id),
+        }
+    }
+}
+
+/// What a resumable upload carries, determines where the server stores it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum UploadKind {
+    File,
+    Image,
 }
 
 /// Represents a message client sends to server while streaming a file or image to it.
-/// Data is sent in chunks and the client can choose to quit anytime and use the connection
-/// for something else.
+/// Data is sent in chunks wrapped in [`Frame::Chunk`] so the client can send other requests
+/// in between and choose to quit anytime. Server sends downloaded files the same way, wrapped
+/// in [`super::response::Message::Chunk`].
 ///
 /// Since the protocol requires first sending the message size, as mandated by [`crate::proto::Payload`],
 /// servers can reject messages that are too long.
 #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum StreamedFile {
     /// File data chunk.
-    Payload(Vec<u8>),
+    Payload(#[serde(with = "serde_bytes")] Vec<u8>),
     /// Abort the current file transfer.
     Abort,
-    /// End of the current file transfer - the whole file has been sent.
-    End,
+    /// End of the current file transfer - the whole file has been sent. Optionally carries SHA-256
+    /// of the whole file computed by the client, server rejects the file if its own hash differs.
+    End(#[serde(with = "serde_bytes")] Option<Vec<u8>>),
+}
+
+impl StreamedFile {
+    /// Upper bound on how many bytes encoding [`StreamedFile::Payload`] wrapped in [`Frame::Chunk`]
+    /// adds to its data.
+    pub const PAYLOAD_OVERHEAD: u64 = 64;
+
+    /// Largest data chunk that fits into a frame of `max_frame_size` bytes.
+    pub fn max_chunk_len(max_frame_size: u64) -> u64 {
+        max_frame_size.saturating_sub(Self::PAYLOAD_OVERHEAD)
+    }
 }
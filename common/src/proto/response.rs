@@ -1,3 +1,21 @@
+/// Envelope of every frame server sends to client after the handshake.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Frame {
+    /// Request this frame responds to. `None` if the frame couldn't be attributed to a request,
+    /// e.g. because it couldn't be decoded, or if it carries an [`Message::Event`].
+    pub id: Option<super::request::RequestId>,
+    pub message: Message,
+}
+
+impl Frame {
+    pub fn new(id: Option<super::request::RequestId>, message: impl Into<Message>) -> Self {
+        Self {
+            id,
+            message: message.into(),
+        }
+    }
+}
+
 /// Represents a message server sends to client in response to a request.
 #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Message {
@@ -5,6 +23,228 @@ pub enum Message {
     Ok,
     /// Request failed with an error.
     Err(Error),
+    /// Something happened on the server that the client didn't ask about, e.g. another user
+    /// sent a message. Frames carrying events have no request ID. Only sent to clients that
+    /// negotiated [`super::handshake::Capability::Push`].
+    Event(Event),
+    /// State of a resumable upload.
+    Upload(UploadStatus),
+    /// Response to [`super::request::Message::Ping`].
+    Pong,
+    /// Text message, file or image was received and stored.
+    Stored(Receipt),
+    /// File requested by [`super::request::Message::Download`] follows in [`Message::Chunk`]s.
+    Download(FileInfo),
+    /// Chunk of a downloaded file. The download ends with [`super::request::StreamedFile::End`],
+    /// or with [`Message::Err`] if the server fails to read the file.
+    Chunk(super::request::StreamedFile),
+    /// Messages requested by [`super::request::Message::History`], oldest first.
+    History(Vec<StoredMessage>),
+    /// Users requested by [`super::request::Message::Who`], sorted by nickname.
+    Presence(Vec<UserPresence>),
+}
+
+/// Whether a user is connected and how recently they did something.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct UserPresence {
+    pub nickname: String,
+    pub status: PresenceStatus,
+    /// When the user last did something if they're connected, otherwise when they disconnected.
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+}
+
+impl std::fmt::Display for UserPresence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let last_seen = self.last_seen.format("%Y-%m-%d %H:%M:%S");
+
+        match self.status {
+            PresenceStatus::Online => write!(f, "{} is online", self.nickname),
+            status => write!(f, "{} is {status}, last seen {last_seen}", self.nickname),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum PresenceStatus {
+    /// Connected and recently active.
+    Online,
+    /// Connected but hasn't sent anything except keepalive pings for a while.
+    Idle,
+    /// Not connected.
+    Offline,
+    /// Status introduced by a newer version of the protocol that this peer doesn't know.
+    #[serde(other)]
+    Unknown,
+}
+
+impl std::fmt::Display for PresenceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            Self::Online => "online",
+            Self::Idle => "idle",
+            Self::Offline => "offline",
+            Self::Unknown => "unknown",
+        };
+
+        f.write_str(status)
+    }
+}
+
+/// Message stored by the server.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StoredMessage {
+    pub receipt: Receipt,
+    /// What the message carries, the same as was pushed to clients when it was sent.
+    pub event: Event,
+}
+
+impl std::fmt::Display for StoredMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.receipt.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            self.receipt.id,
+            self.event
+        )
+    }
+}
+
+/// Describes a file before it's downloaded.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct FileInfo {
+    pub filename: String,
+    pub mime: Option<String>,
+    /// Number of bytes that will be sent.
+    pub length: u64,
+    /// SHA-256 of the file, client should check it once the download ends.
+    #[serde(with = "serde_bytes")]
+    pub hash: Vec<u8>,
+}
+
+/// Identification of a message stored by the server.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Receipt {
+    pub id: super::request::MessageId,
+    /// When the server received the message.
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// State of a resumable upload as seen by the server.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct UploadStatus {
+    pub id: super::request::UploadId,
+    /// Number of bytes persisted by the server. Client should continue sending data from here.
+    pub offset: u64,
+    /// Size of the whole file announced when the upload was created.
+    pub size: u64,
+}
+
+/// Server-originated event pushed to connected clients.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Event {
+    /// Another user sent a text message.
+    Text {
+        from: Option<String>,
+        text: String,
+        /// Room the message was sent to, `None` if it was sent to everyone.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        /// Message this one replies to.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<super::request::MessageId>,
+    },
+    /// Another user sent a text message only to this user. Only sent to clients that
+    /// negotiated [`super::handshake::Capability::DirectMessages`].
+    Direct { from: Option<String>, text: String },
+    /// Another user sent a file or an image.
+    File {
+        from: Option<String>,
+        filename: String,
+        mime: Option<String>,
+        length: u64,
+        /// Room the file was sent to, `None` if it was sent to everyone.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        /// Message the file replies to.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<super::request::MessageId>,
+    },
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Text {
+                from,
+                text,
+                room,
+                reply_to,
+            } => {
+                write_room(f, room.as_deref())?;
+                write!(f, "[{}] ", from.as_deref().unwrap_or("anonymous"))?;
+                write_reply(f, reply_to.as_ref())?;
+                write!(f, "{text}")
+            }
+            Event::Direct { from, text } => {
+                write!(
+                    f,
+                    "[{} -> you] {text}",
+                    from.as_deref().unwrap_or("anonymous")
+                )
+            }
+            Event::File {
+                from,
+                filename,
+                mime,
+                length,
+                room,
+                reply_to,
+            } => {
+                write_room(f, room.as_deref())?;
+                write!(f, "[{}] ", from.as_deref().unwrap_or("anonymous"))?;
+                write_reply(f, reply_to.as_ref())?;
+                write!(
+                    f,
+                    "sent file {filename} ({length} bytes, {})",
+                    mime.as_deref().unwrap_or("unknown type"),
+                )
+            }
+        }
+    }
+}
+
+impl Event {
+    /// Room the event happened in, `None` if it concerns everyone or only this user.
+    pub fn room(&self) -> Option<&str> {
+        match self {
+            Event::Text { room, .. } | Event::File { room, .. } => room.as_deref(),
+            Event::Direct { .. } => None,
+        }
+    }
+}
+
+fn write_room(f: &mut std::fmt::Formatter<'_>, room: Option<&str>) -> std::fmt::Result {
+    match room {
+        Some(room) => write!(f, "#{room} "),
+        None => Ok(()),
+    }
+}
+
+fn write_reply(
+    f: &mut std::fmt::Formatter<'_>,
+    reply_to: Option<&super::request::MessageId>,
+) -> std::fmt::Result {
+    match reply_to {
+        Some(reply_to) => write!(f, "(reply to {reply_to}) "),
+        None => Ok(()),
+    }
+}
+
+impl From<Event> for Message {
+    fn from(event: Event) -> Self {
+        Message::Event(event)
+    }
 }
 
 impl From<Result<(), Error>> for Message {
@@ -16,11 +256,22 @@ impl From<Result<(), Error>> for Message {
     }
 }
 
-impl From<Message> for Result<(), Error> {
-    fn from(msg: Message) -> Self {
+impl TryFrom<Message> for Result<(), Error> {
+    type Error = Message;
+
+    /// Fails if the message carries something else than success or an error, e.g. an [`Event`].
+    fn try_from(msg: Message) -> Result<Self, Message> {
         match msg {
-            Message::Ok => Ok(()),
-            Message::Err(err) => Err(err),
+            Message::Ok => Ok(Ok(())),
+            Message::Err(err) => Ok(Err(err)),
+            msg @ (Message::Event(_)
+            | Message::Upload(_)
+            | Message::Pong
+            | Message::Stored(_)
+            | Message::Download(_)
+            | Message::Chunk(_)
+            | Message::History(_)
+            | Message::Presence(_)) => Err(msg),
         }
     }
 }
@@ -32,27 +283,198 @@ impl From<Error> for Message {
 }
 
 /// Represents an error that occurred during request handling.
-#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize, thiserror::Error)]
-pub enum Error {
-    /// Failed to read message - e.g. due to client incompatibility.
-    #[error("failed to read message")]
-    Read(String),
-    #[error("abort understood")]
+///
+/// Clients should act on [`Self::code`] and [`Self::retryable`], [`Self::details`] are meant
+/// for humans only.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, thiserror::Error)]
+pub struct Error {
+    pub code: ErrorCode,
+    /// Whether the same request may succeed if sent again later.
+    pub retryable: bool,
+    pub details: Option<String>,
+}
+
+/// Stable identifier of an [`Error`]. New codes may be added in minor protocol versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum ErrorCode {
+    /// Frame couldn't be decoded - e.g. due to client incompatibility.
+    Decode,
+    /// Frame exceeded the server's limit. Server closes the connection after sending this.
+    FrameTooLarge,
+    /// Client's protocol version isn't supported by the server.
+    Incompatible,
+    /// Client didn't start the connection with a valid [`super::handshake::Hello`].
+    Handshake,
+    /// Chunk refers to a file transfer that doesn't exist or has already finished.
+    UnknownStream,
+    /// Request ID is already used by a file transfer in progress.
+    StreamInUse,
+    /// File transfer was aborted by the client.
     ClientAbort,
-    /// Failed to execute message - e.g. not enough disk space.
-    #[error("message execution error: {0}")]
-    MessageExec(String),
-    /// Unspecified error.
-    #[error("unspecified error: {0}")]
-    Unspecified(String),
+    /// Client sent more or less data than it announced.
+    SizeMismatch,
+    /// Hash of a received file differs from the one computed by the client. Server deletes the file.
+    HashMismatch,
+    /// Upload doesn't exist or has already finished.
+    UnknownUpload,
+    /// Upload is being resumed by another connection.
+    UploadBusy,
+    /// Client tried to resume an upload past what the server has persisted.
+    InvalidOffset,
+    /// Filename is empty or refers to another directory.
+    InvalidFilename,
+    /// Message doesn't exist or has no file attached.
+    FileNotFound,
+    /// Message doesn't exist.
+    MessageNotFound,
+    /// Direct message is addressed to a nickname the server doesn't know.
+    UnknownRecipient,
+    /// Direct message can't be delivered because the server has no listener where clients prove
+    /// their nickname with a certificate.
+    UnverifiedRecipient,
+    /// Room name is empty, too long or contains characters other than letters, digits, `-`
+    /// and `_`.
+    InvalidRoom,
+    /// Client sent a message to a room it hasn't joined.
+    NotInRoom,
+    /// Request can't be sent to a room, see [`super::request::Message::Room`].
+    NotRoomMessage,
+    /// Request can't be a reply, or can't reply to the given message, see
+    /// [`super::request::Message::Reply`].
+    NotReplyMessage,
+    /// Message was sent by someone else, or anonymously, so the client can't change it.
+    NotSender,
+    /// Only text messages can be edited.
+    NotEditable,
+    /// Server only allows nicknames the client's certificate was issued to.
+    NicknameNotAllowed,
+    /// Server ran out of disk space.
+    StorageFull,
+    /// Server's disk quota was exceeded.
+    QuotaExceeded,
+    /// Server isn't allowed to write the file.
+    PermissionDenied,
+    /// Other failure of the server's storage.
+    Storage,
+    /// Server failed to persist the message to its database.
+    Database,
+    /// Any other failure of the server.
+    Internal,
+    /// Code introduced by a newer version of the protocol that this peer doesn't know.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Whether errors with this code are usually temporary. Servers may override this for
+    /// individual errors.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::UploadBusy | Self::Database)
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Self::Decode => "failed to read message",
+            Self::FrameTooLarge => "frame too large",
+            Self::Incompatible => "incompatible protocol version",
+            Self::Handshake => "handshake failed",
+            Self::UnknownStream => "unknown stream",
+            Self::StreamInUse => "stream already in use",
+            Self::ClientAbort => "abort understood",
+            Self::SizeMismatch => "file size mismatch",
+            Self::HashMismatch => "file hash mismatch",
+            Self::UnknownUpload => "unknown upload",
+            Self::UploadBusy => "upload already in progress",
+            Self::InvalidOffset => "invalid upload offset",
+            Self::InvalidFilename => "invalid filename",
+            Self::FileNotFound => "file not found",
+            Self::MessageNotFound => "message not found",
+            Self::UnknownRecipient => "unknown recipient",
+            Self::UnverifiedRecipient => "recipient can't be verified",
+            Self::InvalidRoom => "invalid room name",
+            Self::NotInRoom => "not a member of the room",
+            Self::NotRoomMessage => "request can't be sent to a room",
+            Self::NotReplyMessage => "request can't be a reply",
+            Self::NotSender => "message was sent by someone else",
+            Self::NotEditable => "message can't be edited",
+            Self::NicknameNotAllowed => "nickname isn't allowed for this certificate",
+            Self::StorageFull => "server storage is full",
+            Self::QuotaExceeded => "server storage quota exceeded",
+            Self::PermissionDenied => "server storage permission denied",
+            Self::Storage => "server storage error",
+            Self::Database => "server database error",
+            Self::Internal => "internal server error",
+            Self::Unknown => "unknown error",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.details {
+            Some(details) => write!(f, "{}: {details}", self.code),
+            None => write!(f, "{}", self.code),
+        }
+    }
 }
 
 impl Error {
-    pub fn message_exec<S: ToString>(msg: S) -> Self {
-        Self::MessageExec(msg.to_string())
+    /// Error with `code` and its default retryability.
+    pub fn new(code: ErrorCode) -> Self {
+        Self {
+            code,
+            retryable: code.is_retryable(),
+            details: None,
+        }
+    }
+
+    pub fn with_details<S: ToString>(mut self, details: S) -> Self {
+        self.details = Some(details.to_string());
+        self
+    }
+
+    pub fn with_retryable(mut self, retryable: bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    pub fn internal<S: ToString>(details: S) -> Self {
+        Self::new(ErrorCode::Internal).with_details(details)
+    }
+
+    pub fn decode<S: ToString>(details: S) -> Self {
+        Self::new(ErrorCode::Decode).with_details(details)
+    }
+
+    pub fn handshake<S: ToString>(details: S) -> Self {
+        Self::new(ErrorCode::Handshake).with_details(details)
+    }
+
+    pub fn incompatible(
+        server: super::handshake::Version,
+        client: super::handshake::Version,
+    ) -> Self {
+        Self::new(ErrorCode::Incompatible)
+            .with_details(format!("server speaks {server}, client speaks {client}"))
+    }
+
+    pub fn frame_too_large(size: u64, limit: u64) -> Self {
+        Self::new(ErrorCode::FrameTooLarge).with_details(format!(
+            "frame of {size} bytes exceeds the limit of {limit} bytes"
+        ))
     }
 
-    pub fn unspecified<S: ToString>(msg: S) -> Self {
-        Self::Unspecified(msg.to_string())
+    pub fn hash_mismatch(expected: &[u8], computed: &[u8]) -> Self {
+        Self::new(ErrorCode::HashMismatch).with_details(format!(
+            "client computed {}, server computed {}",
+            hex::encode(expected),
+            hex::encode(computed)
+        ))
     }
 }
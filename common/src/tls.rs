@@ -1,9 +1,23 @@
-use std::{fs, io, path};
+use std::{
+    fs, io, path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
 
-use rustls_pemfile::{certs, rsa_private_keys};
+use anyhow::Context;
+use rustls_pemfile::{certs, crls};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
 
-// No real reason to make this async since this runs once at startup and
-// functions from `rustls_pemfile` are synchronous.
+// No real reason to make this async since this runs at startup or when the server
+// reloads its settings, and functions from `rustls_pemfile` are synchronous.
+
+/// Env var with the password of encrypted private keys and PKCS#12 bundles. If it isn't set,
+/// the password is asked for on the terminal.
+pub const KEY_PASSWORD_ENV: &str = "TLS_KEY_PASSWORD";
+
+const ENCRYPTED_KEY_LABEL: &str = "ENCRYPTED PRIVATE KEY";
 
 pub fn load_root_certs(path: &path::Path) -> anyhow::Result<rustls::RootCertStore> {
     let mut root_store = rustls::RootCertStore::empty();
@@ -15,13 +29,242 @@ pub fn load_root_certs(path: &path::Path) -> anyhow::Result<rustls::RootCertStor
     Ok(root_store)
 }
 
-pub fn load_certs(path: &path::Path) -> io::Result<Vec<rustls_pki_types::CertificateDer<'static>>> {
+pub fn load_certs(path: &path::Path) -> io::Result<Vec<CertificateDer<'static>>> {
     certs(&mut io::BufReader::new(fs::File::open(path)?)).collect()
 }
 
-pub fn load_keys(path: &path::Path) -> io::Result<rustls_pki_types::PrivateKeyDer<'static>> {
-    rsa_private_keys(&mut io::BufReader::new(fs::File::open(path)?))
-        .next()
-        .unwrap()
-        .map(Into::into)
+pub fn load_crls(
+    path: &path::Path,
+) -> io::Result<Vec<rustls_pki_types::CertificateRevocationListDer<'static>>> {
+    crls(&mut io::BufReader::new(fs::File::open(path)?)).collect()
+}
+
+/// Certificate chain and private key of this side of the connection. `cert` is either PEM
+/// certificates with the key in `key`, or a PKCS#12 bundle holding both, `key` isn't read then.
+pub fn load_identity(
+    cert: &path::Path,
+    key: &path::Path,
+    password: &KeyPassword,
+) -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let data = fs::read(cert).with_context(|| format!("failed to read {}", cert.display()))?;
+
+    if is_pem(&data) {
+        let certs = load_certs(cert)?;
+        if certs.is_empty() {
+            anyhow::bail!("no certificate found in {}", cert.display());
+        }
+
+        return Ok((certs, load_keys(key, password)?));
+    }
+
+    load_pkcs12(cert, &data, password)
+        .with_context(|| format!("failed to read PKCS#12 bundle {}", cert.display()))
+}
+
+/// Reads the first private key in PEM file `path`: PKCS#1 RSA, PKCS#8 of any algorithm, SEC1 EC
+/// or encrypted PKCS#8.
+pub fn load_keys(
+    path: &path::Path,
+    password: &KeyPassword,
+) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+
+    let key = rustls_pemfile::private_key(&mut data.as_slice())
+        .with_context(|| format!("invalid PEM in {}", path.display()))?;
+    if let Some(key) = key {
+        return Ok(key);
+    }
+
+    // `rustls_pemfile` skips encrypted keys
+    let Some(encrypted) = find_pem(&data, ENCRYPTED_KEY_LABEL) else {
+        anyhow::bail!(
+            "no private key found in {}, expected PKCS#1, PKCS#8 or SEC1 in PEM",
+            path.display()
+        );
+    };
+
+    decrypt_key(encrypted, &password.get(path)?)
+        .with_context(|| format!("failed to decrypt private key in {}", path.display()))
+}
+
+/// Password of encrypted private keys and PKCS#12 bundles, taken from [`KEY_PASSWORD_ENV`] or
+/// asked for on the terminal when a key needs it. It's only asked for once, the same password
+/// is used for all keys.
+#[derive(Debug, Default)]
+pub struct KeyPassword {
+    password: Mutex<Option<String>>,
+    /// Fail instead of asking on the terminal, see [`Self::disable_prompt`].
+    no_prompt: AtomicBool,
+}
+
+impl KeyPassword {
+    /// Password known in advance.
+    pub fn new(password: impl ToString) -> Self {
+        Self {
+            password: Mutex::new(Some(password.to_string())),
+            no_prompt: AtomicBool::new(false),
+        }
+    }
+
+    /// Keys needing a password that isn't known yet fail to load from now on instead of asking
+    /// for it, e.g. once the server is running and nobody watches the terminal. Asking would
+    /// also block the loading thread until someone answers.
+    pub fn disable_prompt(&self) {
+        self.no_prompt.store(true, Ordering::Relaxed);
+    }
+
+    fn get(&self, path: &path::Path) -> anyhow::Result<String> {
+        let mut password = self.password.lock().unwrap();
+
+        if let Some(password) = password.as_ref() {
+            return Ok(password.clone());
+        }
+
+        let entered = match std::env::var(KEY_PASSWORD_ENV) {
+            Ok(entered) => entered,
+            Err(_) if self.no_prompt.load(Ordering::Relaxed) => anyhow::bail!(
+                "{} is encrypted and its password isn't known, set {KEY_PASSWORD_ENV}",
+                path.display()
+            ),
+            Err(_) => rpassword::prompt_password(format!("Password for {}: ", path.display()))
+                .with_context(|| {
+                    format!(
+                        "{} is encrypted, set {KEY_PASSWORD_ENV} or run in a terminal",
+                        path.display()
+                    )
+                })?,
+        };
+
+        Ok(password.insert(entered).clone())
+    }
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.windows(b"-----BEGIN ".len())
+        .any(|window| window == b"-----BEGIN ")
+}
+
+/// PEM document labeled `label` in `data`, including its boundaries.
+fn find_pem<'a>(data: &'a [u8], label: &str) -> Option<&'a [u8]> {
+    let begin = format!("-----BEGIN {label}-----");
+    let end = format!("-----END {label}-----");
+
+    let start = find(data, begin.as_bytes())?;
+    let len = find(&data[start..], end.as_bytes())? + end.len();
+
+    Some(&data[start..start + len])
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn decrypt_key(pem: &[u8], password: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let pem = std::str::from_utf8(pem)?;
+    let (_, document) = pkcs8::Document::from_pem(pem).map_err(|err| anyhow::anyhow!("{err}"))?;
+
+    let encrypted = pkcs8::EncryptedPrivateKeyInfo::try_from(document.as_bytes())
+        .map_err(|err| anyhow::anyhow!("{err}"))?;
+    let decrypted = encrypted
+        .decrypt(password)
+        .map_err(|_| anyhow::anyhow!("wrong password or unsupported encryption"))?;
+
+    Ok(PrivatePkcs8KeyDer::from(decrypted.as_bytes().to_vec()).into())
+}
+
+fn load_pkcs12(
+    path: &path::Path,
+    data: &[u8],
+    password: &KeyPassword,
+) -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let keystore = p12_keystore::KeyStore::from_pkcs12(data, &password.get(path)?)
+        .map_err(|err| anyhow::anyhow!("{err}, wrong password?"))?;
+
+    let Some((_, chain)) = keystore.private_key_chain() else {
+        anyhow::bail!("bundle has no private key");
+    };
+    if chain.chain().is_empty() {
+        anyhow::bail!("bundle has no certificate for its private key");
+    }
+
+    let certs = chain
+        .chain()
+        .iter()
+        .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+        .collect();
+    let key = PrivatePkcs8KeyDer::from(chain.key().to_vec()).into();
+
+    Ok((certs, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> path::PathBuf {
+        path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join(name)
+    }
+
+    fn load(name: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+        load_keys(&testdata(name), &KeyPassword::new("secret"))
+    }
+
+    #[test]
+    fn test_load_key_formats() {
+        assert!(matches!(load("ec-sec1.key"), Ok(PrivateKeyDer::Sec1(_))));
+        assert!(matches!(load("ec-pkcs8.key"), Ok(PrivateKeyDer::Pkcs8(_))));
+        assert!(matches!(load("ed25519.key"), Ok(PrivateKeyDer::Pkcs8(_))));
+
+        // Decrypted key is the same as the plain one
+        assert_eq!(
+            load("ec-encrypted.key").unwrap(),
+            load("ec-pkcs8.key").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_load_key_errors() {
+        let password = KeyPassword::new("wrong");
+        let err = load_keys(&testdata("ec-encrypted.key"), &password).unwrap_err();
+        assert!(format!("{err:#}").contains("wrong password"));
+
+        let err = load("ec.crt").unwrap_err();
+        assert!(err.to_string().contains("no private key found"));
+
+        assert!(load("missing.key").is_err());
+    }
+
+    #[test]
+    fn test_disable_prompt() {
+        // The password would be taken from the environment instead
+        if std::env::var(KEY_PASSWORD_ENV).is_ok() {
+            return;
+        }
+
+        let password = KeyPassword::default();
+        password.disable_prompt();
+        let err = load_keys(&testdata("ec-encrypted.key"), &password).unwrap_err();
+        assert!(format!("{err:#}").contains("password isn't known"));
+
+        // A known password is still used
+        let password = KeyPassword::new("secret");
+        password.disable_prompt();
+        assert!(load_keys(&testdata("ec-encrypted.key"), &password).is_ok());
+    }
+
+    #[test]
+    fn test_load_pkcs12() {
+        let password = KeyPassword::new("secret");
+        let (certs, key) =
+            load_identity(&testdata("ec.p12"), &testdata("unused"), &password).unwrap();
+
+        assert_eq!(certs, load_certs(&testdata("ec.crt")).unwrap());
+        assert_eq!(key, load("ec-pkcs8.key").unwrap());
+
+        let password = KeyPassword::new("wrong");
+        assert!(load_identity(&testdata("ec.p12"), &testdata("unused"), &password).is_err());
+    }
 }
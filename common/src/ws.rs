@@ -0,0 +1,146 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use futures::{Sink, Stream};
+use tokio_tungstenite::{tungstenite, WebSocketStream};
+
+pub use tokio_tungstenite::{accept_async, client_async};
+
+/// Longest binary message written at once. Larger writes are split, so one protocol frame may
+/// span several messages.
+pub const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// Byte stream over a WebSocket connection. Bytes written are sent in binary messages and
+/// binary messages received are read back as one continuous stream, so the protocol's
+/// length-prefixed frames work the same as over TCP.
+///
+/// Pings are answered by the WebSocket implementation, text messages are rejected.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    /// Part of the last received message that hasn't been read yet.
+    read_buf: Bytes,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Bytes::new(),
+        }
+    }
+}
+
+impl<S> tokio::io::AsyncRead for WsStream<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_buf.is_empty() {
+            let message = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(message) => message.map_err(into_io_error)?,
+                None => return Poll::Ready(Ok(())),
+            };
+
+            match message {
+                tungstenite::Message::Binary(data) => self.read_buf = data.into(),
+                tungstenite::Message::Close(_) => return Poll::Ready(Ok(())),
+                tungstenite::Message::Text(_) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "text WebSocket messages aren't supported",
+                    )));
+                }
+                tungstenite::Message::Ping(_)
+                | tungstenite::Message::Pong(_)
+                | tungstenite::Message::Frame(_) => {}
+            }
+        }
+
+        let len = self.read_buf.len().min(buf.remaining());
+        buf.put_slice(&self.read_buf[..len]);
+        self.read_buf.advance(len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> tokio::io::AsyncWrite for WsStream<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(into_io_error)?;
+
+        let len = buf.len().min(MAX_MESSAGE_LEN);
+        let message = tungstenite::Message::Binary(buf[..len].to_vec());
+        Pin::new(&mut self.inner)
+            .start_send(message)
+            .map_err(into_io_error)?;
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(into_io_error)
+    }
+}
+
+fn into_io_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::BrokenPipe, err)
+        }
+        err => io::Error::other(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_large_write_is_read_back() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let data: Vec<u8> = (0..3 * MAX_MESSAGE_LEN + 7).map(|i| i as u8).collect();
+
+        let sent = data.clone();
+        let server = tokio::spawn(async move {
+            let mut stream = WsStream::new(accept_async(server).await.unwrap());
+            stream.write_all(&sent).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let (client, _) = client_async("ws://localhost/", client).await.unwrap();
+        let mut received = vec![];
+        WsStream::new(client)
+            .read_to_end(&mut received)
+            .await
+            .unwrap();
+        server.await.unwrap();
+
+        assert_eq!(received, data);
+    }
+}
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "message" DROP COLUMN "delivered_at";
+ALTER TABLE "message" DROP COLUMN "recipient";
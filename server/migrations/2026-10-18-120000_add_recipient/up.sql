@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE "message" ADD COLUMN "recipient" VARCHAR;
+ALTER TABLE "message" ADD COLUMN "delivered_at" TIMESTAMP;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE "message_text_revision"(
+    "revision_id" BIGSERIAL NOT NULL PRIMARY KEY,
+    "message_id" BIGINT NOT NULL,
+    "text" TEXT NOT NULL,
+    "replaced_at" TIMESTAMP NOT NULL,
+    FOREIGN KEY ("message_id") REFERENCES "message_text"("message_id") ON DELETE CASCADE
+);
+
+CREATE INDEX "message_text_revision_message_id_idx" ON "message_text_revision"("message_id");
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "message" DROP COLUMN "reply_to";
+
+ALTER TABLE "message" DROP CONSTRAINT "message_public_id_key";
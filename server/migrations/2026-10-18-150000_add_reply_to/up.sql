@@ -0,0 +1,12 @@
+-- Your SQL goes here
+ALTER TABLE
+    "message"
+ADD
+    CONSTRAINT "message_public_id_key" UNIQUE ("public_id");
+
+ALTER TABLE
+    "message"
+ADD
+    COLUMN "reply_to" UUID REFERENCES "message"("public_id") ON DELETE SET NULL;
+
+CREATE INDEX "message_reply_to_idx" ON "message"("reply_to");
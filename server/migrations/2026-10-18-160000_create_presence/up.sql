@@ -0,0 +1,5 @@
+-- Your SQL goes here
+CREATE TABLE "presence"(
+    "nickname" VARCHAR NOT NULL PRIMARY KEY,
+    "last_seen" TIMESTAMP NOT NULL
+);
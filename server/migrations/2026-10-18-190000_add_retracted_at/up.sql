@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE "message" ADD COLUMN "retracted_at" TIMESTAMP;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+-- Unix domain socket clients are stored as `unix:uid=<uid>`, not only IP addresses
+ALTER TABLE "message" RENAME COLUMN "user_ip" TO "peer";
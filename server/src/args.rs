@@ -1,33 +1,379 @@
-use std::path;
+use std::{fmt, path, time};
+
+use common::{cli::Address, proto::handshake};
+
+use crate::server::Timeouts;
 
 /// Command-line arguments for the server.
 #[derive(clap::Parser, Debug, Clone)]
+#[clap(args_conflicts_with_subcommands = true)]
 pub struct ServerArgs {
     #[clap(short, long, default_value = ".")]
     pub root: path::PathBuf,
     #[clap(flatten)]
     pub common: common::cli::Args,
 
+    /// Also accept WebSocket connections on this address, frames are sent in binary messages.
+    #[cfg(feature = "ws")]
+    #[clap(
+        long,
+        value_parser(common::cli::parse_socket_addr),
+        conflicts_with = "listen"
+    )]
+    pub ws_address: Option<std::net::SocketAddr>,
+
+    /// Listen on this instead of the server address, can be repeated. One of `tcp://<addr>`,
+    /// `mtls://<addr>`, `ws://<addr>`, `wss://<addr>` or `unix:<path>`, TLS listeners may
+    /// override `--cert`, `--key`, `--ca-cert` and `--crl` like
+    /// `mtls://<addr>,cert=<path>,key=<path>`.
+    #[clap(long, value_parser(parse_listen), conflicts_with = "server_address")]
+    pub listen: Vec<ListenArg>,
+
+    /// Permissions of the socket file when listening on a Unix domain socket, in octal.
+    #[cfg(unix)]
+    #[clap(long, value_parser(crate::server::parse_mode), default_value = "660")]
+    pub unix_socket_mode: u32,
+
+    #[clap(flatten)]
+    pub limits: LimitsArgs,
+
+    /// Remove resumable uploads with their partially received files once they haven't been
+    /// resumed for this many seconds.
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = crate::msg_exec::DEFAULT_UPLOAD_TTL.as_secs()
+    )]
+    pub upload_ttl: u64,
+
     #[cfg(feature = "mtls")]
     #[clap(flatten)]
     pub mtls: MtlsArgs,
 
     #[clap(flatten)]
     pub web: crate::web::Config,
+
+    #[cfg(feature = "mtls")]
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Tools run instead of serving clients.
+#[cfg(feature = "mtls")]
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Manage a certificate authority issuing certificates for mTLS.
+    Ca(CaArgs),
+}
+
+#[cfg(feature = "mtls")]
+#[derive(clap::Parser, Debug, Clone)]
+pub struct CaArgs {
+    /// Directory with the CA's certificate, key and index of issued certificates. Issued
+    /// certificates and keys are written there as well.
+    #[clap(long, default_value = "ca")]
+    pub dir: path::PathBuf,
+
+    #[clap(subcommand)]
+    pub command: CaCommand,
+}
+
+#[cfg(feature = "mtls")]
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum CaCommand {
+    /// Create the CA's key and self-signed certificate.
+    Init {
+        /// Common name of the CA.
+        #[clap(long, default_value = "Chat CA")]
+        name: String,
+
+        /// Days the certificate is valid for.
+        #[clap(long, default_value_t = 3650)]
+        days: u32,
+    },
+
+    /// Issue a client certificate with the nickname as its common name.
+    IssueClient {
+        nickname: String,
+
+        /// Days the certificate is valid for.
+        #[clap(long, default_value_t = 365)]
+        days: u32,
+    },
+
+    /// Issue a server certificate for DNS names or IP addresses, the first one is also its
+    /// common name and the name of its files.
+    IssueServer {
+        #[clap(required = true)]
+        dns: Vec<String>,
+
+        /// Days the certificate is valid for.
+        #[clap(long, default_value_t = 365)]
+        days: u32,
+    },
+
+    /// List certificates issued by the CA.
+    List,
+}
+
+impl ServerArgs {
+    /// Listeners given by `--listen`, or the server address (with mTLS if it's enabled) and
+    /// `--ws-address` if there are none.
+    pub fn listeners(&self) -> Vec<ListenArg> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+
+        let tls = match self.common.server_address {
+            Address::Tcp(_) if cfg!(feature = "mtls") => Some(TlsOverrides::default()),
+            _ => None,
+        };
+        #[cfg_attr(not(feature = "ws"), allow(unused_mut))]
+        let mut listeners = vec![ListenArg {
+            address: self.common.server_address.clone(),
+            websocket: false,
+            tls,
+        }];
+
+        #[cfg(feature = "ws")]
+        if let Some(address) = self.ws_address {
+            listeners.push(ListenArg {
+                address: Address::Tcp(address),
+                websocket: true,
+                tls: cfg!(feature = "mtls").then(TlsOverrides::default),
+            });
+        }
+
+        listeners
+    }
+}
+
+/// Listener given with `--listen`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenArg {
+    pub address: Address,
+    /// Frames are carried in binary WebSocket messages.
+    pub websocket: bool,
+    /// Clients are authenticated with mTLS, `None` for plaintext.
+    pub tls: Option<TlsOverrides>,
+}
+
+/// TLS settings of one listener, those not given are taken from `MtlsArgs`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsOverrides {
+    pub cert: Option<path::PathBuf>,
+    pub key: Option<path::PathBuf>,
+    pub ca_cert: Option<path::PathBuf>,
+    /// Replace `--crl` if there are any.
+    pub crls: Vec<path::PathBuf>,
+}
+
+impl fmt::Display for ListenArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match (self.websocket, self.tls.is_some()) {
+            (false, false) => "tcp://",
+            (false, true) => "mtls://",
+            (true, false) => "ws://",
+            (true, true) => "wss://",
+        };
+
+        match &self.address {
+            Address::Tcp(address) => write!(f, "{scheme}{address}"),
+            Address::Unix(_) if self.websocket => write!(f, "ws://{}", self.address),
+            Address::Unix(_) => write!(f, "{}", self.address),
+        }
+    }
+}
+
+pub fn parse_listen(arg: &str) -> anyhow::Result<ListenArg> {
+    let (address, options) = match arg.split_once(',') {
+        Some((address, options)) => (address, Some(options)),
+        None => (arg, None),
+    };
+
+    let (websocket, tls, address) = match address.split_once("://") {
+        Some(("tcp", address)) => (false, false, address),
+        Some(("mtls", address)) => (false, true, address),
+        Some(("ws", address)) => (true, false, address),
+        Some(("wss", address)) => (true, true, address),
+        Some((scheme, _)) => anyhow::bail!("unknown scheme {scheme}://"),
+        None if address.starts_with("unix:") => (false, false, address),
+        // Plaintext has to be asked for, e.g. a forgotten mtls:// mustn't disable TLS
+        None => anyhow::bail!("{address} has no scheme, use tcp://{address} for plaintext TCP"),
+    };
+    let address = common::cli::parse_address(address)?;
+
+    if tls && matches!(address, Address::Unix(_)) {
+        anyhow::bail!("Unix domain sockets don't use TLS");
+    }
+    if tls && !cfg!(feature = "mtls") {
+        anyhow::bail!("server is built without mTLS support");
+    }
+    if websocket && !cfg!(feature = "ws") {
+        anyhow::bail!("server is built without WebSocket support");
+    }
+
+    let mut overrides = TlsOverrides::default();
+    for option in options.into_iter().flat_map(|options| options.split(',')) {
+        let Some((name, value)) = option.split_once('=') else {
+            anyhow::bail!("option {option} has no value");
+        };
+
+        let value = path::PathBuf::from(value);
+        match name {
+            _ if !tls => anyhow::bail!("only mtls:// and wss:// listeners take options"),
+            "cert" => overrides.cert = Some(value),
+            "key" => overrides.key = Some(value),
+            "ca-cert" => overrides.ca_cert = Some(value),
+            "crl" => overrides.crls.push(value),
+            _ => anyhow::bail!("unknown option {name}"),
+        }
+    }
+
+    Ok(ListenArg {
+        address,
+        websocket,
+        tls: tls.then_some(overrides),
+    })
 }
 
 #[cfg(feature = "mtls")]
 #[derive(clap::Parser, Debug, Clone)]
 pub struct MtlsArgs {
-    /// Path to the server's certificate.
+    /// Path to the server's certificate chain in PEM or a PKCS#12 bundle with its key.
     #[clap(long, default_value = "../ssl/server-localhost.bundle.crt")]
     pub cert: path::PathBuf,
 
-    /// Path to the server's private key.
+    /// Path to the server's private key in PEM, unused with a PKCS#12 bundle.
     #[clap(long, default_value = "../ssl/server-localhost.key")]
     pub key: path::PathBuf,
 
     /// Path to the CA certificate used for authenticating clients.
     #[clap(long, default_value = "../ssl/ca.crt")]
     pub ca_cert: path::PathBuf,
+
+    /// Path to a certificate revocation list issued by the CA in PEM, can be repeated. Clients
+    /// with revoked certificates are refused. Reloaded on SIGHUP.
+    #[clap(long = "crl")]
+    pub crls: Vec<path::PathBuf>,
+
+    /// Check the certificate, key, CA and CRL files every this many seconds and reload TLS
+    /// settings once they change.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub tls_watch_interval: Option<u64>,
+
+    /// Which nicknames clients may use: any, only names their certificate was issued to
+    /// (restrict), or the certificate's name set when they connect (bind).
+    #[clap(long, value_enum, default_value_t)]
+    pub nickname_policy: crate::msg_exec::NicknamePolicy,
+}
+
+#[cfg(feature = "mtls")]
+impl MtlsArgs {
+    /// Settings of a listener, `overrides` replace those given.
+    pub fn with_overrides(&self, overrides: &TlsOverrides) -> Self {
+        Self {
+            cert: overrides.cert.clone().unwrap_or_else(|| self.cert.clone()),
+            key: overrides.key.clone().unwrap_or_else(|| self.key.clone()),
+            ca_cert: overrides
+                .ca_cert
+                .clone()
+                .unwrap_or_else(|| self.ca_cert.clone()),
+            crls: if overrides.crls.is_empty() {
+                self.crls.clone()
+            } else {
+                overrides.crls.clone()
+            },
+            ..self.clone()
+        }
+    }
+}
+
+/// Limits on frames received from clients. Clients sending larger frames are disconnected.
+#[derive(clap::Parser, Debug, Clone)]
+pub struct LimitsArgs {
+    /// Maximum size of a control frame (any message except file chunks) in bytes.
+    #[clap(long, default_value_t = handshake::DEFAULT_MAX_CONTROL_FRAME_SIZE)]
+    pub max_control_frame_size: u64,
+
+    /// Maximum size of a frame carrying a file chunk in bytes.
+    #[clap(long, default_value_t = handshake::DEFAULT_MAX_CHUNK_FRAME_SIZE)]
+    pub max_chunk_frame_size: u64,
+
+    /// Close connections that don't send a complete frame for this many seconds.
+    #[clap(long, default_value_t = handshake::DEFAULT_IDLE_TIMEOUT_SECS)]
+    pub idle_timeout: u64,
+
+    /// Close connections that take longer than this many seconds to send a frame once they start.
+    #[clap(long, default_value_t = crate::server::DEFAULT_FRAME_READ_TIMEOUT.as_secs())]
+    pub frame_read_timeout: u64,
+}
+
+impl From<&LimitsArgs> for handshake::Limits {
+    fn from(args: &LimitsArgs) -> Self {
+        Self {
+            max_control_frame_size: args.max_control_frame_size,
+            max_chunk_frame_size: args.max_chunk_frame_size,
+            idle_timeout_secs: Some(args.idle_timeout),
+        }
+    }
+}
+
+impl From<&LimitsArgs> for Timeouts {
+    fn from(args: &LimitsArgs) -> Self {
+        Self {
+            idle: time::Duration::from_secs(args.idle_timeout),
+            frame_read: time::Duration::from_secs(args.frame_read_timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen() {
+        let plain = parse_listen("tcp://127.0.0.1:11111").unwrap();
+        assert_eq!(
+            plain.address,
+            Address::Tcp("127.0.0.1:11111".parse().unwrap())
+        );
+        assert_eq!(plain.tls, None);
+        assert_eq!(plain.to_string(), "tcp://127.0.0.1:11111");
+
+        let unix = parse_listen("unix:/run/chat.sock").unwrap();
+        assert_eq!(unix.address, Address::Unix("/run/chat.sock".into()));
+        assert_eq!(unix.to_string(), "unix:/run/chat.sock");
+
+        assert!(parse_listen("udp://127.0.0.1:11111").is_err());
+        assert!(parse_listen("127.0.0.1:11111").is_err());
+        assert!(parse_listen("tcp://127.0.0.1:11111,cert=server.crt").is_err());
+    }
+
+    #[cfg(feature = "mtls")]
+    #[test]
+    fn test_parse_listen_tls_overrides() {
+        let listen = parse_listen("mtls://0.0.0.0:11112,cert=public.crt,key=public.key").unwrap();
+
+        assert!(!listen.websocket);
+        assert_eq!(
+            listen.tls,
+            Some(TlsOverrides {
+                cert: Some("public.crt".into()),
+                key: Some("public.key".into()),
+                ca_cert: None,
+                crls: vec![],
+            })
+        );
+
+        let listen = parse_listen("wss://0.0.0.0:11113,crl=a.crl,crl=b.crl").unwrap();
+        assert_eq!(
+            listen.tls.unwrap().crls,
+            [path::PathBuf::from("a.crl"), path::PathBuf::from("b.crl")]
+        );
+
+        assert!(parse_listen("mtls://0.0.0.0:11112,password=x").is_err());
+        assert!(parse_listen("mtls://unix:/run/chat.sock").is_err());
+    }
 }
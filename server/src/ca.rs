@@ -0,0 +1,344 @@
+use std::{fmt, fs, io::Write, path};
+
+use anyhow::Context;
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose,
+};
+use sha2::Digest;
+
+use crate::args::{CaArgs, CaCommand};
+
+const CA_CERT: &str = "ca.crt";
+const CA_KEY: &str = "ca.key";
+/// Certificates issued by the CA, including its own, as JSON.
+const INDEX: &str = "index.json";
+
+pub fn run(args: &CaArgs) -> anyhow::Result<()> {
+    let dir = &args.dir;
+
+    match &args.command {
+        CaCommand::Init { name, days } => {
+            let issued = init(dir, name, *days)?;
+            println!("Created CA {}: {}", issued.name, issued.fingerprint);
+            println!("Pass --ca-cert {}", dir.join(CA_CERT).display());
+        }
+        CaCommand::IssueClient { nickname, days } => {
+            let issued = issue_client(dir, nickname, *days)?;
+            print_issued(dir, &issued);
+        }
+        CaCommand::IssueServer { dns, days } => {
+            let issued = issue_server(dir, dns, *days)?;
+            print_issued(dir, &issued);
+        }
+        CaCommand::List => {
+            println!(
+                "{:>6}  {:<6}  {:<19}  {:<64}  NAME",
+                "SERIAL", "KIND", "NOT AFTER", "FINGERPRINT"
+            );
+            for issued in load_index(dir)? {
+                println!(
+                    "{:>6}  {:<6}  {}  {}  {}",
+                    issued.serial,
+                    issued.kind.to_string(),
+                    issued.not_after.format("%Y-%m-%d %H:%M:%S"),
+                    issued.fingerprint,
+                    issued.name
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_issued(dir: &path::Path, issued: &Issued) {
+    println!(
+        "Issued {} certificate {} for {}: {}",
+        issued.kind, issued.serial, issued.name, issued.fingerprint
+    );
+    println!(
+        "Pass --cert {} --key {}",
+        dir.join(format!("{}.crt", issued.name)).display(),
+        dir.join(format!("{}.key", issued.name)).display()
+    );
+}
+
+/// Certificate recorded in the CA's index.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Issued {
+    pub serial: u64,
+    pub kind: Kind,
+    /// Common name of the certificate.
+    pub name: String,
+    pub not_after: chrono::DateTime<chrono::Utc>,
+    /// Hex SHA-256 of the DER certificate, as stored with messages of mTLS clients.
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Ca,
+    Client,
+    Server,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ca => write!(f, "ca"),
+            Self::Client => write!(f, "client"),
+            Self::Server => write!(f, "server"),
+        }
+    }
+}
+
+/// Creates the CA's key and self-signed certificate in `dir`.
+pub fn init(dir: &path::Path, name: &str, days: u32) -> anyhow::Result<Issued> {
+    let cert_path = dir.join(CA_CERT);
+    let key_path = dir.join(CA_KEY);
+    ensure_missing(&[&cert_path, &key_path, &dir.join(INDEX)])?;
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name = common_name(name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    let serial = 1;
+    let not_after = set_validity(&mut params, serial, days)?;
+
+    let key = KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    write_new(&key_path, &key.serialize_pem(), 0o600)?;
+    write_new(&cert_path, &cert.pem(), 0o644)?;
+
+    let issued = Issued {
+        serial,
+        kind: Kind::Ca,
+        name: name.to_string(),
+        not_after,
+        fingerprint: fingerprint(cert.der()),
+    };
+    save_index(dir, std::slice::from_ref(&issued))?;
+
+    Ok(issued)
+}
+
+/// Issues a certificate for client `nickname`, which is its common name, so the server can
+/// bind the nickname to it.
+pub fn issue_client(dir: &path::Path, nickname: &str, days: u32) -> anyhow::Result<Issued> {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = common_name(nickname);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+
+    issue(dir, Kind::Client, nickname, params, days)
+}
+
+/// Issues a certificate for a server reachable at DNS names or IP addresses `names`.
+pub fn issue_server(dir: &path::Path, names: &[String], days: u32) -> anyhow::Result<Issued> {
+    let Some(name) = names.first() else {
+        anyhow::bail!("server certificate needs at least one name");
+    };
+
+    let mut params = CertificateParams::new(names)?;
+    params.distinguished_name = common_name(name);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+
+    issue(dir, Kind::Server, name, params, days)
+}
+
+/// Signs `params` with a new key and writes the certificate and key as `<name>.crt` and
+/// `<name>.key`.
+fn issue(
+    dir: &path::Path,
+    kind: Kind,
+    name: &str,
+    mut params: CertificateParams,
+    days: u32,
+) -> anyhow::Result<Issued> {
+    check_name(name)?;
+    let cert_path = dir.join(format!("{name}.crt"));
+    let key_path = dir.join(format!("{name}.key"));
+    ensure_missing(&[&cert_path, &key_path])?;
+
+    let (ca, ca_key) = load_ca(dir)?;
+    let mut index = load_index(dir)?;
+
+    let serial = index.iter().map(|issued| issued.serial).max().unwrap_or(0) + 1;
+    let not_after = set_validity(&mut params, serial, days)?;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+
+    let key = KeyPair::generate()?;
+    let cert = params.signed_by(&key, &ca, &ca_key)?;
+
+    write_new(&key_path, &key.serialize_pem(), 0o600)?;
+    write_new(&cert_path, &cert.pem(), 0o644)?;
+
+    let issued = Issued {
+        serial,
+        kind,
+        name: name.to_string(),
+        not_after,
+        fingerprint: fingerprint(cert.der()),
+    };
+    index.push(issued.clone());
+    save_index(dir, &index)?;
+
+    Ok(issued)
+}
+
+/// CA certificate to sign with and its key. The key may be encrypted like any key the server
+/// loads.
+fn load_ca(dir: &path::Path) -> anyhow::Result<(rcgen::Certificate, KeyPair)> {
+    let cert_path = dir.join(CA_CERT);
+    let key_path = dir.join(CA_KEY);
+
+    let pem = fs::read_to_string(&cert_path).with_context(|| {
+        format!(
+            "failed to read {}, create the CA with `ca init`",
+            cert_path.display()
+        )
+    })?;
+    let params = CertificateParams::from_ca_cert_pem(&pem)
+        .with_context(|| format!("invalid CA certificate {}", cert_path.display()))?;
+
+    let key = common::tls::load_keys(&key_path, &common::tls::KeyPassword::default())?;
+    let key = KeyPair::try_from(&key)
+        .with_context(|| format!("unsupported CA key {}, expected PKCS#8", key_path.display()))?;
+
+    // Issued certificates only take the subject and key identifier from it
+    let cert = params.self_signed(&key)?;
+
+    Ok((cert, key))
+}
+
+fn load_index(dir: &path::Path) -> anyhow::Result<Vec<Issued>> {
+    let path = dir.join(INDEX);
+    let data = fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+
+    serde_json::from_slice(&data).with_context(|| format!("invalid index {}", path.display()))
+}
+
+fn save_index(dir: &path::Path, index: &[Issued]) -> anyhow::Result<()> {
+    let path = dir.join(INDEX);
+    let data = serde_json::to_string_pretty(index)?;
+
+    fs::write(&path, data).with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn common_name(name: &str) -> DistinguishedName {
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, name);
+    dn
+}
+
+/// Sets the serial number and makes the certificate valid from now for `days`. Returns when it
+/// expires.
+fn set_validity(
+    params: &mut CertificateParams,
+    serial: u64,
+    days: u32,
+) -> anyhow::Result<chrono::DateTime<chrono::Utc>> {
+    let now = time::OffsetDateTime::now_utc().replace_nanosecond(0)?;
+    let not_after = now + time::Duration::days(days.into());
+
+    params.serial_number = Some(serial.into());
+    params.not_before = now;
+    params.not_after = not_after;
+
+    chrono::DateTime::from_timestamp(not_after.unix_timestamp(), 0)
+        .context("certificate expires too late")
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    hex::encode(sha2::Sha256::digest(der))
+}
+
+/// Names become file names, so they must not point outside of the CA's directory.
+fn check_name(name: &str) -> anyhow::Result<()> {
+    let invalid = name.is_empty()
+        || name.starts_with('.')
+        || name.contains(['/', '\\', '\0'])
+        || name.chars().any(char::is_control);
+
+    match invalid {
+        true => anyhow::bail!("invalid name {name:?}"),
+        false => Ok(()),
+    }
+}
+
+/// Issued keys are never overwritten.
+fn ensure_missing(paths: &[&path::Path]) -> anyhow::Result<()> {
+    match paths.iter().find(|path| path.exists()) {
+        Some(path) => anyhow::bail!("{} already exists", path.display()),
+        None => Ok(()),
+    }
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn write_new(path: &path::Path, contents: &str, mode: u32) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls_pki_types::UnixTime;
+    use tokio_rustls::rustls;
+
+    use super::*;
+
+    #[test]
+    fn test_issue_certificates() {
+        let dir = std::env::temp_dir().join(format!("chat-ca-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        init(&dir, "Test CA", 30).unwrap();
+        let alice = issue_client(&dir, "alice", 1).unwrap();
+        let server = issue_server(&dir, &["localhost".into(), "127.0.0.1".into()], 1).unwrap();
+
+        assert_eq!((alice.serial, server.serial), (2, 3));
+        assert_eq!(load_index(&dir).unwrap().len(), 3);
+        assert!(issue_client(&dir, "alice", 1).is_err());
+        assert!(issue_client(&dir, "../alice", 1).is_err());
+
+        // Loadable like any certificate and key, and trusted by the server's verifier
+        let password = common::tls::KeyPassword::default();
+        let (certs, key) =
+            common::tls::load_identity(&dir.join("alice.crt"), &dir.join("alice.key"), &password)
+                .unwrap();
+        let der = certs[0].clone();
+        let roots = common::tls::load_root_certs(&dir.join(CA_CERT)).unwrap();
+        let verifier = rustls::server::WebPkiClientVerifier::builder(roots.into())
+            .build()
+            .unwrap();
+        verifier
+            .verify_client_cert(&der, &[], UnixTime::now())
+            .unwrap();
+        rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .unwrap();
+
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).unwrap();
+        let common_name = cert.subject().iter_common_name().next().unwrap();
+        assert_eq!(common_name.as_str().unwrap(), "alice");
+        assert_eq!(alice.fingerprint, fingerprint(&der));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,16 @@
 use std::num::NonZeroUsize;
 
+use common::proto::request::HistoryPosition;
 use diesel::prelude::*;
 use uuid::Uuid;
 
 use crate::schema::{message, message::dsl::*};
-use crate::web::FullMessage;
+use crate::web::{FullMessage, MessageNotFound};
 
+/// Nickname messages of clients that didn't announce one are stored with.
+pub const ANONYMOUS: &str = "ANON";
+
+#[derive(Clone)]
 pub struct Repository {
     pool: diesel_async::pooled_connection::deadpool::Pool<diesel_async::AsyncPgConnection>,
 }
@@ -24,6 +29,7 @@ impl crate::web::Repository for Repository {
     async fn get_messages(
         &self,
         username: Option<String>,
+        room_name: Option<String>,
         offset: usize,
         limit: NonZeroUsize,
     ) -> anyhow::Result<Vec<(Message, Option<MessageText>, Option<MessageFile>)>> {
@@ -46,12 +52,166 @@ impl crate::web::Repository for Repository {
             query = query.filter(user_nickname.eq(username));
         }
 
+        if let Some(room_name) = room_name {
+            query = query.filter(room.eq(room_name));
+        }
+
+        // Direct messages aren't public
+        query = query
+            .filter(recipient.is_null())
+            .filter(retracted_at.is_null());
+
         let mut conn = self.pool.get().await?;
         let messages = diesel_async::RunQueryDsl::load(query, &mut conn).await?;
 
         Ok(messages)
     }
 
+    async fn get_history(
+        &self,
+        username: Option<String>,
+        room_name: Option<String>,
+        position: HistoryPosition,
+        limit: NonZeroUsize,
+    ) -> anyhow::Result<Vec<FullMessage>> {
+        let mut conn = self.pool.get().await?;
+
+        let anchor = match position {
+            HistoryPosition::Latest => None,
+            HistoryPosition::Before(id) | HistoryPosition::After(id) => {
+                let query = message
+                    .select((timestamp, message_id))
+                    .filter(public_id.eq(id))
+                    .filter(retracted_at.is_null());
+
+                match diesel_async::RunQueryDsl::first::<(chrono::NaiveDateTime, i64)>(
+                    query, &mut conn,
+                )
+                .await
+                {
+                    Ok(anchor) => Some(anchor),
+                    Err(diesel::NotFound) => return Err(MessageNotFound(id).into()),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        };
+
+        let select = (
+            Message::as_select(),
+            Option::<MessageText>::as_select(),
+            Option::<MessageFile>::as_select(),
+        );
+
+        let mut query = message::table
+            .left_join(crate::schema::message_text::table)
+            .left_join(crate::schema::message_file::table)
+            .select(select)
+            .limit(limit.get().try_into()?)
+            .into_boxed();
+
+        if let Some(username) = username {
+            query = query.filter(user_nickname.eq(username));
+        }
+
+        // Direct messages aren't public
+        query = query
+            .filter(recipient.is_null())
+            .filter(retracted_at.is_null());
+
+        query = match room_name {
+            Some(room_name) => query.filter(room.eq(room_name)),
+            None => query.filter(room.is_null()),
+        };
+
+        // Message ID breaks ties between messages with the same timestamp
+        query = match (position, anchor) {
+            (HistoryPosition::After(_), Some((anchor_timestamp, anchor_id))) => query
+                .filter(
+                    timestamp
+                        .gt(anchor_timestamp)
+                        .or(timestamp.eq(anchor_timestamp).and(message_id.gt(anchor_id))),
+                )
+                .order((timestamp.asc(), message_id.asc())),
+            (_, Some((anchor_timestamp, anchor_id))) => query
+                .filter(
+                    timestamp
+                        .lt(anchor_timestamp)
+                        .or(timestamp.eq(anchor_timestamp).and(message_id.lt(anchor_id))),
+                )
+                .order((timestamp.desc(), message_id.desc())),
+            (_, None) => query.order((timestamp.desc(), message_id.desc())),
+        };
+
+        let mut messages: Vec<FullMessage> =
+            diesel_async::RunQueryDsl::load(query, &mut conn).await?;
+
+        if !matches!(position, HistoryPosition::After(_)) {
+            messages.reverse();
+        }
+
+        Ok(messages)
+    }
+
+    async fn get_thread(&self, id: uuid::Uuid) -> anyhow::Result<Option<Vec<FullMessage>>> {
+        let mut conn = self.pool.get().await?;
+
+        // Parents always exist, replies to deleted messages no longer refer to them. Retracted
+        // messages still hold their thread together but aren't listed.
+        let mut root = id;
+        loop {
+            let query = message.select(reply_to).filter(public_id.eq(root));
+
+            match diesel_async::RunQueryDsl::first::<Option<Uuid>>(query, &mut conn).await {
+                Ok(Some(parent)) => root = parent,
+                Ok(None) => break,
+                Err(diesel::NotFound) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut ids = vec![root];
+        let mut parents = vec![root];
+        while !parents.is_empty() {
+            let query = message
+                .select(public_id)
+                .filter(reply_to.eq_any(parents))
+                .filter(recipient.is_null());
+
+            parents = diesel_async::RunQueryDsl::load(query, &mut conn).await?;
+            ids.extend(&parents);
+        }
+
+        let select = (
+            Message::as_select(),
+            Option::<MessageText>::as_select(),
+            Option::<MessageFile>::as_select(),
+        );
+
+        let query = message::table
+            .left_join(crate::schema::message_text::table)
+            .left_join(crate::schema::message_file::table)
+            .select(select)
+            .filter(public_id.eq_any(ids))
+            .filter(recipient.is_null())
+            .filter(retracted_at.is_null())
+            .order((timestamp.asc(), message_id.asc()));
+
+        let messages = diesel_async::RunQueryDsl::load(query, &mut conn).await?;
+
+        Ok(Some(messages))
+    }
+
+    async fn has_nickname(&self, username: &str) -> anyhow::Result<bool> {
+        let query = diesel::dsl::select(diesel::dsl::exists(
+            message.filter(user_nickname.eq(username)),
+        ));
+
+        let mut conn = self.pool.get().await?;
+        let exists = diesel_async::RunQueryDsl::get_result(query, &mut conn).await?;
+
+        Ok(exists)
+    }
+
     async fn get_message_by_public_id(
         &self,
         id: uuid::Uuid,
@@ -66,7 +226,8 @@ impl crate::web::Repository for Repository {
             .left_join(crate::schema::message_text::table)
             .left_join(crate::schema::message_file::table)
             .select(select)
-            .filter(public_id.eq(id));
+            .filter(public_id.eq(id))
+            .filter(retracted_at.is_null());
 
         let mut conn = self.pool.get().await?;
 
@@ -77,6 +238,179 @@ impl crate::web::Repository for Repository {
         }
     }
 
+    async fn edit_text(
+        &self,
+        id: uuid::Uuid,
+        new_text: String,
+        edited_at: chrono::NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        use crate::schema::{message_text, message_text_revision};
+        use diesel_async::scoped_futures::ScopedFutureExt;
+        use diesel_async::AsyncConnection;
+
+        let mut conn = self.pool.get().await?;
+
+        let edited = conn
+            .transaction::<bool, diesel::result::Error, _>(|conn| {
+                async move {
+                    let query = message_text::table
+                        .inner_join(message::table)
+                        .select((message_text::message_id, message_text::text))
+                        .filter(public_id.eq(id))
+                        .filter(retracted_at.is_null())
+                        .for_update();
+
+                    let (text_id, previous) =
+                        match diesel_async::RunQueryDsl::first::<(i64, String)>(query, conn).await {
+                            Ok(row) => row,
+                            Err(diesel::NotFound) => return Ok(false),
+                            Err(e) => return Err(e),
+                        };
+
+                    let revision = NewMessageTextRevision {
+                        message_id: text_id,
+                        text: previous,
+                        replaced_at: edited_at,
+                    };
+                    diesel_async::RunQueryDsl::execute(
+                        diesel::insert_into(message_text_revision::table).values(&revision),
+                        conn,
+                    )
+                    .await?;
+
+                    diesel_async::RunQueryDsl::execute(
+                        diesel::update(message_text::table.find(text_id))
+                            .set(message_text::text.eq(new_text)),
+                        conn,
+                    )
+                    .await?;
+
+                    Ok(true)
+                }
+                .scope_boxed()
+            })
+            .await?;
+
+        Ok(edited)
+    }
+
+    async fn get_revisions(
+        &self,
+        ids: Vec<uuid::Uuid>,
+    ) -> anyhow::Result<Vec<(uuid::Uuid, MessageTextRevision)>> {
+        use crate::schema::{message_text, message_text_revision};
+
+        let query = message_text_revision::table
+            .inner_join(message_text::table.inner_join(message::table))
+            .select((public_id, MessageTextRevision::as_select()))
+            .filter(public_id.eq_any(ids))
+            .order((
+                message_text_revision::replaced_at.asc(),
+                message_text_revision::revision_id.asc(),
+            ));
+
+        let mut conn = self.pool.get().await?;
+        let revisions = diesel_async::RunQueryDsl::load(query, &mut conn).await?;
+
+        Ok(revisions)
+    }
+
+    async fn record_presence(&self, name: String, at: chrono::NaiveDateTime) -> anyhow::Result<()> {
+        use crate::schema::presence;
+
+        let row = NewPresence {
+            nickname: name,
+            last_seen: at,
+        };
+        let query = diesel::insert_into(presence::table)
+            .values(&row)
+            .on_conflict(presence::nickname)
+            .do_update()
+            .set(presence::last_seen.eq(diesel::upsert::excluded(presence::last_seen)));
+
+        let mut conn = self.pool.get().await?;
+        diesel_async::RunQueryDsl::execute(query, &mut conn).await?;
+
+        Ok(())
+    }
+
+    async fn get_last_seen(&self) -> anyhow::Result<Vec<(String, chrono::NaiveDateTime)>> {
+        use crate::schema::presence;
+
+        let query = presence::table.select((presence::nickname, presence::last_seen));
+
+        let mut conn = self.pool.get().await?;
+        let last_seen = diesel_async::RunQueryDsl::load(query, &mut conn).await?;
+
+        Ok(last_seen)
+    }
+
+    async fn mark_delivered(
+        &self,
+        id: uuid::Uuid,
+        at: chrono::NaiveDateTime,
+    ) -> anyhow::Result<()> {
+        let query = diesel::update(
+            message
+                .filter(public_id.eq(id))
+                .filter(delivered_at.is_null()),
+        )
+        .set(delivered_at.eq(at));
+
+        let mut conn = self.pool.get().await?;
+        diesel_async::RunQueryDsl::execute(query, &mut conn).await?;
+
+        Ok(())
+    }
+
+    async fn take_undelivered(
+        &self,
+        nickname: String,
+        at: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Vec<FullMessage>> {
+        let mut conn = self.pool.get().await?;
+
+        // Claimed in one statement so that concurrent connections don't deliver a message twice
+        let claim = diesel::update(
+            message
+                .filter(recipient.eq(nickname))
+                .filter(delivered_at.is_null())
+                .filter(retracted_at.is_null()),
+        )
+        .set(delivered_at.eq(at))
+        .returning(message_id);
+        let ids: Vec<i64> = diesel_async::RunQueryDsl::get_results(claim, &mut conn).await?;
+
+        let select = (
+            Message::as_select(),
+            Option::<MessageText>::as_select(),
+            Option::<MessageFile>::as_select(),
+        );
+        let query = message::table
+            .left_join(crate::schema::message_text::table)
+            .left_join(crate::schema::message_file::table)
+            .select(select)
+            .filter(message_id.eq_any(ids))
+            .order((timestamp.asc(), message_id.asc()));
+        let messages = diesel_async::RunQueryDsl::load(query, &mut conn).await?;
+
+        Ok(messages)
+    }
+
+    async fn retract(&self, id: uuid::Uuid, at: chrono::NaiveDateTime) -> anyhow::Result<bool> {
+        let query = diesel::update(
+            message
+                .filter(public_id.eq(id))
+                .filter(retracted_at.is_null()),
+        )
+        .set(retracted_at.eq(at));
+
+        let mut conn = self.pool.get().await?;
+        let retracted = diesel_async::RunQueryDsl::execute(query, &mut conn).await?;
+
+        Ok(retracted > 0)
+    }
+
     async fn delete_by_ids(&self, ids: Vec<uuid::Uuid>) -> anyhow::Result<()> {
         let mut conn = self.pool.get().await?;
         let query = diesel::delete(message.filter(public_id.eq_any(ids)));
@@ -100,7 +434,11 @@ pub struct NewMessage {
     pub public_id: Uuid,
     pub timestamp: chrono::NaiveDateTime,
     pub user_nickname: String,
-    pub user_ip: String,
+    pub peer: String,
+    pub recipient: Option<String>,
+    pub room: Option<String>,
+    pub reply_to: Option<Uuid>,
+    pub cert_fingerprint: Option<String>,
 }
 
 #[derive(Queryable, Selectable, serde::Serialize)]
@@ -112,7 +450,18 @@ pub struct Message {
     pub public_id: Uuid,
     pub timestamp: chrono::NaiveDateTime,
     pub user_nickname: String,
-    pub user_ip: String,
+    /// Where the message was sent from, see [`crate::server::PeerIdentity`].
+    pub peer: String,
+    pub recipient: Option<String>,
+    pub room: Option<String>,
+    pub reply_to: Option<Uuid>,
+    pub cert_fingerprint: Option<String>,
+    /// When a direct message reached its recipient.
+    #[serde(skip)]
+    pub delivered_at: Option<chrono::NaiveDateTime>,
+    /// When the sender retracted the message, retracted messages are kept but not shown.
+    #[serde(skip)]
+    pub retracted_at: Option<chrono::NaiveDateTime>,
 }
 
 #[derive(Insertable)]
@@ -148,3 +497,147 @@ pub struct NewMessageText {
 pub struct MessageText {
     pub text: String,
 }
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::message_text_revision)]
+pub struct NewMessageTextRevision {
+    pub message_id: i64,
+    pub text: String,
+    pub replaced_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::presence)]
+pub struct NewPresence {
+    pub nickname: String,
+    pub last_seen: chrono::NaiveDateTime,
+}
+
+/// Text of a message before it was edited.
+#[derive(Queryable, Selectable, serde::Serialize)]
+#[diesel(table_name = crate::schema::message_text_revision)]
+pub struct MessageTextRevision {
+    pub text: String,
+    /// When the text was replaced by an edit.
+    pub replaced_at: chrono::NaiveDateTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::Repository as _;
+
+    /// Repository of the migrated database in `DATABASE_URL`, tests using it are skipped without
+    /// one.
+    fn test_repository() -> Option<Repository> {
+        let db_url = std::env::var("DATABASE_URL").ok()?;
+
+        Some(Repository::new(&db_url).unwrap())
+    }
+
+    async fn insert_text(repo: &Repository, room_name: &str, at: chrono::NaiveDateTime) -> Uuid {
+        let row = NewMessage {
+            public_id: Uuid::new_v4(),
+            timestamp: at,
+            user_nickname: "alice".to_string(),
+            peer: "127.0.0.1".to_string(),
+            recipient: None,
+            room: Some(room_name.to_string()),
+            reply_to: None,
+            cert_fingerprint: None,
+        };
+
+        let mut conn = repo.pool.get().await.unwrap();
+        let id = diesel_async::RunQueryDsl::get_result::<i64>(
+            diesel::insert_into(message::table)
+                .values(&row)
+                .returning(message_id),
+            &mut conn,
+        )
+        .await
+        .unwrap();
+        let text = NewMessageText {
+            message_id: id,
+            text: format!("text at {at}"),
+        };
+        diesel_async::RunQueryDsl::execute(
+            diesel::insert_into(crate::schema::message_text::table).values(&text),
+            &mut conn,
+        )
+        .await
+        .unwrap();
+
+        row.public_id
+    }
+
+    fn ids(messages: Vec<FullMessage>) -> Vec<Uuid> {
+        messages
+            .into_iter()
+            .map(|(msg, _, _)| msg.public_id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_get_history() {
+        let Some(repo) = test_repository() else {
+            return;
+        };
+        let room_name = format!("history-{}", Uuid::new_v4());
+        let start = chrono::Utc::now().naive_utc();
+        let mut sent = Vec::new();
+        for secs in 0..3 {
+            let at = start + chrono::Duration::seconds(secs);
+            sent.push(insert_text(&repo, &room_name, at).await);
+        }
+        let history = |position, limit| {
+            let limit = NonZeroUsize::new(limit).unwrap();
+            repo.get_history(None, Some(room_name.clone()), position, limit)
+        };
+
+        let latest = history(HistoryPosition::Latest, 2).await.unwrap();
+        let before = history(HistoryPosition::Before(sent[2]), 5).await.unwrap();
+        let after = history(HistoryPosition::After(sent[0]), 1).await.unwrap();
+        let missing = Uuid::new_v4();
+        let Err(error) = history(HistoryPosition::After(missing), 1).await else {
+            panic!("history after a missing message");
+        };
+
+        repo.delete_by_ids(sent.clone()).await.unwrap();
+
+        assert_eq!(ids(latest), sent[1..]);
+        assert_eq!(ids(before), sent[..2]);
+        assert_eq!(ids(after), sent[1..2]);
+        assert_eq!(error.downcast::<MessageNotFound>().unwrap().0, missing);
+    }
+
+    #[tokio::test]
+    async fn test_retract() {
+        let Some(repo) = test_repository() else {
+            return;
+        };
+        let room_name = format!("retract-{}", Uuid::new_v4());
+        let at = chrono::Utc::now().naive_utc();
+        let id = insert_text(&repo, &room_name, at).await;
+        repo.edit_text(id, "edited".to_string(), at).await.unwrap();
+
+        let retracted = repo.retract(id, at).await.unwrap();
+        let retracted_again = repo.retract(id, at).await.unwrap();
+        let found = repo.get_message_by_public_id(id).await.unwrap().is_some();
+        let edited = repo.edit_text(id, "again".to_string(), at).await.unwrap();
+        let limit = NonZeroUsize::new(5).unwrap();
+        let history = repo
+            .get_history(None, Some(room_name), HistoryPosition::Latest, limit)
+            .await
+            .unwrap();
+        let revisions = repo.get_revisions(vec![id]).await.unwrap();
+
+        repo.delete_by_ids(vec![id]).await.unwrap();
+
+        assert!(retracted);
+        assert!(!retracted_again);
+        assert!(!found);
+        assert!(!edited);
+        assert!(history.is_empty());
+        assert_eq!(revisions.len(), 1);
+    }
+}
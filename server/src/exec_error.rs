@@ -0,0 +1,196 @@
+use std::io;
+
+use common::proto::{
+    request::MessageId,
+    response::{Error, ErrorCode},
+};
+
+use crate::{msg_exec::PersistStopped, receive_file::StreamFileError};
+
+/// Request can't be executed because of what the client asked for, unrelated to file transfers.
+#[derive(Debug, thiserror::Error)]
+pub enum ExecError {
+    #[error("Message {0} doesn't exist or has no file attached")]
+    FileNotFound(MessageId),
+    #[error("Message {0} doesn't exist")]
+    MessageNotFound(MessageId),
+    #[error("Nobody uses nickname {0:?}")]
+    UnknownRecipient(String),
+    #[error("No listener requires client certificates, so nobody can prove to be {0:?}")]
+    UnverifiedRecipient(String),
+    #[error("Invalid room name {0:?}")]
+    InvalidRoom(String),
+    #[error("Client isn't in room {0:?}")]
+    NotInRoom(String),
+    #[error("Request can't be sent to a room")]
+    NotRoomMessage,
+    #[error("Request can't be a reply")]
+    NotReplyMessage,
+    #[error("Message {0} wasn't sent to the room the reply is sent to")]
+    NotInConversation(MessageId),
+    #[error("Message {0} was sent by someone else")]
+    NotSender(MessageId),
+    #[error("Message {0} isn't a text message")]
+    NotEditable(MessageId),
+    #[error("Client's certificate wasn't issued to nickname {0:?}")]
+    NicknameNotAllowed(String),
+}
+
+impl From<ExecError> for Error {
+    fn from(error: ExecError) -> Self {
+        let code = match &error {
+            ExecError::FileNotFound(_) => ErrorCode::FileNotFound,
+            ExecError::MessageNotFound(_) => ErrorCode::MessageNotFound,
+            ExecError::UnknownRecipient(_) => ErrorCode::UnknownRecipient,
+            ExecError::UnverifiedRecipient(_) => ErrorCode::UnverifiedRecipient,
+            ExecError::InvalidRoom(_) => ErrorCode::InvalidRoom,
+            ExecError::NotInRoom(_) => ErrorCode::NotInRoom,
+            ExecError::NotRoomMessage => ErrorCode::NotRoomMessage,
+            ExecError::NotReplyMessage | ExecError::NotInConversation(_) => {
+                ErrorCode::NotReplyMessage
+            }
+            ExecError::NotSender(_) => ErrorCode::NotSender,
+            ExecError::NotEditable(_) => ErrorCode::NotEditable,
+            ExecError::NicknameNotAllowed(_) => ErrorCode::NicknameNotAllowed,
+        };
+
+        Self::new(code).with_details(error)
+    }
+}
+
+/// Maps an error of [`crate::MessageExecutor`] onto the error sent to the client.
+pub fn response_error(error: anyhow::Error) -> Error {
+    let error = match error.downcast::<StreamFileError>() {
+        Ok(error) => return error.into(),
+        Err(error) => error,
+    };
+
+    let error = match error.downcast::<ExecError>() {
+        Ok(error) => return error.into(),
+        Err(error) => error,
+    };
+
+    if error.is::<PersistStopped>() {
+        return Error::new(ErrorCode::Database)
+            .with_retryable(false)
+            .with_details(error);
+    }
+
+    if let Some(db_error) = error.downcast_ref::<diesel::result::Error>() {
+        return database_error(db_error).with_details(format!("{error:#}"));
+    }
+
+    if error.is::<diesel_async::pooled_connection::deadpool::PoolError>() {
+        return Error::new(ErrorCode::Database).with_details(format!("{error:#}"));
+    }
+
+    if find_io_error(&error).is_some() {
+        return storage_error(&error);
+    }
+
+    Error::internal(format!("{error:#}"))
+}
+
+/// Maps a failure of the server's storage onto the error sent to the client.
+pub fn storage_error(error: &anyhow::Error) -> Error {
+    let Some(io_error) = find_io_error(error) else {
+        return Error::new(ErrorCode::Storage).with_details(format!("{error:#}"));
+    };
+
+    let code = match io_error.kind() {
+        io::ErrorKind::StorageFull => ErrorCode::StorageFull,
+        io::ErrorKind::QuotaExceeded => ErrorCode::QuotaExceeded,
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+            ErrorCode::PermissionDenied
+        }
+        io::ErrorKind::InvalidFilename | io::ErrorKind::IsADirectory => ErrorCode::InvalidFilename,
+        _ => ErrorCode::Storage,
+    };
+    let retryable = matches!(
+        io_error.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::ResourceBusy
+    );
+
+    Error::new(code)
+        .with_retryable(retryable)
+        .with_details(format!("{error:#}"))
+}
+
+fn database_error(error: &diesel::result::Error) -> Error {
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+    // Failures caused by the data itself won't go away by sending the request again
+    let retryable = match error {
+        DieselError::DatabaseError(kind, _) => matches!(
+            kind,
+            DatabaseErrorKind::SerializationFailure
+                | DatabaseErrorKind::ClosedConnection
+                | DatabaseErrorKind::UnableToSendCommand
+        ),
+        DieselError::BrokenTransactionManager => true,
+        _ => false,
+    };
+
+    Error::new(ErrorCode::Database).with_retryable(retryable)
+}
+
+fn find_io_error(error: &anyhow::Error) -> Option<&io::Error> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_full() {
+        let error = anyhow::Error::new(io::Error::from(io::ErrorKind::StorageFull))
+            .context("failed to write file");
+
+        let error = response_error(error);
+
+        assert_eq!(error.code, ErrorCode::StorageFull);
+        assert!(!error.retryable);
+    }
+
+    #[test]
+    fn test_stream_file_error() {
+        let id = common::proto::request::UploadId::nil();
+        let error = response_error(StreamFileError::UploadBusy(id).into());
+
+        assert_eq!(error.code, ErrorCode::UploadBusy);
+        assert!(error.retryable);
+    }
+
+    #[test]
+    fn test_exec_error() {
+        let error = response_error(ExecError::NotInRoom("ops".to_string()).into());
+
+        assert_eq!(error.code, ErrorCode::NotInRoom);
+        assert_eq!(
+            error.details.as_deref(),
+            Some("Client isn't in room \"ops\"")
+        );
+    }
+
+    #[test]
+    fn test_persist_stopped() {
+        let error = response_error(PersistStopped.into());
+
+        assert_eq!(error.code, ErrorCode::Database);
+        assert!(!error.retryable);
+    }
+
+    #[test]
+    fn test_other_error() {
+        let error = response_error(anyhow::anyhow!("oops"));
+
+        assert_eq!(error.code, ErrorCode::Internal);
+        assert_eq!(error.details.as_deref(), Some("oops"));
+    }
+}
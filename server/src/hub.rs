@@ -0,0 +1,291 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use common::proto::response::Event;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::server::PeerAddr;
+
+// Clients that fall this far behind skip the oldest events instead of slowing down everyone else.
+const EVENT_BUFFER_SIZE: usize = 256;
+
+/// Fans out events to all connected clients and delivers direct messages to their recipients.
+/// Events that happened in a room only reach clients in the room.
+/// Cloning the hub gives another handle to the same set of subscribers.
+#[derive(Debug, Clone)]
+pub struct Hub {
+    sender: broadcast::Sender<Published>,
+    mailboxes: Arc<Mutex<HashMap<String, Mailbox>>>,
+}
+
+/// Connections receiving direct messages for one nickname, they are delivered to all of them.
+///
+/// The hub only delivers messages to connected recipients, messages for nicknames nobody is
+/// connected with are left to be delivered from the database.
+type Mailbox = Vec<mpsc::UnboundedSender<Event>>;
+
+#[derive(Debug, Clone)]
+struct Published {
+    origin: PeerAddr,
+    event: Event,
+}
+
+/// Events a single client receives. Events published by the client itself are skipped.
+pub struct Subscription {
+    address: PeerAddr,
+    receiver: broadcast::Receiver<Published>,
+    mailboxes: Arc<Mutex<HashMap<String, Mailbox>>>,
+    /// Nickname the client receives direct messages for.
+    direct: Option<(String, mpsc::UnboundedReceiver<Event>)>,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+
+        Self {
+            sender,
+            mailboxes: Arc::default(),
+        }
+    }
+
+    /// Sends `event` caused by client at `origin` to all other subscribers.
+    pub fn publish(&self, origin: PeerAddr, event: Event) {
+        // Fails only if nobody is subscribed, which is fine.
+        let _ = self.sender.send(Published { origin, event });
+    }
+
+    /// Sends `event` to all connections receiving direct messages for `nickname`, returns whether
+    /// there were any.
+    pub fn send_direct(&self, nickname: &str, event: Event) -> bool {
+        let mut mailboxes = self.mailboxes.lock().expect("poisoned lock");
+        let Some(mailbox) = mailboxes.get_mut(nickname) else {
+            return false;
+        };
+
+        mailbox.retain(|connection| connection.send(event.clone()).is_ok());
+        if !mailbox.is_empty() {
+            return true;
+        }
+
+        mailboxes.remove(nickname);
+        false
+    }
+
+    /// Subscribe client at `address` to events published after this call.
+    pub fn subscribe(&self, address: PeerAddr) -> Subscription {
+        Subscription {
+            address,
+            receiver: self.sender.subscribe(),
+            mailboxes: self.mailboxes.clone(),
+            direct: None,
+        }
+    }
+}
+
+impl Subscription {
+    /// Waits for the next event from another client, including direct messages and events in
+    /// `rooms`. Returns `None` once the hub is gone.
+    ///
+    /// Cancel safe, can be used in `tokio::select!`.
+    pub async fn recv(&mut self, rooms: &BTreeSet<String>) -> Option<Event> {
+        let direct = async {
+            match self.direct.as_mut() {
+                Some((_, direct)) => direct.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            biased;
+
+            // Sender is owned by the hub, the mailbox is never closed while it's registered.
+            Some(event) = direct => Some(event),
+            event = Self::recv_published(&mut self.receiver, self.address, rooms) => event,
+        }
+    }
+
+    /// Receives direct messages to `nickname` instead of the previous one, returns whether it
+    /// changed. Only nicknames the client has proven to own may be given.
+    pub fn set_direct(&mut self, nickname: Option<&str>) -> bool {
+        let current = self.direct.as_ref().map(|(nickname, _)| nickname.as_str());
+        if current == nickname {
+            return false;
+        }
+
+        self.unregister();
+        self.direct = nickname.map(|nickname| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let mut mailboxes = self.mailboxes.lock().expect("poisoned lock");
+            mailboxes
+                .entry(nickname.to_string())
+                .or_default()
+                .push(sender);
+
+            (nickname.to_string(), receiver)
+        });
+
+        true
+    }
+
+    /// Stops receiving direct messages, the mailbox is removed once nobody uses it.
+    fn unregister(&mut self) {
+        let Some((nickname, receiver)) = self.direct.take() else {
+            return;
+        };
+        drop(receiver);
+
+        let mut mailboxes = self.mailboxes.lock().expect("poisoned lock");
+        if let Some(mailbox) = mailboxes.get_mut(&nickname) {
+            mailbox.retain(|connection| !connection.is_closed());
+            if mailbox.is_empty() {
+                mailboxes.remove(&nickname);
+            }
+        }
+    }
+
+    async fn recv_published(
+        receiver: &mut broadcast::Receiver<Published>,
+        address: PeerAddr,
+        rooms: &BTreeSet<String>,
+    ) -> Option<Event> {
+        loop {
+            match receiver.recv().await {
+                Ok(published) if published.origin == address => continue,
+                Ok(published)
+                    if published
+                        .event
+                        .room()
+                        .is_some_and(|room| !rooms.contains(room)) =>
+                {
+                    continue
+                }
+                Ok(published) => return Some(published.event),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Client {address} missed {skipped} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Event {
+        Event::Text {
+            from: None,
+            text: text.to_string(),
+            room: None,
+            reply_to: None,
+        }
+    }
+
+    fn addr(addr: &str) -> PeerAddr {
+        PeerAddr::Ip(addr.parse().unwrap())
+    }
+
+    fn room_text(room: &str, text: &str) -> Event {
+        Event::Text {
+            from: None,
+            text: text.to_string(),
+            room: Some(room.to_string()),
+            reply_to: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_own_events_are_skipped() {
+        let alice = addr("127.0.0.1:1000");
+        let bob = addr("127.0.0.1:2000");
+
+        let no_rooms = BTreeSet::new();
+
+        let hub = Hub::new();
+        let mut alice_events = hub.subscribe(alice);
+        let mut bob_events = hub.subscribe(bob);
+
+        hub.publish(alice, text("from alice"));
+        hub.publish(bob, text("from bob"));
+        drop(hub);
+
+        assert_eq!(alice_events.recv(&no_rooms).await, Some(text("from bob")));
+        assert_eq!(alice_events.recv(&no_rooms).await, None);
+        assert_eq!(bob_events.recv(&no_rooms).await, Some(text("from alice")));
+        assert_eq!(bob_events.recv(&no_rooms).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_direct_messages() {
+        let alice = addr("127.0.0.1:1000");
+        let bob = addr("127.0.0.1:2000");
+
+        let no_rooms = BTreeSet::new();
+
+        let hub = Hub::new();
+        let mut alice_events = hub.subscribe(alice);
+        let mut bob_events = hub.subscribe(bob);
+
+        // Nobody receives messages for bob yet, they are left to the database
+        assert!(!hub.send_direct("bob", text("missed")));
+
+        assert!(bob_events.set_direct(Some("bob")));
+        assert!(!bob_events.set_direct(Some("bob")));
+        assert!(hub.send_direct("bob", text("direct")));
+        hub.publish(alice, text("public"));
+        assert_eq!(bob_events.recv(&no_rooms).await, Some(text("direct")));
+        assert_eq!(bob_events.recv(&no_rooms).await, Some(text("public")));
+
+        // Unused mailboxes are removed
+        assert!(bob_events.set_direct(None));
+        assert!(hub.mailboxes.lock().unwrap().is_empty());
+        assert!(!hub.send_direct("bob", text("missed")));
+
+        alice_events.set_direct(Some("alice"));
+        drop(alice_events);
+        assert!(hub.mailboxes.lock().unwrap().is_empty());
+
+        drop(hub);
+        assert_eq!(bob_events.recv(&no_rooms).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_room_events_reach_members() {
+        let alice = addr("127.0.0.1:1000");
+        let bob = addr("127.0.0.1:2000");
+        let no_rooms = BTreeSet::new();
+        let rust = BTreeSet::from(["rust".to_string()]);
+
+        let hub = Hub::new();
+        let mut bob_events = hub.subscribe(bob);
+
+        hub.publish(alice, room_text("go", "skipped"));
+        hub.publish(alice, room_text("rust", "in room"));
+        hub.publish(alice, text("public"));
+        hub.publish(alice, room_text("rust", "after leaving"));
+        drop(hub);
+
+        assert_eq!(
+            bob_events.recv(&rust).await,
+            Some(room_text("rust", "in room"))
+        );
+        assert_eq!(bob_events.recv(&no_rooms).await, Some(text("public")));
+        assert_eq!(bob_events.recv(&no_rooms).await, None);
+    }
+}
@@ -1,7 +1,11 @@
 use clap::Parser;
+use common::cli::Address;
 
 mod args;
-use args::ServerArgs;
+use args::{ListenArg, ServerArgs};
+
+#[cfg(feature = "mtls")]
+mod ca;
 
 mod msg_exec;
 use diesel::SelectableHelper;
@@ -9,8 +13,20 @@ use futures::try_join;
 pub(crate) use msg_exec::MessageExecutor;
 use msg_exec::{ExecNotification, Message};
 
+mod hub;
+pub(crate) use hub::Hub;
+
+mod presence;
+use presence::{Presence, PresenceChange};
+
+mod exec_error;
+
 mod receive_file;
-pub(crate) use receive_file::receive_streamed_file;
+pub(crate) use receive_file::StreamedFileReceiver;
+
+mod send_file;
+
+mod uploads;
 
 mod db;
 mod schema;
@@ -20,74 +36,173 @@ mod server;
 pub(crate) use server::TlsListener;
 pub(crate) use server::{Client, Server};
 
+mod tls_reload;
+use tls_reload::TlsReloader;
+
 /// Defines names of metrics according to conventions specified at <https://prometheus.io/docs/practices/naming/#metric-names>.
 mod metrics;
 mod web;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = ServerArgs::parse();
+
+    #[cfg(feature = "mtls")]
+    if let Some(args::Command::Ca(ca_args)) = &args.command {
+        return ca::run(ca_args);
+    }
+
     dotenvy::dotenv()?;
 
     common::tracing::init()?;
 
     metrics::register(prometheus::default_registry())?;
 
-    let args = ServerArgs::parse();
-    let mut listener = metrics::MeteredListener::new(get_listener(&args).await?);
-    listener.set_active_connections(crate::metrics::ACTIVE_CONNECTIONS.clone());
-    listener.set_read_metric(crate::metrics::MESSAGES_RECEIVED_BYTES.clone());
-    listener.set_write_metric(crate::metrics::MESSAGES_SENT_BYTES.clone());
+    let config = server::Config {
+        limits: (&args.limits).into(),
+        timeouts: (&args.limits).into(),
+        ..Default::default()
+    };
 
-    tracing::info!("Listening on {}", args.common.server_address);
+    let mut servers = vec![];
+    let mut tls_reloader = TlsReloader::default();
+    for listen in args.listeners() {
+        let listener = metered(bind_listener(&args, &listen, &mut tls_reloader).await?);
 
-    let mut server = Server::new(listener);
+        tracing::info!("Listening on {listen}");
 
-    let (sender, receiver) = tokio::sync::mpsc::channel(8);
-    let executor = MessageExecutor::new(args.root.clone()).with_notifications(sender);
+        servers.push(Server::new(listener).with_config(config.clone()));
+    }
+
+    #[cfg(all(feature = "mtls", unix))]
+    tokio::spawn(tls_reloader.clone().reload_on_hangup());
+    #[cfg(feature = "mtls")]
+    if let Some(secs) = args.mtls.tls_watch_interval {
+        let interval = std::time::Duration::from_secs(secs);
+        tokio::spawn(tls_reloader.clone().watch(interval));
+    }
 
+    let (sender, receiver) = tokio::sync::mpsc::channel(8);
     let db_url =
         std::env::var("DATABASE_URL").map_err(|_| anyhow::Error::msg("DATABASE_URL not set"))?;
 
     let repo = db::Repository::new(&db_url)?;
 
+    let (presence_sender, presence_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let presence = Presence::default().with_changes(presence_sender);
+    for (nickname, last_seen) in web::Repository::get_last_seen(&repo).await? {
+        presence.restore(nickname, last_seen.and_utc());
+    }
+
+    let executor = MessageExecutor::new(args.root.clone())
+        .with_notifications(sender)
+        .with_hub(Hub::new())
+        .with_presence(presence.clone())
+        .with_repository(repo.clone())
+        .with_upload_ttl(std::time::Duration::from_secs(args.upload_ttl));
+    #[cfg(feature = "mtls")]
+    let executor = executor
+        .with_nickname_policy(args.mtls.nickname_policy)
+        .with_verified_nicknames(args.listeners().iter().any(|listen| listen.tls.is_some()));
+    let executor = std::sync::Arc::new(executor);
+    tokio::spawn(executor.clone().expire_uploads());
+    tokio::spawn(presence.clone().save_periodically(presence::SAVE_INTERVAL));
+
+    // All listeners feed the same executor, clients on any of them see each other
+    let servers = futures::future::try_join_all(
+        servers
+            .iter_mut()
+            .map(|server| server.run(executor.clone())),
+    );
+
     try_join!(
         persist_to_db(&db_url, receiver),
-        server.run(executor),
-        web::run(&args, repo),
+        persist_presence(repo.clone(), presence_receiver),
+        servers,
+        web::run(&args, repo, presence, tls_reloader),
     )?;
 
     Ok(())
 }
 
-async fn get_listener(args: &ServerArgs) -> anyhow::Result<impl server::Listener> {
-    let listener = tokio::net::TcpListener::bind(args.common.server_address).await?;
+/// Binds a listener of the kind `listen` asks for. Settings of TLS listeners are loaded by
+/// `tls_reloader`, which reloads them later.
+#[cfg_attr(not(feature = "mtls"), allow(unused_variables))]
+async fn bind_listener(
+    args: &ServerArgs,
+    listen: &ListenArg,
+    tls_reloader: &mut TlsReloader,
+) -> anyhow::Result<server::AnyListener> {
+    let listener = match &listen.address {
+        Address::Tcp(address) => {
+            let listener = tokio::net::TcpListener::bind(address).await?;
+
+            match &listen.tls {
+                #[cfg(feature = "mtls")]
+                Some(overrides) => {
+                    let config = tls_reloader.load(listen, args.mtls.with_overrides(overrides))?;
+                    server::AnyListener::new(TlsListener::new(listener, config))
+                }
+                #[cfg(not(feature = "mtls"))]
+                Some(_) => anyhow::bail!("server is built without mTLS support"),
+                None => server::AnyListener::new(listener),
+            }
+        }
+        // Clients are identified by peer credentials, local connections don't use TLS
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            server::AnyListener::new(server::UnixListener::bind(path, args.unix_socket_mode)?)
+        }
+        #[cfg(not(unix))]
+        Address::Unix(_) => anyhow::bail!("Unix domain sockets aren't supported on this platform"),
+    };
+
+    #[cfg(feature = "ws")]
+    if listen.websocket {
+        // The WebSocket handshake has as long as the protocol's one
+        let handshake_timeout = std::time::Duration::from_secs(args.limits.idle_timeout);
+        let listener = server::WsListener::new(listener).with_handshake_timeout(handshake_timeout);
 
-    #[cfg(feature = "mtls")]
-    let listener = {
-        let certs = common::tls::load_certs(&args.mtls.cert)?;
-        let priv_key = common::tls::load_keys(&args.mtls.key)?;
-        let roots_store = common::tls::load_root_certs(&args.mtls.ca_cert)?;
+        return Ok(server::AnyListener::new(listener));
+    }
 
-        let verifier = rustls::server::WebPkiClientVerifier::builder(roots_store.into()).build()?;
+    Ok(listener)
+}
 
-        let tls_config = rustls::ServerConfig::builder()
-            .with_client_cert_verifier(verifier)
-            .with_single_cert(certs, priv_key)?;
+fn metered<L>(listener: L) -> metrics::MeteredListener<L> {
+    let mut listener = metrics::MeteredListener::new(listener);
+    listener.set_active_connections(crate::metrics::ACTIVE_CONNECTIONS.clone());
+    listener.set_read_metric(crate::metrics::MESSAGES_RECEIVED_BYTES.clone());
+    listener.set_write_metric(crate::metrics::MESSAGES_SENT_BYTES.clone());
 
-        TlsListener::new(listener, tls_config)
-    };
+    listener
+}
 
-    Ok(listener)
+/// Records when nicknames were last seen, so that it survives restarts.
+async fn persist_presence(
+    repo: impl web::Repository,
+    mut receiver: tokio::sync::mpsc::UnboundedReceiver<PresenceChange>,
+) -> anyhow::Result<()> {
+    while let Some(change) = receiver.recv().await {
+        let nickname = change.nickname.clone();
+
+        if let Err(err) = repo
+            .record_presence(change.nickname, change.at.naive_utc())
+            .await
+        {
+            tracing::warn!("Failed to save presence of {nickname}: {err}");
+        }
+    }
+
+    Ok(())
 }
 
 async fn persist_to_db(
     db_url: &str,
     mut receiver: tokio::sync::mpsc::Receiver<ExecNotification>,
 ) -> anyhow::Result<()> {
-    use diesel_async::scoped_futures::ScopedFutureExt;
     use diesel_async::AsyncConnection;
     use diesel_async::AsyncPgConnection;
-    use diesel_async::RunQueryDsl;
 
     let mut conn = AsyncPgConnection::establish(db_url)
         .await
@@ -95,72 +210,164 @@ async fn persist_to_db(
 
     tracing::info!("Connected to database");
 
-    while let Some(notification) = receiver.recv().await {
+    while let Some(mut notification) = receiver.recv().await {
         tracing::debug!("Received notification");
+        let public_id = notification.public_id;
+        let persisted = notification.persisted.take();
 
-        conn.transaction::<(), diesel::result::Error, _>(|conn| {
-            async move {
-                let row_message = db::NewMessage {
-                    public_id: uuid::Uuid::new_v4(),
-                    timestamp: notification.timestamp.naive_utc(),
-                    user_nickname: notification.client_nickname.unwrap_or("ANON".to_string()),
-                    user_ip: notification.client_ip.to_string(),
-                };
-
-                let inserted = diesel::insert_into(schema::message::table)
-                    .values(&row_message)
-                    .returning(db::Message::as_returning())
-                    .get_results(conn)
-                    .await?;
-
-                let row_message = inserted
-                    .into_iter()
-                    .next()
-                    .ok_or_else(|| diesel::result::Error::RollbackTransaction)?;
-
-                match notification.message {
-                    Message::Text(text) => {
-                        let row_text = db::NewMessageText {
-                            message_id: row_message.message_id,
-                            text,
-                        };
-
-                        diesel::insert_into(schema::message_text::table)
-                            .values(&row_text)
-                            .execute(conn)
-                            .await?;
-                    }
-                    Message::File {
+        // Only this message is refused, the client is told once `persisted` is dropped
+        if let Err(err) = save_notification(&mut conn, notification).await {
+            tracing::error!("Failed to save message {public_id}: {err}");
+            continue;
+        }
+
+        tracing::info!("Saved notification to DB");
+        if let Some(persisted) = persisted {
+            // Client may have disconnected in the meantime
+            let _ = persisted.send(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Saves the message of `notification` with its text or file in one transaction.
+async fn save_notification(
+    conn: &mut diesel_async::AsyncPgConnection,
+    notification: ExecNotification,
+) -> Result<(), diesel::result::Error> {
+    use diesel::{ExpressionMethods, QueryDsl};
+    use diesel_async::scoped_futures::ScopedFutureExt;
+    use diesel_async::AsyncConnection;
+    use diesel_async::RunQueryDsl;
+
+    conn.transaction::<(), diesel::result::Error, _>(|conn| {
+        async move {
+            let recipient = match &notification.message {
+                Message::Direct { recipient, .. } => Some(recipient.clone()),
+                _ => None,
+            };
+            // Parent may have been retracted since the reply was accepted
+            let reply_to = match notification.conversation.reply_to {
+                Some(parent) => diesel::select(diesel::dsl::exists(
+                    schema::message::table.filter(schema::message::public_id.eq(parent)),
+                ))
+                .get_result::<bool>(conn)
+                .await?
+                .then_some(parent),
+                None => None,
+            };
+            let row_message = db::NewMessage {
+                public_id: notification.public_id,
+                timestamp: notification.timestamp.naive_utc(),
+                user_nickname: notification
+                    .client_nickname
+                    .unwrap_or(db::ANONYMOUS.to_string()),
+                peer: notification.client_identity.to_string(),
+                cert_fingerprint: notification.cert_fingerprint,
+                recipient,
+                room: notification.conversation.room,
+                reply_to,
+            };
+
+            let inserted = diesel::insert_into(schema::message::table)
+                .values(&row_message)
+                .returning(db::Message::as_returning())
+                .get_results(conn)
+                .await?;
+
+            let row_message = inserted
+                .into_iter()
+                .next()
+                .ok_or_else(|| diesel::result::Error::RollbackTransaction)?;
+
+            match notification.message {
+                Message::Text(text) | Message::Direct { text, .. } => {
+                    let row_text = db::NewMessageText {
+                        message_id: row_message.message_id,
+                        text,
+                    };
+
+                    diesel::insert_into(schema::message_text::table)
+                        .values(&row_text)
+                        .execute(conn)
+                        .await?;
+                }
+                Message::File {
+                    filename,
+                    filepath,
+                    mime,
+                    hash,
+                    length,
+                } => {
+                    let row_file = db::NewMessageFile {
+                        message_id: row_message.message_id,
                         filename,
                         filepath,
                         mime,
-                        hash,
-                        length,
-                    } => {
-                        let row_file = db::NewMessageFile {
-                            message_id: row_message.message_id,
-                            filename,
-                            filepath,
-                            mime,
-                            length: length as i64,
-                            hash: hex::encode(&hash),
-                        };
-
-                        diesel::insert_into(schema::message_file::table)
-                            .values(&row_file)
-                            .execute(conn)
-                            .await?;
-                    }
+                        length: length as i64,
+                        hash: hex::encode(&hash),
+                    };
+
+                    diesel::insert_into(schema::message_file::table)
+                        .values(&row_file)
+                        .execute(conn)
+                        .await?;
                 }
-
-                Ok(())
             }
-            .scope_boxed()
-        })
-        .await?;
 
-        tracing::info!("Saved notification to DB");
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(
+        public_id: common::proto::request::MessageId,
+    ) -> (ExecNotification, tokio::sync::oneshot::Receiver<()>) {
+        let (persisted, saved) = tokio::sync::oneshot::channel();
+        let notification = ExecNotification {
+            public_id,
+            client_nickname: Some("alice".to_string()),
+            client_identity: server::PeerIdentity::Ip("127.0.0.1".parse().unwrap()),
+            cert_fingerprint: None,
+            timestamp: chrono::Utc::now(),
+            conversation: msg_exec::Conversation::default(),
+            message: Message::Text("hello".to_string()),
+            persisted: Some(persisted),
+        };
+
+        (notification, saved)
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_persist_failure() {
+        let Ok(db_url) = std::env::var("DATABASE_URL") else {
+            return;
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(8);
+        let writer = tokio::spawn(async move { persist_to_db(&db_url, receiver).await });
+
+        let public_id = common::proto::request::MessageId::new_v4();
+        let (first, saved) = notification(public_id);
+        sender.send(first).await.unwrap();
+        saved.await.unwrap();
+
+        // Same ID again violates the unique constraint, only this message is refused
+        let (duplicate, refused) = notification(public_id);
+        sender.send(duplicate).await.unwrap();
+        assert!(refused.await.is_err());
+
+        let (next, saved) = notification(common::proto::request::MessageId::new_v4());
+        sender.send(next).await.unwrap();
+        saved.await.unwrap();
+
+        drop(sender);
+        writer.await.unwrap().unwrap();
+    }
 }
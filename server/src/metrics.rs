@@ -29,6 +29,13 @@ lazy_static::lazy_static! {
             "Number of active connections to the server.",
         )
     ).expect("a metric");
+    pub static ref TLS_CERTIFICATE_EXPIRY: prometheus::IntGaugeVec = prometheus::IntGaugeVec::new(
+        prometheus::Opts::new(
+            "tls_certificate_expiry_timestamp_seconds",
+            "Unix time at which the certificate of a TLS listener expires.",
+        ),
+        &["listener"],
+    ).expect("a metric");
 }
 
 pub fn register(registry: &prometheus::Registry) -> Result<(), prometheus::Error> {
@@ -37,6 +44,7 @@ pub fn register(registry: &prometheus::Registry) -> Result<(), prometheus::Error
     registry.register(Box::new(MESSAGES_RECEIVED_BYTES.clone()))?;
     registry.register(Box::new(MESSAGES_SENT_BYTES.clone()))?;
     registry.register(Box::new(ACTIVE_CONNECTIONS.clone()))?;
+    registry.register(Box::new(TLS_CERTIFICATE_EXPIRY.clone()))?;
 
     Ok(())
 }
@@ -80,8 +88,8 @@ where
 {
     type Stream = MeteredStream<L::Stream>;
 
-    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, std::net::SocketAddr)> {
-        self.inner.accept_conn().await.map(|(stream, addr)| {
+    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, crate::server::Peer)> {
+        self.inner.accept_conn().await.map(|(stream, peer)| {
             let mut stream = MeteredStream::new(stream);
 
             if let Some(metric) = self.read_metric.clone() {
@@ -96,7 +104,7 @@ where
                 stream.set_active_metric(metric);
             }
 
-            (stream, addr)
+            (stream, peer)
         })
     }
 }
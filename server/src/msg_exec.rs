@@ -1,27 +1,115 @@
-use std::path;
+use std::{num::NonZeroUsize, path};
 
-use crate::{receive_streamed_file, Client};
+use common::proto::{
+    request::{HistoryQuery, MessageId, RequestId, StreamId, StreamedFile, UploadId, UploadKind},
+    response::{self, Event},
+};
+
+use crate::{
+    exec_error::ExecError, presence::Presence, receive_file::StreamFileError,
+    send_file::StreamedFileSender, server::Principal, uploads::UploadRegistry, Client, Hub,
+    StreamedFileReceiver,
+};
 
 pub struct MessageExecutor {
     root: path::PathBuf,
     on_execute: Option<tokio::sync::mpsc::Sender<ExecNotification>>,
+    hub: Option<Hub>,
+    presence: Option<Presence>,
+    repository: Option<Box<dyn crate::web::Repository>>,
+    uploads: UploadRegistry<Hash>,
+    upload_ttl: std::time::Duration,
+    nickname_policy: NicknamePolicy,
+    /// Whether clients can prove their nickname with a certificate on any listener.
+    verified_nicknames: bool,
 }
 
 #[derive(Debug)]
 pub struct ExecNotification {
+    pub public_id: MessageId,
     pub client_nickname: Option<String>,
-    pub client_ip: std::net::IpAddr,
+    /// IP address or peer credentials of the client.
+    pub client_identity: crate::server::PeerIdentity,
+    /// Fingerprint of the client's certificate.
+    pub cert_fingerprint: Option<String>,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub conversation: Conversation,
     pub message: Message,
+    /// Told once the message has been saved, it's only acknowledged to the client afterwards.
+    pub persisted: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+/// Where in the conversation a message is posted.
+#[derive(Debug, Clone, Default)]
+pub struct Conversation {
+    /// Room the message is sent to, `None` if it's sent to everyone.
+    pub room: Option<String>,
+    /// Message this one replies to.
+    pub reply_to: Option<MessageId>,
+}
+
+/// Which nicknames clients may announce, based on their certificates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NicknamePolicy {
+    /// Any nickname, certificates are only recorded with messages.
+    #[default]
+    Any,
+    /// Only names the client's certificate was issued to, its common name or alternative names.
+    /// Clients without a certificate can't announce a nickname.
+    Restrict,
+    /// Same as `restrict`, and clients are named after their certificate when they connect.
+    Bind,
 }
 
+/// Message wasn't saved, either because saving it failed or because the task persisting messages
+/// has stopped.
+#[derive(Debug, thiserror::Error)]
+#[error("message couldn't be persisted")]
+pub struct PersistStopped;
+
 type Hash = sha2::Sha256;
 
+/// Most messages returned by one history request.
+const MAX_HISTORY_LIMIT: usize = 100;
+
+/// Default for how long resumable uploads wait to be resumed.
+pub const DEFAULT_UPLOAD_TTL: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Longest room name in bytes.
+const MAX_ROOM_NAME_LEN: usize = 64;
+
+/// Whether a request has been fully handled and the client can be sent a response.
+#[derive(Debug)]
+pub enum Completion {
+    Done,
+    /// Request has been handled and the client should be sent this instead of plain success.
+    Reply(response::Message),
+    /// Request started a file transfer, it completes once all chunks are received.
+    Pending,
+    /// Client should be sent [`response::Message::Download`] followed by the file's chunks.
+    Download(response::FileInfo, StreamedFileSender),
+}
+
+/// File transfer in progress.
+pub struct Upload {
+    filename: String,
+    /// Where the file is moved once received.
+    filepath: path::PathBuf,
+    receiver: StreamedFileReceiver<Hash>,
+    start: tokio::time::Instant,
+    /// Set if the transfer is a resumable upload.
+    resumable: Option<UploadId>,
+    /// Where the file is posted once received.
+    conversation: Conversation,
+}
+
 /// Shorted representation of [`common::proto::request::Message`] for notification purposes.
 #[derive(Debug)]
 pub enum Message {
     /// Text message and its content.
     Text(String),
+    /// Text message for a single user, their nickname and the content.
+    Direct { recipient: String, text: String },
     /// File (and images) message, its filename and sha256 hash.
     File {
         filename: String,
@@ -32,11 +120,51 @@ pub enum Message {
     },
 }
 
+impl Message {
+    fn to_event(&self, nickname: Option<&str>, conversation: Conversation) -> Event {
+        let from = nickname.map(ToString::to_string);
+        let Conversation { room, reply_to } = conversation;
+
+        match self {
+            Message::Text(text) => Event::Text {
+                from,
+                text: text.clone(),
+                room,
+                reply_to,
+            },
+            Message::Direct { text, .. } => Event::Direct {
+                from,
+                text: text.clone(),
+            },
+            Message::File {
+                filename,
+                mime,
+                length,
+                ..
+            } => Event::File {
+                from,
+                filename: filename.clone(),
+                mime: mime.clone(),
+                length: *length,
+                room,
+                reply_to,
+            },
+        }
+    }
+}
+
 impl MessageExecutor {
     pub fn new(root: path::PathBuf) -> Self {
         Self {
             root,
             on_execute: None,
+            hub: None,
+            presence: None,
+            repository: None,
+            uploads: UploadRegistry::default(),
+            upload_ttl: DEFAULT_UPLOAD_TTL,
+            nickname_policy: NicknamePolicy::default(),
+            verified_nicknames: false,
         }
     }
 
@@ -48,11 +176,74 @@ impl MessageExecutor {
         self
     }
 
+    /// Publish executed messages to other clients via `hub`.
+    pub fn with_hub(mut self, hub: Hub) -> Self {
+        self.hub = Some(hub);
+        self
+    }
+
+    /// Track which clients are online in `presence`.
+    pub fn with_presence(mut self, presence: Presence) -> Self {
+        self.presence = Some(presence);
+        self
+    }
+
+    /// Look up stored messages in `repository`, e.g. when a client downloads a file.
+    pub fn with_repository(mut self, repository: impl crate::web::Repository) -> Self {
+        self.repository = Some(Box::new(repository));
+        self
+    }
+
+    /// Remove resumable uploads that haven't been resumed for `ttl`, see [`Self::expire_uploads`].
+    pub fn with_upload_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.upload_ttl = ttl;
+        self
+    }
+
+    /// Check nicknames announced by clients against their certificates.
+    #[cfg_attr(not(feature = "mtls"), allow(dead_code))]
+    pub fn with_nickname_policy(mut self, policy: NicknamePolicy) -> Self {
+        self.nickname_policy = policy;
+        self
+    }
+
+    /// Accept direct messages, only clients whose certificate was issued to the recipient's
+    /// nickname receive them, so without `verified` nobody could.
+    #[cfg_attr(not(feature = "mtls"), allow(dead_code))]
+    pub fn with_verified_nicknames(mut self, verified: bool) -> Self {
+        self.verified_nicknames = verified;
+        self
+    }
+
+    /// Names a newly connected client after its certificate if the policy binds nicknames.
+    pub fn bind_nickname<S>(&self, client: &mut Client<S>) {
+        if self.nickname_policy != NicknamePolicy::Bind {
+            return;
+        }
+
+        if let Some(name) = client.get_cert().and_then(|cert| cert.name()) {
+            let name = name.to_string();
+            tracing::info!("Client bound to nickname {name}");
+            client.set_nickname(name);
+        }
+    }
+
+    pub fn hub(&self) -> Option<&Hub> {
+        self.hub.as_ref()
+    }
+
+    pub fn presence(&self) -> Option<&Presence> {
+        self.presence.as_ref()
+    }
+
+    /// Executes request `id`. Requests starting a file transfer complete once [`Self::exec_chunk`]
+    /// receives the end of the transfer.
     pub async fn exec<S>(
         &self,
+        id: RequestId,
         msg: common::proto::request::Message,
         client: &mut Client<S>,
-    ) -> anyhow::Result<()>
+    ) -> anyhow::Result<Completion>
     where
         S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
     {
@@ -62,10 +253,43 @@ impl MessageExecutor {
 
         let start = tokio::time::Instant::now();
 
+        let (room, msg) = match msg {
+            request::Message::Room(room, msg) => {
+                check_room_name(&room)?;
+                if !client.is_in_room(&room) {
+                    return Err(ExecError::NotInRoom(room).into());
+                }
+                let postable = match msg.as_ref() {
+                    request::Message::Reply(_, reply) => is_postable(reply),
+                    msg => is_postable(msg),
+                };
+                if !postable {
+                    return Err(ExecError::NotRoomMessage.into());
+                }
+
+                (Some(room), *msg)
+            }
+            msg => (None, msg),
+        };
+
+        let (reply_to, msg) = match msg {
+            request::Message::Reply(parent, msg) => {
+                if !is_postable(&msg) {
+                    return Err(ExecError::NotReplyMessage.into());
+                }
+                self.check_parent(parent, room.as_deref()).await?;
+
+                (Some(parent), *msg)
+            }
+            msg => (None, msg),
+        };
+
+        let conversation = Conversation { room, reply_to };
+
         let notification = match msg {
-            request::Message::File(filename, data) => {
+            request::Message::File(filename, data, expected_hash) => {
                 let filepath = self.get_file_path(&filename).await?;
-                let info = receive_file::<Hash>(&filepath, &data).await?;
+                let info = receive_file::<Hash>(&filepath, &data, expected_hash.as_deref()).await?;
                 log_file_receive(start, &filename, data.len() as f64);
 
                 Some(Message::File {
@@ -76,9 +300,9 @@ impl MessageExecutor {
                     length: info.length,
                 })
             }
-            request::Message::Image(filename, data) => {
+            request::Message::Image(filename, data, expected_hash) => {
                 let filepath = self.get_image_path(&filename).await?;
-                let info = receive_file::<Hash>(&filepath, &data).await?;
+                let info = receive_file::<Hash>(&filepath, &data, expected_hash.as_deref()).await?;
                 log_file_receive(start, &filename, data.len() as f64);
 
                 Some(Message::File {
@@ -90,61 +314,541 @@ impl MessageExecutor {
                 })
             }
             request::Message::FileStream(filename, size) => {
+                check_stream_unused(id, client)?;
                 let filepath = self.get_file_path(&filename).await?;
-                let info =
-                    receive_streamed_file::<Hash, _>(&filepath, size, client.get_stream()).await?;
-                log_file_receive(start, &filename, size as f64);
+                let partial = self.get_partial_path(uuid::Uuid::new_v4()).await?;
+                let receiver = StreamedFileReceiver::create(partial, size).await?;
+                start_upload(id, filename, filepath, receiver, None, conversation, client);
 
-                Some(Message::File {
-                    filename,
-                    filepath: filepath.to_str().unwrap_or_default().to_string(),
-                    mime: info.mime,
-                    hash: info.hash,
-                    length: info.length,
-                })
+                return Ok(Completion::Pending);
             }
             request::Message::ImageStream(filename, size) => {
+                check_stream_unused(id, client)?;
                 let filepath = self.get_image_path(&filename).await?;
-                let info =
-                    receive_streamed_file::<Hash, _>(&filepath, size, client.get_stream()).await?;
-                log_file_receive(start, &filename, size as f64);
+                let partial = self.get_partial_path(uuid::Uuid::new_v4()).await?;
+                let receiver = StreamedFileReceiver::create(partial, size).await?;
+                start_upload(id, filename, filepath, receiver, None, conversation, client);
 
-                Some(Message::File {
+                return Ok(Completion::Pending);
+            }
+            request::Message::CreateUpload(kind, filename, size) => {
+                let filepath = match kind {
+                    UploadKind::File => self.get_file_path(&filename).await?,
+                    UploadKind::Image => self.get_image_path(&filename).await?,
+                };
+                let upload = self.uploads.create(
+                    client.get_principal(),
                     filename,
-                    filepath: filepath.to_str().unwrap_or_default().to_string(),
-                    mime: info.mime,
-                    hash: info.hash,
-                    length: info.length,
-                })
+                    filepath,
+                    size,
+                    conversation,
+                );
+                if let Err(err) = self.create_partial_file(upload).await {
+                    self.uploads.remove(upload);
+                    return Err(err);
+                }
+                tracing::info!("Created upload {upload} of {size} bytes");
+
+                let status = response::UploadStatus {
+                    id: upload,
+                    offset: 0,
+                    size,
+                };
+                return Ok(Completion::Reply(response::Message::Upload(status)));
+            }
+            request::Message::QueryUpload(upload) => {
+                let size = self.uploads.get(upload, &client.get_principal())?;
+                let offset = tokio::fs::metadata(self.partial_path(upload))
+                    .await
+                    .map_err(StreamFileError::fs)?
+                    .len();
+
+                let status = response::UploadStatus {
+                    id: upload,
+                    offset,
+                    size,
+                };
+                return Ok(Completion::Reply(response::Message::Upload(status)));
+            }
+            request::Message::ResumeUpload(upload, offset) => {
+                check_stream_unused(id, client)?;
+
+                let activated = self.uploads.activate(upload, &client.get_principal())?;
+                let receiver = match StreamedFileReceiver::resume(
+                    self.partial_path(upload),
+                    activated.size,
+                    offset,
+                    activated.checkpoint,
+                )
+                .await
+                {
+                    Ok(receiver) => receiver,
+                    Err(err) => {
+                        self.uploads.suspend(upload, None);
+                        return Err(err.into());
+                    }
+                };
+
+                tracing::info!("Resuming upload {upload} at {offset} bytes");
+                start_upload(
+                    id,
+                    activated.filename,
+                    activated.filepath,
+                    receiver,
+                    Some(upload),
+                    activated.conversation,
+                    client,
+                );
+
+                return Ok(Completion::Pending);
+            }
+            request::Message::Ping => {
+                return Ok(Completion::Reply(response::Message::Pong));
+            }
+            request::Message::Who => {
+                let presence = self
+                    .presence
+                    .as_ref()
+                    .ok_or_else(|| anyhow::Error::msg("presence isn't available"))?;
+
+                let users = presence.snapshot();
+                return Ok(Completion::Reply(response::Message::Presence(users)));
+            }
+            request::Message::Download(public_id) => {
+                let (info, sender) = self.open_download(public_id).await?;
+                tracing::info!("Sending {} ({} bytes)", info.filename, info.length);
+
+                return Ok(Completion::Download(info, sender));
+            }
+            request::Message::History(query) => {
+                if let Some(room) = query.room.as_deref() {
+                    // Room history is only for those who would have received the messages
+                    if !client.is_in_room(room) {
+                        return Err(ExecError::NotInRoom(room.to_string()).into());
+                    }
+                }
+                let messages = self.history(query).await?;
+                return Ok(Completion::Reply(response::Message::History(messages)));
             }
             request::Message::Text(msg) => {
                 tracing::info!("Message from: {msg}");
 
                 Some(Message::Text(msg))
             }
+            request::Message::Direct(recipient, text) => {
+                self.check_recipient(&recipient).await?;
+                tracing::info!("Direct message for {recipient}");
+
+                Some(Message::Direct { recipient, text })
+            }
             request::Message::AnnounceNickname(nickname) => {
+                self.check_nickname(client, &nickname)?;
                 client.set_nickname(&nickname);
                 tracing::info!("Client set nickname to {nickname}");
 
                 None
             }
+            request::Message::Join(room) => {
+                check_room_name(&room)?;
+                if client.join_room(&room) {
+                    tracing::info!("Client joined room {room}");
+                }
+
+                None
+            }
+            request::Message::Leave(room) => {
+                if client.leave_room(&room) {
+                    tracing::info!("Client left room {room}");
+                }
+
+                None
+            }
+            request::Message::Edit(public_id, text) => {
+                self.check_sender(public_id, &client.get_principal(), client.get_nickname())
+                    .await?;
+
+                let edited_at = chrono::Utc::now().naive_utc();
+                if !self
+                    .repository()?
+                    .edit_text(public_id, text, edited_at)
+                    .await?
+                {
+                    return Err(ExecError::NotEditable(public_id).into());
+                }
+                tracing::info!("Client edited message {public_id}");
+
+                None
+            }
+            request::Message::Retract(public_id) => {
+                self.check_sender(public_id, &client.get_principal(), client.get_nickname())
+                    .await?;
+
+                let retracted_at = chrono::Utc::now().naive_utc();
+                if !self.repository()?.retract(public_id, retracted_at).await? {
+                    return Err(ExecError::MessageNotFound(public_id).into());
+                }
+                tracing::info!("Client retracted message {public_id}");
+
+                None
+            }
+            // Envelopes are unwrapped above, they can't be nested
+            request::Message::Room(..) => return Err(ExecError::NotRoomMessage.into()),
+            request::Message::Reply(..) => return Err(ExecError::NotReplyMessage.into()),
+        };
+
+        let Some(notification) = notification else {
+            return Ok(Completion::Done);
+        };
+        let receipt = self.notify(notification, conversation, client).await?;
+
+        Ok(Completion::Reply(response::Message::Stored(receipt)))
+    }
+
+    /// Handles a chunk of file transfer `stream`.
+    pub async fn exec_chunk<S>(
+        &self,
+        stream: StreamId,
+        chunk: StreamedFile,
+        client: &mut Client<S>,
+    ) -> anyhow::Result<Completion> {
+        match chunk {
+            StreamedFile::Payload(data) => {
+                let Some(upload) = client.get_upload(stream) else {
+                    return Err(StreamFileError::UnknownStream(stream).into());
+                };
+
+                if let Err(err) = upload.receiver.push(&data).await {
+                    self.cancel_upload(stream, client).await;
+                    return Err(err.into());
+                }
+
+                Ok(Completion::Pending)
+            }
+            StreamedFile::Abort => {
+                let upload = take_upload(stream, client)?;
+
+                if let Some(id) = upload.resumable {
+                    self.uploads.remove(id);
+                }
+
+                Err(upload.receiver.abort().await.into())
+            }
+            StreamedFile::End(expected_hash) => {
+                let upload = take_upload(stream, client)?;
+
+                let resumable = upload.resumable;
+                let partial = upload.receiver.path().to_path_buf();
+                let finished = upload
+                    .receiver
+                    .finish(&upload.filepath, expected_hash.as_deref())
+                    .await;
+                let (info, filepath) = match finished {
+                    Ok(finished) => finished,
+                    Err(err) => {
+                        match (resumable, &err) {
+                            // File has been removed, there's nothing to resume.
+                            (Some(id), StreamFileError::HashMismatch { .. }) => {
+                                self.uploads.remove(id)
+                            }
+                            // Client can still send the missing data.
+                            (Some(id), _) => self.uploads.suspend(id, None),
+                            (None, _) => crate::receive_file::remove_partial_file(&partial).await,
+                        }
+                        return Err(err.into());
+                    }
+                };
+                if let Some(id) = resumable {
+                    self.uploads.remove(id);
+                }
+                log_file_receive(upload.start, &upload.filename, info.length as f64);
+
+                let notification = Message::File {
+                    filename: upload.filename,
+                    filepath: filepath.to_str().unwrap_or_default().to_string(),
+                    mime: info.mime,
+                    hash: info.hash,
+                    length: info.length,
+                };
+                let receipt = self
+                    .notify(notification, upload.conversation, client)
+                    .await?;
+
+                Ok(Completion::Reply(response::Message::Stored(receipt)))
+            }
+        }
+    }
+
+    /// Stops transfer `stream` after it failed, e.g. because the client sent too large a chunk.
+    /// Resumable uploads can be resumed from the persisted part of the file, other transfers are
+    /// removed with their partial file.
+    pub async fn cancel_upload<S>(&self, stream: StreamId, client: &mut Client<S>) {
+        let Some(upload) = client.remove_upload(stream) else {
+            return;
+        };
+
+        match upload.resumable {
+            // Whatever was written past the failure is discarded when resuming.
+            Some(id) => self.uploads.suspend(id, None),
+            None => upload.receiver.discard().await,
+        }
+    }
+
+    /// Suspends transfers the client didn't finish, e.g. because it disconnected. Resumable uploads
+    /// can be continued by another connection afterwards, other transfers are removed with their
+    /// partial file.
+    pub async fn suspend_uploads<S>(&self, client: &mut Client<S>) {
+        for (_, upload) in client.take_uploads() {
+            let Some(id) = upload.resumable else {
+                tracing::info!("Client left transfer of {} unfinished", upload.filename);
+                upload.receiver.discard().await;
+                continue;
+            };
+
+            match upload.receiver.suspend().await {
+                Ok(checkpoint) => {
+                    tracing::info!("Suspended upload {id} of {}", upload.filename);
+                    self.uploads.suspend(id, Some(checkpoint));
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to suspend upload {id}: {err}");
+                    self.uploads.suspend(id, None);
+                }
+            }
+        }
+    }
+
+    /// Periodically removes resumable uploads that haven't been resumed within the upload TTL,
+    /// together with their partially received files. Runs until the executor is dropped.
+    pub async fn expire_uploads(self: std::sync::Arc<Self>) {
+        // Uploads live at most a minute past their TTL.
+        let period = self.upload_ttl.min(std::time::Duration::from_secs(60));
+        let executor = std::sync::Arc::downgrade(&self);
+        drop(self);
+
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let Some(executor) = executor.upgrade() else {
+                return;
+            };
+            for id in executor.uploads.expire(executor.upload_ttl) {
+                crate::receive_file::remove_partial_file(&executor.partial_path(id)).await;
+            }
+        }
+    }
+
+    /// Has `message` posted to `conversation` persisted under a newly issued ID and publishes it
+    /// to other clients. The ID is only returned, and the message only published, once the message
+    /// has been saved, so that clients never learn about messages that can't be looked up.
+    async fn notify<S>(
+        &self,
+        message: Message,
+        conversation: Conversation,
+        client: &mut Client<S>,
+    ) -> anyhow::Result<response::Receipt> {
+        let receipt = response::Receipt {
+            id: MessageId::new_v4(),
+            timestamp: chrono::Utc::now(),
+        };
+
+        let event = message.to_event(client.get_nickname(), conversation.clone());
+        let recipient = match &message {
+            Message::Direct { recipient, .. } => Some(recipient.clone()),
+            _ => None,
         };
 
-        if let Some((notification, sender)) = notification.zip(self.on_execute.as_ref()) {
+        if let Some(sender) = self.on_execute.as_ref() {
+            let (persisted, saved) = tokio::sync::oneshot::channel();
             let notification = ExecNotification {
+                public_id: receipt.id,
                 client_nickname: client.get_nickname().map(ToString::to_string),
-                client_ip: client.get_address().ip(),
-                timestamp: chrono::Utc::now(),
-                message: notification,
+                client_identity: client.get_address().identity(),
+                cert_fingerprint: client.get_cert().map(|cert| cert.fingerprint.clone()),
+                timestamp: receipt.timestamp,
+                conversation,
+                message,
+                persisted: Some(persisted),
             };
 
-            sender.send(notification).await?;
+            sender
+                .send(notification)
+                .await
+                .map_err(|_| PersistStopped)?;
+            // Writer drops the sender without answering if saving fails
+            saved.await.map_err(|_| PersistStopped)?;
+        }
+
+        if let Some(hub) = self.hub.as_ref() {
+            match recipient {
+                Some(recipient) => {
+                    if hub.send_direct(&recipient, event) {
+                        self.mark_delivered(receipt.id).await;
+                    }
+                }
+                None => hub.publish(client.get_address(), event),
+            }
+        }
+
+        Ok(receipt)
+    }
+
+    /// Direct messages for `nickname` that didn't reach it yet, e.g. because it was offline,
+    /// oldest first. They count as delivered afterwards.
+    pub async fn take_held_direct(&self, nickname: &str) -> anyhow::Result<Vec<Event>> {
+        let now = chrono::Utc::now().naive_utc();
+        let messages = self
+            .repository()?
+            .take_undelivered(nickname.to_string(), now)
+            .await?;
+
+        let events = messages
+            .into_iter()
+            .filter_map(|(message, text, _)| {
+                Some(Event::Direct {
+                    from: Some(message.user_nickname),
+                    text: text?.text,
+                })
+            })
+            .collect();
+
+        Ok(events)
+    }
+
+    /// Direct message `public_id` reached its recipient. A failure is only logged, the message
+    /// is delivered again once the recipient reconnects.
+    async fn mark_delivered(&self, public_id: MessageId) {
+        let Some(repository) = self.repository.as_deref() else {
+            return;
+        };
+
+        let now = chrono::Utc::now().naive_utc();
+        if let Err(err) = repository.mark_delivered(public_id, now).await {
+            tracing::warn!("Failed to mark direct message {public_id} as delivered: {err:#}");
+        }
+    }
+
+    /// Direct messages can only be sent to nicknames that have connected or sent a message before,
+    /// otherwise typos would go unnoticed. They're refused if no client could prove to be the
+    /// recipient, they would never be delivered.
+    async fn check_recipient(&self, recipient: &str) -> anyhow::Result<()> {
+        if self.hub.is_none() {
+            return Err(anyhow::Error::msg("direct messages aren't available"));
+        }
+        if !self.verified_nicknames {
+            return Err(ExecError::UnverifiedRecipient(recipient.to_string()).into());
+        }
+
+        let connected = self
+            .presence
+            .as_ref()
+            .is_some_and(|presence| presence.knows(recipient));
+        if connected || self.repository()?.has_nickname(recipient).await? {
+            return Ok(());
+        }
+
+        Err(ExecError::UnknownRecipient(recipient.to_string()).into())
+    }
+
+    /// Nicknames may be restricted to the names in the client's certificate.
+    fn check_nickname<S>(&self, client: &Client<S>, nickname: &str) -> anyhow::Result<()> {
+        match (self.nickname_policy, client.get_cert()) {
+            (NicknamePolicy::Any, _) => Ok(()),
+            (_, Some(cert)) if cert.has_name(nickname) => Ok(()),
+            _ => Err(ExecError::NicknameNotAllowed(nickname.to_string()).into()),
+        }
+    }
+
+    /// Replies can only be sent to stored messages, in the same room and not to direct messages.
+    async fn check_parent(&self, parent: MessageId, room: Option<&str>) -> anyhow::Result<()> {
+        let (message, _, _) = self
+            .repository()?
+            .get_message_by_public_id(parent)
+            .await?
+            .ok_or(ExecError::MessageNotFound(parent))?;
+
+        if !is_in_conversation(&message, room) {
+            return Err(ExecError::NotInConversation(parent).into());
         }
 
         Ok(())
     }
 
+    /// Messages can only be changed by the client that sent them, see [`is_sender`].
+    async fn check_sender(
+        &self,
+        public_id: MessageId,
+        principal: &Principal,
+        nickname: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let (message, _, _) = self
+            .repository()?
+            .get_message_by_public_id(public_id)
+            .await?
+            .ok_or(ExecError::MessageNotFound(public_id))?;
+
+        if !is_sender(&message, principal, nickname) {
+            return Err(ExecError::NotSender(public_id).into());
+        }
+
+        Ok(())
+    }
+
+    /// Opens the file attached to message `public_id`.
+    async fn open_download(
+        &self,
+        public_id: MessageId,
+    ) -> anyhow::Result<(response::FileInfo, StreamedFileSender)> {
+        let file = self
+            .repository()?
+            .get_message_by_public_id(public_id)
+            .await?
+            .and_then(|(_, _, file)| file)
+            .ok_or(ExecError::FileNotFound(public_id))?;
+
+        let length = u64::try_from(file.length)?;
+        let sender = StreamedFileSender::open(path::Path::new(&file.filepath), length).await?;
+        let info = response::FileInfo {
+            filename: file.filename,
+            mime: file.mime,
+            length,
+            hash: hex::decode(&file.hash)?,
+        };
+
+        Ok((info, sender))
+    }
+
+    /// Stored messages matching `query`, at most [`MAX_HISTORY_LIMIT`] of them.
+    async fn history(&self, query: HistoryQuery) -> anyhow::Result<Vec<response::StoredMessage>> {
+        let limit = usize::try_from(query.limit)?.min(MAX_HISTORY_LIMIT);
+        let Some(limit) = NonZeroUsize::new(limit) else {
+            return Ok(Vec::new());
+        };
+
+        let messages = self
+            .repository()?
+            .get_history(query.nickname, query.room, query.position, limit)
+            .await
+            .map_err(|err| match err.downcast::<crate::web::MessageNotFound>() {
+                Ok(crate::web::MessageNotFound(anchor)) => {
+                    ExecError::MessageNotFound(anchor).into()
+                }
+                Err(err) => err,
+            })?;
+
+        Ok(messages.into_iter().filter_map(to_stored_message).collect())
+    }
+
+    fn repository(&self) -> anyhow::Result<&dyn crate::web::Repository> {
+        self.repository
+            .as_deref()
+            .ok_or_else(|| anyhow::Error::msg("stored messages aren't available"))
+    }
+
     async fn get_file_path(&self, filename: &str) -> anyhow::Result<path::PathBuf> {
+        check_filename(filename)?;
         let file_root = self.mk_files_dir().await?;
         Ok(file_root.join(filename))
     }
@@ -156,6 +860,7 @@ impl MessageExecutor {
     }
 
     async fn get_image_path(&self, filename: &str) -> anyhow::Result<path::PathBuf> {
+        check_filename(filename)?;
         let image_root = self.mk_images_dir().await?;
         Ok(image_root.join(filename))
     }
@@ -165,6 +870,160 @@ impl MessageExecutor {
         tokio::fs::create_dir_all(&images_dir).await?;
         Ok(images_dir)
     }
+
+    /// Creates the file resumable upload `id` is received into, it stays apart from stored files
+    /// until the upload is finished.
+    async fn create_partial_file(&self, id: UploadId) -> anyhow::Result<()> {
+        let partial = self.get_partial_path(id).await?;
+        tokio::fs::File::create(partial)
+            .await
+            .map_err(StreamFileError::fs)?;
+
+        Ok(())
+    }
+
+    async fn get_partial_path(&self, id: uuid::Uuid) -> anyhow::Result<path::PathBuf> {
+        tokio::fs::create_dir_all(self.root.join("uploads")).await?;
+        Ok(self.partial_path(id))
+    }
+
+    /// Where transfer `id` is received until it's finished, see [`StreamedFileReceiver`].
+    fn partial_path(&self, id: uuid::Uuid) -> path::PathBuf {
+        self.root.join("uploads").join(id.to_string())
+    }
+}
+
+fn to_stored_message(
+    (message, text, file): crate::web::FullMessage,
+) -> Option<response::StoredMessage> {
+    let from = Some(message.user_nickname);
+    let room = message.room;
+    let reply_to = message.reply_to;
+    let event = match (text, file) {
+        (Some(text), _) => Event::Text {
+            from,
+            text: text.text,
+            room,
+            reply_to,
+        },
+        (None, Some(file)) => Event::File {
+            from,
+            filename: file.filename,
+            mime: file.mime,
+            length: u64::try_from(file.length).ok()?,
+            room,
+            reply_to,
+        },
+        (None, None) => return None,
+    };
+
+    Some(response::StoredMessage {
+        receipt: response::Receipt {
+            id: message.public_id,
+            timestamp: message.timestamp.and_utc(),
+        },
+        event,
+    })
+}
+
+/// Filenames come from clients so they must not point outside of the target directory.
+fn check_filename(filename: &str) -> Result<(), StreamFileError> {
+    let invalid = filename.is_empty()
+        || filename == "."
+        || filename == ".."
+        || filename.contains(['/', '\\', '\0']);
+
+    match invalid {
+        true => Err(StreamFileError::InvalidFilename(filename.to_string())),
+        false => Ok(()),
+    }
+}
+
+fn check_room_name(room: &str) -> Result<(), ExecError> {
+    let valid = !room.is_empty()
+        && room.len() <= MAX_ROOM_NAME_LEN
+        && room
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+
+    match valid {
+        true => Ok(()),
+        false => Err(ExecError::InvalidRoom(room.to_string())),
+    }
+}
+
+/// Only messages that are stored and shown to other users can be sent to a room or be replies.
+fn is_postable(msg: &common::proto::request::Message) -> bool {
+    use common::proto::request::Message;
+
+    matches!(
+        msg,
+        Message::Text(_)
+            | Message::File(..)
+            | Message::Image(..)
+            | Message::FileStream(..)
+            | Message::ImageStream(..)
+            | Message::CreateUpload(..)
+    )
+}
+
+/// Whether `message` was sent where a reply to `room` goes. Replies are never direct messages, so
+/// the replied message must have no recipient either.
+fn is_in_conversation(message: &crate::db::Message, room: Option<&str>) -> bool {
+    message.recipient.is_none() && message.room.as_deref() == room
+}
+
+/// Whether the client `principal` using `nickname` sent `message`. A message sent with a
+/// certificate belongs to whoever has the certificate. Without one, only the address or local user
+/// it was sent from tells who sent it, which several users may share, so the nickname has to match
+/// as well.
+fn is_sender(message: &crate::db::Message, principal: &Principal, nickname: Option<&str>) -> bool {
+    let sender = Principal::from_stored(message.cert_fingerprint.as_deref(), &message.peer);
+
+    match sender {
+        Some(sender @ Principal::Cert(_)) => sender == *principal,
+        Some(sender) => {
+            sender == *principal
+                && nickname == Some(message.user_nickname.as_str())
+                && message.user_nickname != crate::db::ANONYMOUS
+        }
+        None => false,
+    }
+}
+
+fn check_stream_unused<S>(id: RequestId, client: &mut Client<S>) -> anyhow::Result<()> {
+    if client.get_upload(id).is_some() {
+        return Err(StreamFileError::StreamInUse(id).into());
+    }
+
+    Ok(())
+}
+
+/// Stops tracking transfer `stream` on the client's connection to finish it.
+fn take_upload<S>(stream: StreamId, client: &mut Client<S>) -> Result<Upload, StreamFileError> {
+    client
+        .remove_upload(stream)
+        .ok_or(StreamFileError::UnknownStream(stream))
+}
+
+fn start_upload<S>(
+    id: RequestId,
+    filename: String,
+    filepath: path::PathBuf,
+    receiver: StreamedFileReceiver<Hash>,
+    resumable: Option<UploadId>,
+    conversation: Conversation,
+    client: &mut Client<S>,
+) {
+    let upload = Upload {
+        filename,
+        filepath,
+        receiver,
+        start: tokio::time::Instant::now(),
+        resumable,
+        conversation,
+    };
+    client.add_upload(id, upload);
 }
 
 fn log_file_receive(start: tokio::time::Instant, filename: &str, filesize: f64) {
@@ -188,16 +1047,20 @@ pub struct StreamInfo {
 async fn receive_file<H: sha2::Digest>(
     filepath: &path::Path,
     data: &[u8],
+    expected_hash: Option<&[u8]>,
 ) -> anyhow::Result<StreamInfo> {
     use tokio::io::AsyncWriteExt;
 
-    let mut file = tokio::fs::File::create(filepath).await?;
-    file.write_all(data).await?;
-
     let mut hasher = H::new();
     hasher.update(data);
 
     let hash = hasher.finalize().to_vec();
+    // Checked before writing so that a corrupted file never replaces an existing one.
+    crate::receive_file::verify_hash(&hash, expected_hash)?;
+
+    let mut file = tokio::fs::File::create(filepath).await?;
+    file.write_all(data).await?;
+
     let info = StreamInfo {
         length: data.len() as u64,
         hash,
@@ -206,3 +1069,212 @@ async fn receive_file<H: sha2::Digest>(
 
     Ok(info)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{CertIdentity, Peer, PeerAddr};
+
+    fn client(cert: Option<&str>) -> Client<tokio::io::DuplexStream> {
+        let cert = cert.map(|name| CertIdentity {
+            common_name: Some(name.to_string()),
+            alt_names: vec![format!("{name}@example.com")],
+            fingerprint: "aa".to_string(),
+        });
+        let peer = Peer {
+            addr: PeerAddr::Ip("10.0.0.1:4000".parse().unwrap()),
+            cert,
+        };
+        let (stream, _) = tokio::io::duplex(64);
+
+        Client::new(peer, stream)
+    }
+
+    async fn announce<S>(
+        executor: &MessageExecutor,
+        client: &mut Client<S>,
+        nickname: &str,
+    ) -> anyhow::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let msg = common::proto::request::Message::AnnounceNickname(nickname.to_string());
+        executor.exec(0, msg, client).await.map(|_| ())
+    }
+
+    fn is_not_allowed(result: anyhow::Result<()>) -> bool {
+        matches!(
+            result.map_err(|err| err.downcast::<ExecError>()),
+            Err(Ok(ExecError::NicknameNotAllowed(_)))
+        )
+    }
+
+    #[tokio::test]
+    async fn test_restrict_nickname() {
+        let executor = MessageExecutor::new(path::PathBuf::new())
+            .with_nickname_policy(NicknamePolicy::Restrict);
+        let mut alice = client(Some("alice"));
+        let mut anonymous = client(None);
+
+        announce(&executor, &mut alice, "alice").await.unwrap();
+        announce(&executor, &mut alice, "alice@example.com")
+            .await
+            .unwrap();
+        assert_eq!(alice.get_nickname(), Some("alice@example.com"));
+
+        assert!(is_not_allowed(announce(&executor, &mut alice, "bob").await));
+        assert_eq!(alice.get_nickname(), Some("alice@example.com"));
+
+        assert!(is_not_allowed(
+            announce(&executor, &mut anonymous, "alice").await
+        ));
+        assert_eq!(anonymous.get_nickname(), None);
+    }
+
+    #[tokio::test]
+    async fn test_bind_nickname() {
+        let executor =
+            MessageExecutor::new(path::PathBuf::new()).with_nickname_policy(NicknamePolicy::Bind);
+        let mut alice = client(Some("alice"));
+        let mut anonymous = client(None);
+
+        executor.bind_nickname(&mut alice);
+        executor.bind_nickname(&mut anonymous);
+        assert_eq!(alice.get_nickname(), Some("alice"));
+        assert_eq!(anonymous.get_nickname(), None);
+
+        assert!(is_not_allowed(announce(&executor, &mut alice, "bob").await));
+        assert_eq!(alice.get_nickname(), Some("alice"));
+    }
+
+    #[test]
+    fn test_any_nickname() {
+        let executor = MessageExecutor::new(path::PathBuf::new());
+        let mut alice = client(Some("alice"));
+
+        executor.bind_nickname(&mut alice);
+        assert_eq!(alice.get_nickname(), None);
+        assert!(executor.check_nickname(&client(None), "bob").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_create_upload_keeps_stored_file() {
+        let root = std::env::temp_dir().join(format!("exec-upload-{}", std::process::id()));
+        let stored = root.join("files").join("notes.txt");
+        tokio::fs::create_dir_all(stored.parent().unwrap())
+            .await
+            .unwrap();
+        tokio::fs::write(&stored, b"stored").await.unwrap();
+
+        let executor = MessageExecutor::new(root.clone());
+        let mut alice = client(None);
+        let msg = common::proto::request::Message::CreateUpload(
+            UploadKind::File,
+            "notes.txt".to_string(),
+            6,
+        );
+        let Completion::Reply(response::Message::Upload(status)) =
+            executor.exec(0, msg, &mut alice).await.unwrap()
+        else {
+            panic!("expected upload status");
+        };
+
+        assert_eq!(tokio::fs::read(&stored).await.unwrap(), b"stored");
+        assert!(executor.partial_path(status.id).exists());
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_direct_needs_verified_nicknames() {
+        let presence = Presence::default();
+        presence.restore("bob".to_string(), chrono::Utc::now());
+        let executor = MessageExecutor::new(path::PathBuf::new())
+            .with_hub(Hub::new())
+            .with_presence(presence);
+        let mut alice = client(None);
+        let direct =
+            || common::proto::request::Message::Direct("bob".to_string(), "hi".to_string());
+
+        let result = executor.exec(0, direct(), &mut alice).await;
+        assert!(matches!(
+            result.map_err(|err| err.downcast::<ExecError>()),
+            Err(Ok(ExecError::UnverifiedRecipient(_)))
+        ));
+
+        let executor = executor.with_verified_nicknames(true);
+        let result = executor.exec(0, direct(), &mut alice).await.unwrap();
+        assert!(matches!(
+            result,
+            Completion::Reply(response::Message::Stored(_))
+        ));
+    }
+
+    fn message(cert_fingerprint: Option<&str>, peer: &str) -> crate::db::Message {
+        crate::db::Message {
+            message_id: 1,
+            public_id: MessageId::new_v4(),
+            timestamp: chrono::Utc::now().naive_utc(),
+            user_nickname: "alice".to_string(),
+            peer: peer.to_string(),
+            recipient: None,
+            room: None,
+            reply_to: None,
+            cert_fingerprint: cert_fingerprint.map(str::to_string),
+            delivered_at: None,
+            retracted_at: None,
+        }
+    }
+
+    #[test]
+    fn test_is_sender() {
+        let cert = |fingerprint: &str| Principal::Cert(fingerprint.to_string());
+        let ip = Principal::Ip("10.0.0.1".parse().unwrap());
+        let other_ip = Principal::Ip("10.0.0.2".parse().unwrap());
+
+        // The certificate is what counts, not the address or the nickname
+        let signed = message(Some("aa"), "10.0.0.1");
+        assert!(is_sender(&signed, &cert("aa"), Some("bob")));
+        assert!(!is_sender(&signed, &cert("bb"), Some("alice")));
+        assert!(!is_sender(&signed, &ip, Some("alice")));
+
+        let unsigned = message(None, "10.0.0.1");
+        assert!(is_sender(&unsigned, &ip, Some("alice")));
+        assert!(!is_sender(&unsigned, &ip, Some("bob")));
+        assert!(!is_sender(&unsigned, &ip, None));
+        assert!(!is_sender(&unsigned, &other_ip, Some("alice")));
+        assert!(!is_sender(&unsigned, &cert("aa"), Some("alice")));
+
+        let local = message(None, "unix:uid=1000,pid=42");
+        assert!(is_sender(&local, &Principal::UnixUser(1000), Some("alice")));
+        assert!(!is_sender(
+            &local,
+            &Principal::UnixUser(1001),
+            Some("alice")
+        ));
+
+        let mut anonymous = message(None, "10.0.0.1");
+        anonymous.user_nickname = crate::db::ANONYMOUS.to_string();
+        assert!(!is_sender(&anonymous, &ip, Some(crate::db::ANONYMOUS)));
+
+        let unknown = message(None, "somewhere");
+        assert!(!is_sender(&unknown, &ip, Some("alice")));
+    }
+
+    #[test]
+    fn test_is_in_conversation() {
+        let public = message(None, "10.0.0.1");
+        assert!(is_in_conversation(&public, None));
+        assert!(!is_in_conversation(&public, Some("ops")));
+
+        let mut in_room = message(None, "10.0.0.1");
+        in_room.room = Some("ops".to_string());
+        assert!(is_in_conversation(&in_room, Some("ops")));
+        assert!(!is_in_conversation(&in_room, Some("dev")));
+        assert!(!is_in_conversation(&in_room, None));
+
+        let mut direct = message(None, "10.0.0.1");
+        direct.recipient = Some("bob".to_string());
+        assert!(!is_in_conversation(&direct, None));
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time,
+};
+
+use common::proto::response::{PresenceStatus, UserPresence};
+use tokio::sync::mpsc;
+
+/// Default for [`Presence::new`], users who don't send anything for this long are idle.
+pub const DEFAULT_IDLE_AFTER: time::Duration = time::Duration::from_secs(5 * 60);
+
+/// How often connected nicknames are sent to be persisted, a crash loses at most this much of
+/// their last seen.
+pub const SAVE_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+/// Tracks which nicknames are connected and when they were last active. Cloning gives another
+/// handle to the same state.
+#[derive(Debug, Clone)]
+pub struct Presence {
+    users: Arc<Mutex<HashMap<String, User>>>,
+    changes: Option<mpsc::UnboundedSender<PresenceChange>>,
+    idle_after: time::Duration,
+}
+
+#[derive(Debug)]
+struct User {
+    /// Number of connections using the nickname.
+    connections: usize,
+    last_active: tokio::time::Instant,
+    last_seen: chrono::DateTime<chrono::Utc>,
+}
+
+/// Nickname came online, went offline or is still connected, sent so that when it was last seen
+/// can be persisted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PresenceChange {
+    pub nickname: String,
+    pub at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Presence of a single connection, the nickname it uses goes offline once all of its
+/// trackers are dropped.
+pub struct Tracker {
+    presence: Presence,
+    nickname: Option<String>,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDLE_AFTER)
+    }
+}
+
+impl Presence {
+    pub fn new(idle_after: time::Duration) -> Self {
+        Self {
+            users: Arc::default(),
+            changes: None,
+            idle_after,
+        }
+    }
+
+    /// Send nicknames coming online and going offline to `changes`, as well as connected ones
+    /// whenever [`Self::save_connected`] is called.
+    pub fn with_changes(mut self, changes: mpsc::UnboundedSender<PresenceChange>) -> Self {
+        self.changes = Some(changes);
+        self
+    }
+
+    /// Remembers `nickname` as offline since `last_seen`, e.g. as persisted before the server
+    /// restarted. Does nothing if the nickname is already known.
+    pub fn restore(&self, nickname: String, last_seen: chrono::DateTime<chrono::Utc>) {
+        self.lock().entry(nickname).or_insert_with(|| User {
+            connections: 0,
+            last_active: tokio::time::Instant::now(),
+            last_seen,
+        });
+    }
+
+    /// Starts tracking a new connection, it has no nickname yet.
+    pub fn track(&self) -> Tracker {
+        Tracker {
+            presence: self.clone(),
+            nickname: None,
+        }
+    }
+
+    /// Whether `nickname` has been connected, now or before.
+    pub fn knows(&self, nickname: &str) -> bool {
+        self.lock().contains_key(nickname)
+    }
+
+    /// Presence of all known nicknames sorted by nickname.
+    pub fn snapshot(&self) -> Vec<UserPresence> {
+        let mut users: Vec<_> = self
+            .lock()
+            .iter()
+            .map(|(nickname, user)| UserPresence {
+                nickname: nickname.clone(),
+                status: self.status(user),
+                last_seen: user.last_seen,
+            })
+            .collect();
+
+        users.sort_by(|a, b| a.nickname.cmp(&b.nickname));
+        users
+    }
+
+    /// Marks connected nicknames as seen now and sends them to be persisted.
+    pub fn save_connected(&self) {
+        let now = chrono::Utc::now();
+
+        for (nickname, user) in self.lock().iter_mut() {
+            if user.connections > 0 {
+                user.last_seen = now;
+                self.send_change(nickname, now);
+            }
+        }
+    }
+
+    /// Calls [`Self::save_connected`] every `period`.
+    pub async fn save_periodically(self, period: time::Duration) {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        loop {
+            interval.tick().await;
+            self.save_connected();
+        }
+    }
+
+    fn status(&self, user: &User) -> PresenceStatus {
+        match user.connections {
+            0 => PresenceStatus::Offline,
+            _ if user.last_active.elapsed() >= self.idle_after => PresenceStatus::Idle,
+            _ => PresenceStatus::Online,
+        }
+    }
+
+    fn connect(&self, nickname: &str) {
+        let now = chrono::Utc::now();
+        let mut users = self.lock();
+        let user = users.entry(nickname.to_string()).or_insert_with(|| User {
+            connections: 0,
+            last_active: tokio::time::Instant::now(),
+            last_seen: now,
+        });
+
+        user.connections += 1;
+        user.last_active = tokio::time::Instant::now();
+        user.last_seen = now;
+
+        if user.connections == 1 {
+            self.send_change(nickname, now);
+        }
+    }
+
+    fn disconnect(&self, nickname: &str) {
+        let now = chrono::Utc::now();
+        let mut users = self.lock();
+        let Some(user) = users.get_mut(nickname) else {
+            return;
+        };
+
+        user.connections = user.connections.saturating_sub(1);
+        user.last_seen = now;
+
+        if user.connections == 0 {
+            self.send_change(nickname, now);
+        }
+    }
+
+    fn touch(&self, nickname: &str) {
+        if let Some(user) = self.lock().get_mut(nickname) {
+            user.last_active = tokio::time::Instant::now();
+            user.last_seen = chrono::Utc::now();
+        }
+    }
+
+    fn send_change(&self, nickname: &str, at: chrono::DateTime<chrono::Utc>) {
+        let Some(changes) = self.changes.as_ref() else {
+            return;
+        };
+
+        let change = PresenceChange {
+            nickname: nickname.to_string(),
+            at,
+        };
+
+        if changes.send(change).is_err() {
+            tracing::warn!("Presence of {nickname} can't be persisted, writer has stopped");
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, User>> {
+        self.users.lock().expect("poisoned lock")
+    }
+}
+
+impl Tracker {
+    /// Moves the connection to `nickname` if it changed.
+    pub fn set_nickname(&mut self, nickname: Option<&str>) {
+        if self.nickname.as_deref() == nickname {
+            return;
+        }
+
+        if let Some(previous) = self.nickname.take() {
+            self.presence.disconnect(&previous);
+        }
+
+        if let Some(nickname) = nickname {
+            self.presence.connect(nickname);
+            self.nickname = Some(nickname.to_string());
+        }
+    }
+
+    /// The connection did something, keepalive pings don't count.
+    pub fn active(&self) {
+        if let Some(nickname) = self.nickname.as_deref() {
+            self.presence.touch(nickname);
+        }
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.set_nickname(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses(presence: &Presence) -> Vec<(String, PresenceStatus)> {
+        presence
+            .snapshot()
+            .into_iter()
+            .map(|user| (user.nickname, user.status))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_presence_changes() {
+        let (sender, mut changes) = mpsc::unbounded_channel();
+        let presence = Presence::new(time::Duration::from_secs(60)).with_changes(sender);
+        presence.restore("carol".to_string(), chrono::Utc::now());
+
+        let mut alice = presence.track();
+        let mut bob = presence.track();
+        alice.set_nickname(Some("alice"));
+        bob.set_nickname(Some("bob"));
+
+        tokio::time::advance(time::Duration::from_secs(61)).await;
+        alice.active();
+
+        assert_eq!(
+            statuses(&presence),
+            [
+                ("alice".to_string(), PresenceStatus::Online),
+                ("bob".to_string(), PresenceStatus::Idle),
+                ("carol".to_string(), PresenceStatus::Offline),
+            ]
+        );
+
+        drop(bob);
+        presence.save_connected();
+        let changes: Vec<_> = std::iter::from_fn(|| changes.try_recv().ok())
+            .map(|change| change.nickname)
+            .collect();
+        // Connected, disconnected, then only alice is still connected
+        assert_eq!(changes, ["alice", "bob", "bob", "alice"]);
+    }
+}
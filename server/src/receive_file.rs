@@ -1,75 +1,331 @@
 use std::{cmp::Ordering, path};
 
 use common::proto;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 use crate::msg_exec::StreamInfo;
 
 // 1024 was enough during experiments, this should be enough for (hopefully) all
 const MIME_DETECTION_BUFFER_SIZE: usize = 4096;
 
-pub async fn receive_streamed_file<H: sha2::Digest, S: tokio::io::AsyncReadExt + Unpin>(
-    filepath: &path::PathBuf,
-    expected: u64,
-    stream: &mut S,
-) -> Result<StreamInfo, StreamFileError> {
-    let mut file = tokio::fs::File::create(filepath)
-        .await
-        .map_err(StreamFileError::fs)?;
-    let mut received = 0;
+// Size of reads when hashing the already persisted part of a resumed transfer.
+const REHASH_BUFFER_SIZE: usize = 64 * 1024;
+
+// How many numbered names are tried when a received file's name is taken.
+const MAX_NAME_ATTEMPTS: usize = 1000;
 
-    let mut detection_buffer = [0u8; MIME_DETECTION_BUFFER_SIZE];
+/// Receives chunks of one file transfer and saves them to a file. Chunks of several transfers
+/// can be interleaved on one connection so the receiver only handles one chunk at a time.
+///
+/// Data is received into a file of its own and only moved to its destination once the transfer
+/// is finished, so that transfers of files with the same name don't write into each other.
+pub struct StreamedFileReceiver<H> {
+    /// Where data is received until the transfer is finished.
+    filepath: path::PathBuf,
+    file: tokio::fs::File,
+    expected: u64,
+    received: u64,
+    hasher: H,
+    detection_buffer: [u8; MIME_DETECTION_BUFFER_SIZE],
     // is guaranteed not to be out of bounds
-    let mut bytes_in_detection_buffer = 0;
+    bytes_in_detection_buffer: usize,
+}
 
-    let mut hasher = H::new();
+/// State of a suspended transfer. Lets the transfer continue without rereading the part of
+/// the file that has already been received.
+pub struct Checkpoint<H> {
+    received: u64,
+    hasher: H,
+    detection_buffer: [u8; MIME_DETECTION_BUFFER_SIZE],
+    bytes_in_detection_buffer: usize,
+}
 
-    while received <= expected {
-        match proto::Payload::read_from(stream)
+impl<H: sha2::Digest> StreamedFileReceiver<H> {
+    /// Creates file at `filepath` to receive a transfer of `expected` bytes into.
+    pub async fn create(filepath: path::PathBuf, expected: u64) -> Result<Self, StreamFileError> {
+        let file = tokio::fs::File::create(&filepath)
             .await
-            .map(|p| p.into_inner())
-        {
-            Ok(proto::request::StreamedFile::Payload(data)) => {
-                file.write_all(&data).await.map_err(StreamFileError::fs)?;
-                received += u64::try_from(data.len()).map_err(StreamFileError::read)?;
-
-                hasher.update(&data);
-                bytes_in_detection_buffer += copy_bytes(
-                    data.as_ref(),
-                    &mut detection_buffer[bytes_in_detection_buffer..],
-                );
-            }
-            Ok(proto::request::StreamedFile::Abort) => {
-                if let Err(e) = tokio::fs::remove_file(filepath)
-                    .await
-                    .map_err(StreamFileError::fs)
-                {
-                    tracing::error!("Failed to remove file due to client abort: {e}");
-                }
-
-                return Err(StreamFileError::Abort { expected, received });
-            }
-            Ok(proto::request::StreamedFile::End) => {
-                break;
+            .map_err(StreamFileError::fs)?;
+
+        Ok(Self {
+            filepath,
+            file,
+            expected,
+            received: 0,
+            hasher: H::new(),
+            detection_buffer: [0u8; MIME_DETECTION_BUFFER_SIZE],
+            bytes_in_detection_buffer: 0,
+        })
+    }
+
+    /// Continues a transfer of `expected` bytes into an existing file at `filepath` from `offset`.
+    /// Anything in the file past `offset` is discarded. The hash covers the whole file, the part
+    /// before `offset` is taken from `checkpoint` if it matches, otherwise it's read from the file.
+    pub async fn resume(
+        filepath: path::PathBuf,
+        expected: u64,
+        offset: u64,
+        checkpoint: Option<Checkpoint<H>>,
+    ) -> Result<Self, StreamFileError> {
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&filepath)
+            .await
+            .map_err(StreamFileError::fs)?;
+
+        let persisted = file.metadata().await.map_err(StreamFileError::fs)?.len();
+        if offset > persisted {
+            return Err(StreamFileError::InvalidOffset { offset, persisted });
+        }
+
+        file.set_len(offset).await.map_err(StreamFileError::fs)?;
+
+        let mut receiver = Self {
+            filepath,
+            file,
+            expected,
+            received: 0,
+            hasher: H::new(),
+            detection_buffer: [0u8; MIME_DETECTION_BUFFER_SIZE],
+            bytes_in_detection_buffer: 0,
+        };
+
+        match checkpoint {
+            Some(checkpoint) if checkpoint.received == offset => {
+                receiver.received = checkpoint.received;
+                receiver.hasher = checkpoint.hasher;
+                receiver.detection_buffer = checkpoint.detection_buffer;
+                receiver.bytes_in_detection_buffer = checkpoint.bytes_in_detection_buffer;
             }
-            Err(e) => {
-                return Err(StreamFileError::Read(e));
+            _ => receiver.rehash(offset).await?,
+        }
+
+        receiver
+            .file
+            .seek(std::io::SeekFrom::Start(offset))
+            .await
+            .map_err(StreamFileError::fs)?;
+
+        Ok(receiver)
+    }
+
+    /// File the transfer is received into.
+    pub fn path(&self) -> &path::Path {
+        &self.filepath
+    }
+
+    /// Writes a chunk of data to the file.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file can't be written or the client sent more data than announced.
+    pub async fn push(&mut self, data: &[u8]) -> Result<(), StreamFileError> {
+        self.file
+            .write_all(data)
+            .await
+            .map_err(StreamFileError::fs)?;
+        self.received += u64::try_from(data.len()).map_err(StreamFileError::read)?;
+
+        self.hasher.update(data);
+        self.bytes_in_detection_buffer += copy_bytes(
+            data,
+            &mut self.detection_buffer[self.bytes_in_detection_buffer..],
+        );
+
+        if self.received > self.expected {
+            return Err(StreamFileError::ExpectedLess {
+                expected: self.expected,
+                received: self.received,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Finishes the transfer after client sent [`proto::request::StreamedFile::End`] and moves the
+    /// file to `destination`, see [`move_into_place`] for where it ends up. If the client sent
+    /// `expected_hash` and it doesn't match, the file is removed.
+    pub async fn finish(
+        mut self,
+        destination: &path::Path,
+        expected_hash: Option<&[u8]>,
+    ) -> Result<(StreamInfo, path::PathBuf), StreamFileError> {
+        self.file.flush().await.map_err(StreamFileError::fs)?;
+
+        let hash = self.hasher.finalize().to_vec();
+        let detection_buffer = &self.detection_buffer[..self.bytes_in_detection_buffer];
+
+        let info = StreamInfo {
+            length: self.received,
+            hash,
+            // From `tree_magic_mini` docs:
+            // As the magic database files themselves are licensed under the GPL, you must make sure your project uses a compatible license if you enable this behaviour.
+            mime: Some(tree_magic_mini::from_u8(detection_buffer).to_string()),
+        };
+
+        decide_streamed_file_result(self.received, self.expected)?;
+
+        if let Err(err) = verify_hash(&info.hash, expected_hash) {
+            drop(self.file);
+            remove_mismatched_file(&self.filepath).await;
+            return Err(err);
+        }
+
+        drop(self.file);
+        let filepath = move_into_place(&self.filepath, destination).await?;
+
+        Ok((info, filepath))
+    }
+
+    /// Stops the transfer, e.g. because the client disconnected, so that it can be resumed later.
+    pub async fn suspend(mut self) -> Result<Checkpoint<H>, StreamFileError> {
+        self.file.flush().await.map_err(StreamFileError::fs)?;
+
+        Ok(Checkpoint {
+            received: self.received,
+            hasher: self.hasher,
+            detection_buffer: self.detection_buffer,
+            bytes_in_detection_buffer: self.bytes_in_detection_buffer,
+        })
+    }
+
+    // Hashes the first `len` bytes of the file as if they were just received.
+    async fn rehash(&mut self, len: u64) -> Result<(), StreamFileError> {
+        self.file
+            .seek(std::io::SeekFrom::Start(0))
+            .await
+            .map_err(StreamFileError::fs)?;
+
+        let mut buf = vec![0u8; REHASH_BUFFER_SIZE];
+
+        while self.received < len {
+            let remaining = usize::try_from(len - self.received).unwrap_or(usize::MAX);
+            let to_read = remaining.min(buf.len());
+            let bytes_read = self
+                .file
+                .read(&mut buf[..to_read])
+                .await
+                .map_err(StreamFileError::fs)?;
+
+            if bytes_read == 0 {
+                return Err(StreamFileError::fs(anyhow::anyhow!(
+                    "file ended after {} bytes while resuming at {len} bytes",
+                    self.received
+                )));
             }
+
+            self.received += bytes_read as u64;
+            self.hasher.update(&buf[..bytes_read]);
+            self.bytes_in_detection_buffer += copy_bytes(
+                &buf[..bytes_read],
+                &mut self.detection_buffer[self.bytes_in_detection_buffer..],
+            );
         }
+
+        Ok(())
     }
 
-    let hash = hasher.finalize().to_vec();
-    let detection_buffer = &detection_buffer[..bytes_in_detection_buffer.min(received as usize)];
+    /// Aborts the transfer after client sent [`proto::request::StreamedFile::Abort`]
+    /// and removes the partially received file.
+    pub async fn abort(self) -> StreamFileError {
+        let err = StreamFileError::Abort {
+            expected: self.expected,
+            received: self.received,
+        };
+        self.discard().await;
+
+        err
+    }
+
+    /// Stops the transfer without the possibility to resume it and removes the partially
+    /// received file.
+    pub async fn discard(self) {
+        drop(self.file);
+        remove_partial_file(&self.filepath).await;
+    }
+}
+
+/// Compares hash computed by the server with the one sent by the client, if any.
+pub fn verify_hash(computed: &[u8], expected: Option<&[u8]>) -> Result<(), StreamFileError> {
+    match expected {
+        Some(expected) if expected != computed => Err(StreamFileError::HashMismatch {
+            expected: expected.to_vec(),
+            computed: computed.to_vec(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Moves received file `filepath` to `destination`. Stored messages may still point to a file
+/// with the same name, so if it's taken a number is added to the name until it's free, e.g.
+/// `photo-1.jpg`. Returns where the file ended up.
+pub async fn move_into_place(
+    filepath: &path::Path,
+    destination: &path::Path,
+) -> Result<path::PathBuf, StreamFileError> {
+    for attempt in 0..MAX_NAME_ATTEMPTS {
+        let target = numbered(destination, attempt);
+
+        // Reserves the name so that no other transfer finishing at the same time takes it
+        let reserved = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&target)
+            .await;
+
+        match reserved {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(StreamFileError::fs(e)),
+        }
+
+        if let Err(e) = tokio::fs::rename(filepath, &target).await {
+            let _ = tokio::fs::remove_file(&target).await;
+            return Err(StreamFileError::fs(e));
+        }
+
+        return Ok(target);
+    }
 
-    let info = StreamInfo {
-        length: received,
-        hash,
-        // From `tree_magic_mini` docs:
-        // As the magic database files themselves are licensed under the GPL, you must make sure your project uses a compatible license if you enable this behaviour.
-        mime: Some(tree_magic_mini::from_u8(detection_buffer).to_string()),
+    Err(StreamFileError::fs(anyhow::anyhow!(
+        "no free name for {} after {MAX_NAME_ATTEMPTS} attempts",
+        destination.display()
+    )))
+}
+
+// `filepath` with `number` added to its name, `filepath` itself for 0.
+fn numbered(filepath: &path::Path, number: usize) -> path::PathBuf {
+    if number == 0 {
+        return filepath.to_path_buf();
+    }
+
+    let stem = filepath.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match filepath.extension() {
+        Some(extension) => format!("{stem}-{number}.{}", extension.to_string_lossy()),
+        None => format!("{stem}-{number}"),
     };
 
-    decide_streamed_file_result(received, expected).map(|_| info)
+    filepath.with_file_name(name)
+}
+
+/// Removes file whose content doesn't match the client's hash so that it isn't served to anyone.
+async fn remove_mismatched_file(filepath: &path::Path) {
+    tracing::warn!("Removing {} due to hash mismatch", filepath.display());
+
+    if let Err(e) = tokio::fs::remove_file(filepath).await {
+        tracing::error!("Failed to remove file with mismatched hash: {e}");
+    }
+}
+
+/// Removes file of a transfer that can't be finished. The file may already be gone, e.g. after
+/// a hash mismatch.
+pub async fn remove_partial_file(filepath: &path::Path) {
+    match tokio::fs::remove_file(filepath).await {
+        Ok(()) => tracing::info!("Removed partially received {}", filepath.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => tracing::error!("Failed to remove partially received file: {e}"),
+    }
 }
 
 fn decide_streamed_file_result(received: u64, expected: u64) -> Result<(), StreamFileError> {
@@ -92,10 +348,33 @@ pub enum StreamFileError {
     Fs(anyhow::Error),
     #[error("Client read error: {0}")]
     Read(anyhow::Error),
+    #[error("Client sent a chunk frame of {size} bytes, limit is {limit} bytes")]
+    FrameTooLarge { size: u64, limit: u64 },
+    #[error("Client sent a chunk for unknown stream {0}")]
+    UnknownStream(proto::request::StreamId),
+    #[error("File transfer {0} is already in progress")]
+    StreamInUse(proto::request::StreamId),
+    #[error(
+        "Client computed hash {}, server computed {}",
+        hex::encode(expected),
+        hex::encode(computed)
+    )]
+    HashMismatch {
+        expected: Vec<u8>,
+        computed: Vec<u8>,
+    },
+    #[error("Unknown upload {0}")]
+    UnknownUpload(proto::request::UploadId),
+    #[error("Upload {0} is already in progress")]
+    UploadBusy(proto::request::UploadId),
+    #[error("Cannot resume at {offset} bytes, only {persisted} bytes were persisted")]
+    InvalidOffset { offset: u64, persisted: u64 },
+    #[error("Invalid filename {0:?}")]
+    InvalidFilename(String),
 }
 
 impl StreamFileError {
-    fn fs<E: Into<anyhow::Error>>(error: E) -> Self {
+    pub fn fs<E: Into<anyhow::Error>>(error: E) -> Self {
         Self::Fs(error.into())
     }
 
@@ -106,16 +385,30 @@ impl StreamFileError {
 
 impl From<StreamFileError> for proto::response::Error {
     fn from(error: StreamFileError) -> Self {
-        match error {
-            StreamFileError::Fs(e) => Self::MessageExec(e.to_string()),
-            StreamFileError::Abort { .. } => Self::ClientAbort,
-            // Explicitly listing the errors in case new variants are added.
-            // The programmer will have to decide how to handle them instead of
-            // automatically converting them to `Read`.
-            StreamFileError::Read(_)
-            | StreamFileError::ExpectedLess { .. }
-            | StreamFileError::ExpectedMore { .. } => Self::Read(error.to_string()),
-        }
+        use proto::response::ErrorCode;
+
+        let code = match &error {
+            StreamFileError::Fs(e) => return crate::exec_error::storage_error(e),
+            StreamFileError::FrameTooLarge { size, limit } => {
+                return Self::frame_too_large(*size, *limit)
+            }
+            StreamFileError::HashMismatch { expected, computed } => {
+                return Self::hash_mismatch(expected, computed)
+            }
+            StreamFileError::Abort { .. } => ErrorCode::ClientAbort,
+            StreamFileError::UnknownStream(_) => ErrorCode::UnknownStream,
+            StreamFileError::StreamInUse(_) => ErrorCode::StreamInUse,
+            StreamFileError::UnknownUpload(_) => ErrorCode::UnknownUpload,
+            StreamFileError::UploadBusy(_) => ErrorCode::UploadBusy,
+            StreamFileError::InvalidOffset { .. } => ErrorCode::InvalidOffset,
+            StreamFileError::InvalidFilename(_) => ErrorCode::InvalidFilename,
+            StreamFileError::ExpectedLess { .. } | StreamFileError::ExpectedMore { .. } => {
+                ErrorCode::SizeMismatch
+            }
+            StreamFileError::Read(_) => ErrorCode::Decode,
+        };
+
+        Self::new(code).with_details(error)
     }
 }
 
@@ -160,6 +453,178 @@ mod tests {
         assert!(matches!(result, Err(StreamFileError::ExpectedLess { .. })));
     }
 
+    #[tokio::test]
+    async fn test_receiver_rejects_extra_data() {
+        let filepath = std::env::temp_dir().join(format!("receiver-test-{}", std::process::id()));
+        let mut receiver = StreamedFileReceiver::<sha2::Sha256>::create(filepath.clone(), 4)
+            .await
+            .unwrap();
+
+        receiver.push(b"abc").await.unwrap();
+        let result = receiver.push(b"de").await;
+
+        assert!(matches!(result, Err(StreamFileError::ExpectedLess { .. })));
+        tokio::fs::remove_file(filepath).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receiver_finish() {
+        use sha2::Digest;
+
+        let dir = std::env::temp_dir();
+        let partial = dir.join(format!("receiver-finish-{}", std::process::id()));
+        let destination = dir.join(format!("receiver-finish-{}.txt", std::process::id()));
+        let mut receiver = StreamedFileReceiver::<sha2::Sha256>::create(partial.clone(), 6)
+            .await
+            .unwrap();
+
+        receiver.push(b"abc").await.unwrap();
+        receiver.push(b"def").await.unwrap();
+        let (info, filepath) = receiver.finish(&destination, None).await.unwrap();
+
+        assert_eq!(info.length, 6);
+        assert_eq!(info.hash, sha2::Sha256::digest(b"abcdef").to_vec());
+        assert_eq!(filepath, destination);
+        assert_eq!(tokio::fs::read(&filepath).await.unwrap(), b"abcdef");
+        assert!(!partial.exists());
+
+        // A file with the same name is kept, the next one gets a numbered name
+        let mut receiver = StreamedFileReceiver::<sha2::Sha256>::create(partial.clone(), 3)
+            .await
+            .unwrap();
+        receiver.push(b"ghi").await.unwrap();
+        let (_, numbered) = receiver.finish(&destination, None).await.unwrap();
+
+        assert_eq!(
+            numbered,
+            dir.join(format!("receiver-finish-{}-1.txt", std::process::id()))
+        );
+        assert_eq!(tokio::fs::read(&filepath).await.unwrap(), b"abcdef");
+        assert_eq!(tokio::fs::read(&numbered).await.unwrap(), b"ghi");
+        tokio::fs::remove_file(filepath).await.unwrap();
+        tokio::fs::remove_file(numbered).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_receivers() {
+        use sha2::Digest;
+
+        let dir = std::env::temp_dir();
+        let destination = dir.join(format!("receiver-interleaved-{}", std::process::id()));
+        let mut first = StreamedFileReceiver::<sha2::Sha256>::create(
+            dir.join(format!("receiver-interleaved-{}-a", std::process::id())),
+            4,
+        )
+        .await
+        .unwrap();
+        let mut second = StreamedFileReceiver::<sha2::Sha256>::create(
+            dir.join(format!("receiver-interleaved-{}-b", std::process::id())),
+            4,
+        )
+        .await
+        .unwrap();
+
+        first.push(b"ab").await.unwrap();
+        second.push(b"wx").await.unwrap();
+        first.push(b"cd").await.unwrap();
+        second.push(b"yz").await.unwrap();
+
+        for (receiver, content) in [(first, b"abcd"), (second, b"wxyz")] {
+            let (info, filepath) = receiver.finish(&destination, None).await.unwrap();
+
+            assert_eq!(info.hash, sha2::Sha256::digest(content).to_vec());
+            assert_eq!(tokio::fs::read(&filepath).await.unwrap(), content);
+            tokio::fs::remove_file(filepath).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_numbered() {
+        let filepath = path::Path::new("files/photo.jpg");
+
+        assert_eq!(numbered(filepath, 0), filepath);
+        assert_eq!(numbered(filepath, 2), path::Path::new("files/photo-2.jpg"));
+        assert_eq!(
+            numbered(path::Path::new("files/notes"), 1),
+            path::Path::new("files/notes-1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receiver_resume() {
+        use sha2::Digest;
+
+        let filepath = std::env::temp_dir().join(format!("receiver-resume-{}", std::process::id()));
+        let mut receiver = StreamedFileReceiver::<sha2::Sha256>::create(filepath.clone(), 6)
+            .await
+            .unwrap();
+        receiver.push(b"abcx").await.unwrap();
+        let checkpoint = receiver.suspend().await.unwrap();
+
+        // Checkpoint doesn't match the offset, persisted part has to be read again.
+        let mut receiver =
+            StreamedFileReceiver::<sha2::Sha256>::resume(filepath.clone(), 6, 3, Some(checkpoint))
+                .await
+                .unwrap();
+
+        receiver.push(b"def").await.unwrap();
+        let destination = filepath.with_extension("txt");
+        let (info, filepath) = receiver
+            .finish(&destination, Some(&sha2::Sha256::digest(b"abcdef")))
+            .await
+            .unwrap();
+
+        assert_eq!(info.hash, sha2::Sha256::digest(b"abcdef").to_vec());
+        assert_eq!(tokio::fs::read(&filepath).await.unwrap(), b"abcdef");
+
+        let result =
+            StreamedFileReceiver::<sha2::Sha256>::resume(filepath.clone(), 6, 7, None).await;
+        assert!(matches!(
+            result,
+            Err(StreamFileError::InvalidOffset {
+                offset: 7,
+                persisted: 6
+            })
+        ));
+        tokio::fs::remove_file(filepath).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receiver_hash_mismatch() {
+        use sha2::Digest;
+
+        let filepath = std::env::temp_dir().join(format!("receiver-hash-{}", std::process::id()));
+        let mut receiver = StreamedFileReceiver::<sha2::Sha256>::create(filepath.clone(), 3)
+            .await
+            .unwrap();
+
+        receiver.push(b"abc").await.unwrap();
+        let destination = filepath.with_extension("txt");
+        let result = receiver
+            .finish(&destination, Some(&sha2::Sha256::digest(b"abd")))
+            .await;
+
+        assert!(matches!(result, Err(StreamFileError::HashMismatch { .. })));
+        assert!(!filepath.exists());
+        assert!(!destination.exists());
+    }
+
+    #[tokio::test]
+    async fn test_receiver_discard() {
+        let filepath =
+            std::env::temp_dir().join(format!("receiver-discard-{}", std::process::id()));
+        let mut receiver = StreamedFileReceiver::<sha2::Sha256>::create(filepath.clone(), 6)
+            .await
+            .unwrap();
+
+        receiver.push(b"abc").await.unwrap();
+        receiver.discard().await;
+
+        assert!(!filepath.exists());
+        // Removing a file that is already gone is fine.
+        remove_partial_file(&filepath).await;
+    }
+
     #[test]
     fn test_copy_bytes() {
         let src = [1, 2, 3, 4, 5];
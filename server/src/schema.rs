@@ -6,7 +6,13 @@ diesel::table! {
         public_id -> Uuid,
         timestamp -> Timestamp,
         user_nickname -> Varchar,
-        user_ip -> Varchar,
+        peer -> Varchar,
+        recipient -> Nullable<Varchar>,
+        delivered_at -> Nullable<Timestamp>,
+        room -> Nullable<Varchar>,
+        reply_to -> Nullable<Uuid>,
+        cert_fingerprint -> Nullable<Varchar>,
+        retracted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -28,7 +34,30 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    message_text_revision (revision_id) {
+        revision_id -> Int8,
+        message_id -> Int8,
+        text -> Text,
+        replaced_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    presence (nickname) {
+        nickname -> Varchar,
+        last_seen -> Timestamp,
+    }
+}
+
 diesel::joinable!(message_file -> message (message_id));
 diesel::joinable!(message_text -> message (message_id));
+diesel::joinable!(message_text_revision -> message_text (message_id));
 
-diesel::allow_tables_to_appear_in_same_query!(message, message_file, message_text,);
+diesel::allow_tables_to_appear_in_same_query!(
+    message,
+    message_file,
+    message_text,
+    message_text_revision,
+    presence,
+);
@@ -0,0 +1,132 @@
+use std::{collections::VecDeque, path};
+
+use common::proto::request::{RequestId, StreamedFile};
+use tokio::io::AsyncReadExt;
+
+use crate::receive_file::StreamFileError;
+
+/// Sends a stored file to the client in chunks.
+#[derive(Debug)]
+pub struct StreamedFileSender {
+    file: tokio::fs::File,
+    remaining: u64,
+}
+
+impl StreamedFileSender {
+    /// Opens file at `filepath` which is expected to have `length` bytes.
+    pub async fn open(filepath: &path::Path, length: u64) -> Result<Self, StreamFileError> {
+        let file = tokio::fs::File::open(filepath)
+            .await
+            .map_err(StreamFileError::fs)?;
+
+        Ok(Self {
+            file,
+            remaining: length,
+        })
+    }
+
+    /// Reads at most `max_len` bytes of the file, [`StreamedFile::End`] once the whole file
+    /// has been read.
+    async fn next_chunk(&mut self, max_len: u64) -> Result<StreamedFile, StreamFileError> {
+        if self.remaining == 0 {
+            return Ok(StreamedFile::End(None));
+        }
+
+        let len = self.remaining.min(max_len);
+        let mut buffer = vec![0; usize::try_from(len).map_err(StreamFileError::fs)?];
+        let read = self
+            .file
+            .read(&mut buffer)
+            .await
+            .map_err(StreamFileError::fs)?;
+
+        if read == 0 {
+            return Err(StreamFileError::fs(anyhow::anyhow!(
+                "file ended {} bytes early",
+                self.remaining
+            )));
+        }
+
+        buffer.truncate(read);
+        self.remaining -= read as u64;
+
+        Ok(StreamedFile::Payload(buffer))
+    }
+}
+
+/// Downloads in progress on one connection. Chunks of several downloads take turns so that one
+/// large file doesn't hold up the others.
+#[derive(Default)]
+pub struct Downloads {
+    active: VecDeque<(RequestId, StreamedFileSender)>,
+}
+
+impl Downloads {
+    pub fn start(&mut self, id: RequestId, sender: StreamedFileSender) {
+        self.active.push_back((id, sender));
+    }
+
+    /// Next chunk of the download whose turn it is, never resolves if there are no downloads.
+    /// Downloads are forgotten once they end or fail.
+    ///
+    /// Cancel safe, [`tokio::fs::File`] keeps data of an interrupted read for the next one.
+    pub async fn next_chunk(
+        &mut self,
+        max_len: u64,
+    ) -> (RequestId, Result<StreamedFile, StreamFileError>) {
+        let Some((id, sender)) = self.active.front_mut() else {
+            return std::future::pending().await;
+        };
+        let id = *id;
+
+        let chunk = sender.next_chunk(max_len).await;
+        match chunk {
+            Ok(StreamedFile::Payload(_)) => self.active.rotate_left(1),
+            _ => {
+                self.active.pop_front();
+            }
+        }
+
+        (id, chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_downloads_take_turns() {
+        let dir = std::env::temp_dir().join(format!("send-file-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join("a"), b"aaaa").await.unwrap();
+        tokio::fs::write(dir.join("b"), b"bb").await.unwrap();
+
+        let mut downloads = Downloads::default();
+        for (id, name, length) in [(1, "a", 4), (2, "b", 2)] {
+            let sender = StreamedFileSender::open(&dir.join(name), length)
+                .await
+                .unwrap();
+            downloads.start(id, sender);
+        }
+
+        let mut chunks = Vec::new();
+        for _ in 0..5 {
+            let (id, chunk) = downloads.next_chunk(2).await;
+            chunks.push((id, chunk.unwrap()));
+        }
+
+        assert_eq!(
+            chunks,
+            [
+                (1, StreamedFile::Payload(b"aa".to_vec())),
+                (2, StreamedFile::Payload(b"bb".to_vec())),
+                (1, StreamedFile::Payload(b"aa".to_vec())),
+                (2, StreamedFile::End(None)),
+                (1, StreamedFile::End(None)),
+            ]
+        );
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,51 @@
+use super::{Listener, Peer};
+
+/// Connection accepted by any kind of listener.
+pub trait Connection: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+
+impl<T> Connection for T where T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+
+pub type AnyStream = Box<dyn Connection>;
+
+/// Listener whose kind is chosen at runtime, e.g. plaintext TCP, mTLS or a Unix domain socket.
+/// All of them accept [`AnyStream`]s, so servers on different kinds of listeners are the same type.
+pub struct AnyListener {
+    inner: Box<dyn Listener<Stream = AnyStream>>,
+}
+
+impl AnyListener {
+    pub fn new<L>(listener: L) -> Self
+    where
+        L: Listener + 'static,
+    {
+        Self {
+            inner: Box::new(Boxed(listener)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Listener for AnyListener {
+    type Stream = AnyStream;
+
+    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, Peer)> {
+        self.inner.accept_conn().await
+    }
+}
+
+struct Boxed<L>(L);
+
+#[async_trait::async_trait]
+impl<L> Listener for Boxed<L>
+where
+    L: Listener,
+    L::Stream: 'static,
+{
+    type Stream = AnyStream;
+
+    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, Peer)> {
+        let (stream, peer) = self.0.accept_conn().await?;
+
+        Ok((Box::new(stream), peer))
+    }
+}
@@ -1,57 +1,372 @@
+use std::{collections::BTreeSet, pin::Pin, task::Poll};
+
 use common::proto;
+use futures::{Sink, SinkExt, StreamExt};
 
-use crate::{Client, MessageExecutor};
+use crate::{
+    hub::Subscription, msg_exec::Completion, presence::Tracker, receive_file::StreamFileError,
+    send_file::Downloads, Client, MessageExecutor,
+};
 
-use super::Server;
+use super::{watchdog::Watchdog, ClientStream, Config, Server};
 
 impl<L> Server<L>
 where
     L: Send,
 {
-    #[tracing::instrument(skip(client, executor), fields(client = %client.get_address()))]
+    #[tracing::instrument(skip(client, executor, config), fields(client = %client.get_address()))]
     pub async fn handle_client<S>(
         mut client: Client<S>,
         executor: &MessageExecutor,
+        config: &Config,
     ) -> anyhow::Result<()>
     where
         S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
     {
-        while let LoopInstruction::Continue = Self::client_tick(&mut client, executor).await {
-            // Continue
+        if let Err(err) = Self::handshake(&mut client, config).await {
+            tracing::info!("Handshake failed: {err}");
+            return Ok(());
+        }
+
+        let mut events = executor
+            .hub()
+            .filter(|_| {
+                client
+                    .get_capabilities()
+                    .contains(&proto::handshake::Capability::Push)
+            })
+            .map(|hub| hub.subscribe(client.get_address()));
+
+        let mut watchdog = Watchdog::new(config.timeouts.clone());
+        let mut downloads = Downloads::default();
+        executor.bind_nickname(&mut client);
+
+        let mut presence = executor.presence().map(|presence| {
+            let mut tracker = presence.track();
+            tracker.set_nickname(client.get_nickname());
+            tracker
+        });
+
+        while let LoopInstruction::Continue = Self::client_tick(
+            &mut client,
+            executor,
+            &mut events,
+            &mut watchdog,
+            &mut downloads,
+            presence.as_ref(),
+        )
+        .await
+        {
+            if let Some(presence) = presence.as_mut() {
+                presence.set_nickname(client.get_nickname());
+            }
         }
 
+        // Client is offline before its uploads are suspended
+        drop(presence);
+
+        executor.suspend_uploads(&mut client).await;
+
         Ok(())
     }
 
-    #[tracing::instrument(skip(client, executor), fields(client = ?client.get_nickname()))]
-    async fn client_tick<S>(client: &mut Client<S>, executor: &MessageExecutor) -> LoopInstruction
+    #[tracing::instrument(
+        skip(client, executor, events, watchdog, downloads, presence),
+        fields(client = ?client.get_nickname())
+    )]
+    async fn client_tick<S>(
+        client: &mut Client<S>,
+        executor: &MessageExecutor,
+        events: &mut Option<Subscription>,
+        watchdog: &mut Watchdog,
+        downloads: &mut Downloads,
+        presence: Option<&Tracker>,
+    ) -> LoopInstruction
     where
         S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
     {
-        let response = match proto::Payload::read_from(client.get_stream()).await {
-            Ok(payload) => match executor.exec(payload.into_inner(), client).await {
-                Ok(()) => proto::response::Message::Ok,
-                Err(err) => {
-                    let msg = err.to_string();
-                    proto::response::Message::Err(proto::response::Error::message_exec(msg))
+        let limits = client.get_limits().clone();
+        let max_size = limits
+            .max_control_frame_size
+            .max(limits.max_chunk_frame_size);
+        client.get_stream().codec_mut().set_max_frame_size(max_size);
+        // Frames for the client are only queued, they're written while waiting for its next frame.
+        // Room for a download chunk or an event and the responses queued behind it.
+        let write_buffer_size = usize::try_from(2 * max_size).unwrap_or(usize::MAX);
+        client
+            .get_stream()
+            .set_backpressure_boundary(write_buffer_size);
+        let max_chunk_len =
+            proto::request::StreamedFile::max_chunk_len(limits.max_chunk_frame_size);
+        if let LoopInstruction::Break = Self::receive_direct(client, executor, events).await {
+            return LoopInstruction::Break;
+        }
+        let rooms = client.get_rooms().clone();
+        // Nothing more is queued until the client has taken what's queued already
+        let writable = client.get_stream().write_buffer().is_empty();
+
+        let frame = tokio::select! {
+            incoming = next_frame(client.get_stream()) => match incoming {
+                Incoming::Frame(frame) => frame,
+                Incoming::Flushed => return LoopInstruction::Continue,
+                Incoming::WriteFailed(err) => {
+                    tracing::debug!("Failed to write to client: {err}");
+                    return LoopInstruction::Break;
                 }
             },
+            Some(event) = next_event(events, &rooms), if writable => {
+                return Self::push_event(client, event).await;
+            }
+            (id, chunk) = downloads.next_chunk(max_chunk_len), if writable => {
+                return Self::send_download_chunk(client, id, chunk).await;
+            }
+            _ = tokio::time::sleep_until(watchdog.next_check()) => {
+                let partial_frame = !client.get_stream().read_buffer().is_empty();
+
+                if let Err(timeout) = watchdog.check(partial_frame) {
+                    tracing::info!("Closing connection, {timeout}");
+                    return LoopInstruction::Break;
+                }
+
+                return LoopInstruction::Continue;
+            }
+        };
+
+        let Some(frame) = frame else {
+            tracing::debug!("Client closed the connection");
+            return LoopInstruction::Break;
+        };
+
+        watchdog.frame_received();
+
+        let keepalive = matches!(
+            frame,
+            Ok(proto::request::Frame::Request {
+                message: proto::request::Message::Ping,
+                ..
+            })
+        );
+        if let Some(presence) = presence.filter(|_| !keepalive) {
+            presence.active();
+        }
+
+        // Codec only enforces the larger of the limits, the specific one is checked after decoding.
+        let size = client.get_stream().codec().last_frame_size();
+
+        let response = match frame {
+            Ok(proto::request::Frame::Request { id, .. })
+                if size > limits.max_control_frame_size =>
+            {
+                let limit = limits.max_control_frame_size;
+                Some(proto::response::Frame::new(
+                    Some(id),
+                    proto::response::Error::frame_too_large(size, limit),
+                ))
+            }
+            Ok(proto::request::Frame::Request { id, message }) => {
+                let result = executor.exec(id, message, client).await;
+                Self::response_for(id, result, downloads)
+            }
+            Ok(proto::request::Frame::Chunk { stream, .. })
+                if size > limits.max_chunk_frame_size =>
+            {
+                executor.cancel_upload(stream, client).await;
+
+                let limit = limits.max_chunk_frame_size;
+                Some(proto::response::Frame::new(
+                    Some(stream),
+                    proto::response::Error::frame_too_large(size, limit),
+                ))
+            }
+            Ok(proto::request::Frame::Chunk { stream, chunk }) => {
+                let result = executor.exec_chunk(stream, chunk, client).await;
+                Self::response_for(stream, result, downloads)
+            }
+            Err(proto::ReadError::FrameTooLarge { size, limit }) => {
+                Some(proto::response::Frame::new(
+                    None,
+                    proto::response::Error::frame_too_large(size, limit),
+                ))
+            }
             Err(err) => {
                 tracing::debug!("Failed to read message: {err}");
-                proto::response::Error::Read(err.to_string()).into()
+                Some(proto::response::Frame::new(
+                    None,
+                    proto::response::Error::decode(err),
+                ))
             }
         };
 
+        let Some(response) = response else {
+            // File transfer is still in progress
+            return LoopInstruction::Continue;
+        };
+
         crate::metrics::MESSAGES_TOTAL.inc();
 
-        let payload = proto::Payload::new(&response);
-        if let Err(err) = payload.write_to(&mut client.get_stream()).await {
-            tracing::debug!("Failed to send error response: {err}");
+        if let Err(err) = client.get_stream().feed(&response).await {
+            tracing::debug!("Failed to send response: {err}");
             return LoopInstruction::Break;
         }
 
+        if let proto::response::Message::Err(err) = &response.message {
+            if err.code == proto::response::ErrorCode::FrameTooLarge {
+                tracing::info!("Closing connection: {err}");
+                // Client should learn why
+                let _ = SinkExt::<&proto::response::Frame>::flush(client.get_stream()).await;
+                return LoopInstruction::Break;
+            }
+        }
+
         LoopInstruction::Continue
     }
+
+    /// Receives direct messages for the client's nickname once its certificate proves the client
+    /// owns it. Messages that arrived while it was offline are pushed first.
+    async fn receive_direct<S>(
+        client: &mut Client<S>,
+        executor: &MessageExecutor,
+        events: &mut Option<Subscription>,
+    ) -> LoopInstruction
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let Some(events) = events.as_mut() else {
+            return LoopInstruction::Continue;
+        };
+
+        // Clients that can't display direct messages have them held instead.
+        let nickname = client
+            .get_capabilities()
+            .contains(&proto::handshake::Capability::DirectMessages)
+            .then(|| client.get_verified_nickname().map(ToString::to_string))
+            .flatten();
+        if !events.set_direct(nickname.as_deref()) {
+            return LoopInstruction::Continue;
+        }
+        let Some(nickname) = nickname else {
+            return LoopInstruction::Continue;
+        };
+
+        let held = match executor.take_held_direct(&nickname).await {
+            Ok(held) => held,
+            Err(err) => {
+                tracing::warn!("Failed to load direct messages held for {nickname}: {err:#}");
+                return LoopInstruction::Continue;
+            }
+        };
+
+        for event in held {
+            if let LoopInstruction::Break = Self::push_event(client, event).await {
+                return LoopInstruction::Break;
+            }
+        }
+
+        LoopInstruction::Continue
+    }
+
+    async fn push_event<S>(client: &mut Client<S>, event: proto::response::Event) -> LoopInstruction
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let frame = proto::response::Frame::new(None, event);
+
+        if let Err(err) = client.get_stream().feed(&frame).await {
+            tracing::debug!("Failed to push event: {err}");
+            return LoopInstruction::Break;
+        }
+
+        LoopInstruction::Continue
+    }
+
+    async fn send_download_chunk<S>(
+        client: &mut Client<S>,
+        id: proto::request::RequestId,
+        chunk: Result<proto::request::StreamedFile, StreamFileError>,
+    ) -> LoopInstruction
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let message = match chunk {
+            Ok(chunk) => proto::response::Message::Chunk(chunk),
+            Err(err) => {
+                tracing::warn!("Failed to send download {id}: {err}");
+                proto::response::Error::from(err).into()
+            }
+        };
+
+        if let Err(err) = client
+            .get_stream()
+            .feed(&proto::response::Frame::new(Some(id), message))
+            .await
+        {
+            tracing::debug!("Failed to send download chunk: {err}");
+            return LoopInstruction::Break;
+        }
+
+        LoopInstruction::Continue
+    }
+
+    /// Response to request `id`, if it's complete. Downloads are started here, their chunks
+    /// follow the response.
+    fn response_for(
+        id: proto::request::RequestId,
+        result: anyhow::Result<Completion>,
+        downloads: &mut Downloads,
+    ) -> Option<proto::response::Frame> {
+        let message = match result {
+            Ok(Completion::Pending) => return None,
+            Ok(Completion::Done) => proto::response::Message::Ok,
+            Ok(Completion::Reply(message)) => message,
+            Ok(Completion::Download(info, sender)) => {
+                downloads.start(id, sender);
+                proto::response::Message::Download(info)
+            }
+            Err(err) => crate::exec_error::response_error(err).into(),
+        };
+
+        Some(proto::response::Frame::new(Some(id), message))
+    }
+}
+
+/// What happened while waiting for the client's next frame.
+enum Incoming {
+    Frame(Option<Result<proto::request::Frame, proto::ReadError>>),
+    /// Everything queued for the client has been written.
+    Flushed,
+    WriteFailed(proto::WriteError),
+}
+
+/// Waits for the client's next frame and meanwhile writes out what's queued for the client.
+/// Reading doesn't wait for writing, otherwise a client that only reads once it's done sending,
+/// e.g. a file, would block the connection as soon as both directions are full.
+async fn next_frame<S>(stream: &mut ClientStream<S>) -> Incoming
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    std::future::poll_fn(|cx| {
+        if !stream.write_buffer().is_empty() {
+            match Sink::<&proto::response::Frame>::poll_flush(Pin::new(&mut *stream), cx) {
+                Poll::Ready(Ok(())) => return Poll::Ready(Incoming::Flushed),
+                Poll::Ready(Err(err)) => return Poll::Ready(Incoming::WriteFailed(err)),
+                Poll::Pending => {}
+            }
+        }
+
+        stream.poll_next_unpin(cx).map(Incoming::Frame)
+    })
+    .await
+}
+
+/// Next event for the client, never resolves if the client isn't subscribed.
+async fn next_event(
+    events: &mut Option<Subscription>,
+    rooms: &BTreeSet<String>,
+) -> Option<proto::response::Event> {
+    match events {
+        Some(events) => events.recv(rooms).await,
+        None => std::future::pending().await,
+    }
 }
 
 #[derive(Default)]
@@ -60,3 +375,49 @@ enum LoopInstruction {
     Continue,
     Break,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::{codec::PayloadCodec, request, response};
+
+    #[tokio::test]
+    async fn test_next_frame_reads_while_writing() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut stream = ClientStream::new(server, PayloadCodec::new(1024));
+        stream.set_backpressure_boundary(usize::MAX);
+        let mut client =
+            tokio_util::codec::Framed::new(client, PayloadCodec::<response::Frame>::new(4096));
+
+        // Doesn't fit into the pipe until the client reads
+        let event = response::Event::Text {
+            from: None,
+            text: "x".repeat(1024),
+            room: None,
+            reply_to: None,
+        };
+        stream
+            .feed(&response::Frame::new(None, event))
+            .await
+            .unwrap();
+        client
+            .send(&request::Frame::Request {
+                id: 1,
+                message: request::Message::Ping,
+            })
+            .await
+            .unwrap();
+
+        let Incoming::Frame(Some(Ok(frame))) = next_frame(&mut stream).await else {
+            panic!("expected a frame from the client");
+        };
+        assert!(matches!(frame, request::Frame::Request { id: 1, .. }));
+
+        let (incoming, received) = tokio::join!(next_frame(&mut stream), client.next());
+        assert!(matches!(incoming, Incoming::Flushed));
+        assert!(matches!(
+            received.unwrap().unwrap().message,
+            response::Message::Event(response::Event::Text { .. })
+        ));
+    }
+}
@@ -0,0 +1,58 @@
+use common::proto::{self, handshake};
+
+use crate::Client;
+
+use super::{Config, Server};
+
+impl<L> Server<L>
+where
+    L: Send,
+{
+    /// Perform the mandatory handshake. On success, negotiated capabilities and limits are stored in `client`.
+    /// On failure, the client has already been told why and the connection should be closed.
+    ///
+    /// Must be called before the client's framed stream is polled since it uses the underlying
+    /// stream directly.
+    pub(super) async fn handshake<S>(client: &mut Client<S>, config: &Config) -> anyhow::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let max_size = config.limits.max_control_frame_size;
+        let stream = client.get_stream().get_mut();
+
+        let hello = tokio::time::timeout(
+            config.timeouts.idle,
+            proto::Payload::<handshake::Hello>::read_from(stream, max_size),
+        )
+        .await;
+
+        let result = match hello {
+            Ok(Ok(hello)) => {
+                let hello = hello.into_inner();
+                tracing::debug!("Client hello: {hello:?}");
+
+                handshake::negotiate(&hello, &config.capabilities, &config.limits)
+            }
+            Ok(Err(err)) => Err(proto::response::Error::handshake(err)),
+            Err(_) => Err(proto::response::Error::handshake(format!(
+                "no hello received in {:?}",
+                config.timeouts.idle
+            ))),
+        };
+
+        let reply = handshake::Reply::from(result.clone());
+        proto::Payload::new(reply).write_to(stream).await?;
+
+        let welcome = result?;
+        client.set_capabilities(welcome.capabilities);
+        client.set_limits(welcome.limits);
+
+        tracing::debug!(
+            "Negotiated protocol {} with capabilities {:?}",
+            welcome.version,
+            client.get_capabilities()
+        );
+
+        Ok(())
+    }
+}
@@ -2,14 +2,16 @@
 pub trait Listener: Send + Sync {
     type Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send;
 
-    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, std::net::SocketAddr)>;
+    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, super::Peer)>;
 }
 
 #[async_trait::async_trait]
 impl Listener for tokio::net::TcpListener {
     type Stream = tokio::net::TcpStream;
 
-    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, std::net::SocketAddr)> {
-        self.accept().await.map_err(Into::into)
+    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, super::Peer)> {
+        let (stream, addr) = self.accept().await?;
+
+        Ok((stream, super::PeerAddr::from(addr).into()))
     }
 }
@@ -1,19 +1,46 @@
-use std::{collections::HashMap, net};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time,
+};
+
+use common::proto::{codec, handshake, request};
+
+use crate::msg_exec::Upload;
 
 mod handle_client;
+mod handshake_client;
 mod run;
+mod watchdog;
 
 mod listener;
 pub use listener::Listener;
 
+mod any;
+pub use any::AnyListener;
+
+mod peer;
+pub use peer::{CertIdentity, Peer, PeerAddr, PeerIdentity, Principal, UnixCred};
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub use unix::{parse_mode, UnixListener};
+
 #[cfg(feature = "mtls")]
 mod tls;
 #[cfg(feature = "mtls")]
-pub use tls::TlsListener;
+pub use tls::{TlsConfig, TlsListener};
+
+#[cfg(feature = "ws")]
+mod ws;
+#[cfg(feature = "ws")]
+pub use ws::WsListener;
 
 pub struct Server<L> {
     listener: L,
-    clients: HashMap<net::SocketAddr, tokio::task::JoinHandle<anyhow::Result<()>>>,
+    clients: HashMap<PeerAddr, tokio::task::JoinHandle<anyhow::Result<()>>>,
+    config: Arc<Config>,
 }
 
 impl<L> Server<L> {
@@ -21,27 +48,108 @@ impl<L> Server<L> {
         Self {
             listener,
             clients: HashMap::new(),
+            config: Arc::new(Config::default()),
         }
     }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = Arc::new(config);
+        self
+    }
 }
 
-#[derive(Debug)]
+/// Settings shared by all client connections.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Capabilities offered to clients during handshake.
+    pub capabilities: handshake::Capabilities,
+    /// Limits advertised to clients during handshake.
+    pub limits: handshake::Limits,
+    pub timeouts: Timeouts,
+}
+
+/// Default for [`Timeouts::frame_read`].
+pub const DEFAULT_FRAME_READ_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// How long clients can keep a connection open without sending anything useful.
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    /// Connection is closed if the client doesn't send a complete frame for this long.
+    /// Also applies to the handshake.
+    pub idle: time::Duration,
+    /// Connection is closed if the client takes longer than this to send a frame once it starts.
+    pub frame_read: time::Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle: time::Duration::from_secs(handshake::DEFAULT_IDLE_TIMEOUT_SECS),
+            frame_read: DEFAULT_FRAME_READ_TIMEOUT,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            capabilities: [
+                handshake::Capability::Streaming,
+                handshake::Capability::Push,
+                handshake::Capability::ResumableUploads,
+                handshake::Capability::Downloads,
+                handshake::Capability::History,
+                handshake::Capability::DirectMessages,
+                handshake::Capability::Rooms,
+                handshake::Capability::Edits,
+                handshake::Capability::Replies,
+                handshake::Capability::Presence,
+            ]
+            .into(),
+            limits: handshake::Limits::default(),
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+/// Client's connection as a stream of [`request::Frame`]s. Responses are written into it as well.
+pub type ClientStream<S> = tokio_util::codec::Framed<S, codec::PayloadCodec<request::Frame>>;
+
 pub(crate) struct Client<S> {
-    address: net::SocketAddr,
-    stream: S,
+    address: PeerAddr,
+    cert: Option<CertIdentity>,
+    stream: ClientStream<S>,
     nickname: Option<String>,
+    capabilities: handshake::Capabilities,
+    limits: handshake::Limits,
+    uploads: HashMap<request::StreamId, Upload>,
+    /// Rooms the client has joined, it receives events sent to them.
+    rooms: BTreeSet<String>,
 }
 
-impl<S> Client<S> {
-    pub fn new(address: net::SocketAddr, stream: S) -> Self {
+impl<S> Client<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    pub fn new(peer: Peer, stream: S) -> Self {
+        let limits = handshake::Limits::default();
+        let codec = codec::PayloadCodec::new(limits.max_control_frame_size);
+
         Self {
-            address,
-            stream,
+            address: peer.addr,
+            cert: peer.cert,
+            stream: tokio_util::codec::Framed::new(stream, codec),
             nickname: None,
+            capabilities: handshake::Capabilities::new(),
+            limits,
+            uploads: HashMap::new(),
+            rooms: BTreeSet::new(),
         }
     }
+}
 
-    pub fn get_stream(&mut self) -> &mut S {
+impl<S> Client<S> {
+    pub fn get_stream(&mut self) -> &mut ClientStream<S> {
         &mut self.stream
     }
 
@@ -53,7 +161,89 @@ impl<S> Client<S> {
         self.nickname.as_deref()
     }
 
-    pub fn get_address(&self) -> net::SocketAddr {
+    /// Nickname of the client if its certificate was issued to it, others can be announced by
+    /// anyone.
+    pub fn get_verified_nickname(&self) -> Option<&str> {
+        let nickname = self.nickname.as_deref()?;
+
+        self.cert
+            .as_ref()
+            .filter(|cert| cert.has_name(nickname))
+            .map(|_| nickname)
+    }
+
+    pub fn get_address(&self) -> PeerAddr {
         self.address
     }
+
+    /// Identity from the client's certificate if it connected with one.
+    pub fn get_cert(&self) -> Option<&CertIdentity> {
+        self.cert.as_ref()
+    }
+
+    /// Who the client is, by its certificate if it has one or else where it connects from.
+    pub fn get_principal(&self) -> Principal {
+        if let Some(cert) = &self.cert {
+            return Principal::Cert(cert.fingerprint.clone());
+        }
+
+        match self.address {
+            PeerAddr::Ip(addr) => Principal::Ip(addr.ip()),
+            PeerAddr::Unix { cred, .. } => Principal::UnixUser(cred.uid),
+        }
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: handshake::Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Capabilities negotiated during handshake.
+    pub fn get_capabilities(&self) -> &handshake::Capabilities {
+        &self.capabilities
+    }
+
+    pub fn set_limits(&mut self, limits: handshake::Limits) {
+        self.limits = limits;
+    }
+
+    /// Limits on frames received from this client.
+    pub fn get_limits(&self) -> &handshake::Limits {
+        &self.limits
+    }
+
+    pub fn add_upload(&mut self, stream: request::StreamId, upload: Upload) {
+        self.uploads.insert(stream, upload);
+    }
+
+    /// File transfer in progress started by request `stream`.
+    pub fn get_upload(&mut self, stream: request::StreamId) -> Option<&mut Upload> {
+        self.uploads.get_mut(&stream)
+    }
+
+    pub fn remove_upload(&mut self, stream: request::StreamId) -> Option<Upload> {
+        self.uploads.remove(&stream)
+    }
+
+    /// Adds the client to `room`, returns whether it wasn't there already.
+    pub fn join_room(&mut self, room: impl ToString) -> bool {
+        self.rooms.insert(room.to_string())
+    }
+
+    /// Removes the client from `room`, returns whether it was there.
+    pub fn leave_room(&mut self, room: &str) -> bool {
+        self.rooms.remove(room)
+    }
+
+    pub fn is_in_room(&self, room: &str) -> bool {
+        self.rooms.contains(room)
+    }
+
+    pub fn get_rooms(&self) -> &BTreeSet<String> {
+        &self.rooms
+    }
+
+    /// Removes all file transfers in progress.
+    pub fn take_uploads(&mut self) -> HashMap<request::StreamId, Upload> {
+        std::mem::take(&mut self.uploads)
+    }
 }
@@ -0,0 +1,150 @@
+use std::{fmt, net};
+
+/// Where a client is connected from. Tells apart all connections, even several from one process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Ip(net::SocketAddr),
+    /// Connection to a Unix domain socket, `conn` numbers connections accepted by the listener.
+    Unix {
+        cred: UnixCred,
+        conn: u64,
+    },
+}
+
+/// Peer credentials (`SO_PEERCRED`) of a Unix domain socket connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UnixCred {
+    pub uid: u32,
+    /// Not available on all platforms.
+    pub pid: Option<i32>,
+}
+
+/// Client accepted by a listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub addr: PeerAddr,
+    /// Identity proven by a client certificate, only on TLS listeners.
+    pub cert: Option<CertIdentity>,
+}
+
+/// Identity of a client taken from its verified TLS certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertIdentity {
+    /// Common name of the certificate's subject.
+    pub common_name: Option<String>,
+    /// DNS names and email addresses of the subject alternative name extension.
+    pub alt_names: Vec<String>,
+    /// Hex-encoded SHA-256 of the certificate.
+    pub fingerprint: String,
+}
+
+/// Who sent a message, the IP address of TCP clients and peer credentials of Unix socket clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerIdentity {
+    Ip(net::IpAddr),
+    Unix(UnixCred),
+}
+
+/// Who a client is across connections, e.g. to check that it may continue what it started on
+/// another connection. A certificate identifies the client if it has one, otherwise only the
+/// address or the local user it connects from does.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Principal {
+    /// Fingerprint of the client's certificate.
+    Cert(String),
+    Ip(net::IpAddr),
+    /// User of a Unix domain socket client, its process changes when it reconnects.
+    UnixUser(u32),
+}
+
+impl PeerAddr {
+    pub fn identity(&self) -> PeerIdentity {
+        match self {
+            PeerAddr::Ip(addr) => PeerIdentity::Ip(addr.ip()),
+            PeerAddr::Unix { cred, .. } => PeerIdentity::Unix(*cred),
+        }
+    }
+}
+
+impl Principal {
+    /// Principal that sent a stored message, from its certificate fingerprint or else its stored
+    /// [`PeerIdentity`]. `None` if neither tells who sent it.
+    pub fn from_stored(cert_fingerprint: Option<&str>, peer: &str) -> Option<Principal> {
+        if let Some(fingerprint) = cert_fingerprint {
+            return Some(Principal::Cert(fingerprint.to_string()));
+        }
+
+        if let Ok(ip) = peer.parse() {
+            return Some(Principal::Ip(ip));
+        }
+
+        let uid = peer.strip_prefix("unix:uid=")?;
+        let uid = uid.split_once(',').map_or(uid, |(uid, _)| uid);
+
+        uid.parse().ok().map(Principal::UnixUser)
+    }
+}
+
+impl CertIdentity {
+    /// Name the certificate was issued to, the common name or else the first alternative name.
+    pub fn name(&self) -> Option<&str> {
+        self.common_name
+            .as_deref()
+            .or(self.alt_names.first().map(String::as_str))
+    }
+
+    /// Whether the certificate was issued to `name`.
+    pub fn has_name(&self, name: &str) -> bool {
+        self.common_name.as_deref() == Some(name) || self.alt_names.iter().any(|alt| alt == name)
+    }
+}
+
+impl From<PeerAddr> for Peer {
+    fn from(addr: PeerAddr) -> Self {
+        Peer { addr, cert: None }
+    }
+}
+
+impl From<net::SocketAddr> for PeerAddr {
+    fn from(addr: net::SocketAddr) -> Self {
+        PeerAddr::Ip(addr)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Ip(addr) => write!(f, "{addr}"),
+            PeerAddr::Unix { cred, conn } => write!(f, "{cred}#{conn}"),
+        }
+    }
+}
+
+impl fmt::Display for CertIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{name} ({})", self.fingerprint),
+            None => write!(f, "{}", self.fingerprint),
+        }
+    }
+}
+
+impl fmt::Display for UnixCred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unix:uid={}", self.uid)?;
+
+        match self.pid {
+            Some(pid) => write!(f, ",pid={pid}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for PeerIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerIdentity::Ip(ip) => write!(f, "{ip}"),
+            PeerIdentity::Unix(cred) => write!(f, "{cred}"),
+        }
+    }
+}
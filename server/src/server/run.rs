@@ -6,22 +6,26 @@ impl<L> Server<L>
 where
     L: Listener + 'static,
 {
-    pub async fn run(&mut self, executor: MessageExecutor) -> anyhow::Result<()> {
-        let executor = std::sync::Arc::new(executor);
-
+    /// Serves clients until Ctrl+C. `executor` may be shared with servers on other listeners.
+    pub async fn run(&mut self, executor: std::sync::Arc<MessageExecutor>) -> anyhow::Result<()> {
         loop {
             self.join_finished_clients().await?;
 
-            let Some((client_stream, client_addr)) = self.accept_conn().await? else {
+            let Some((client_stream, peer)) = self.accept_conn().await? else {
                 return Ok(());
             };
+            let client_addr = peer.addr;
 
             let executor = executor.clone();
+            let config = self.config.clone();
 
             let handle = tokio::spawn(async move {
-                tracing::info!("Handling connection from {client_addr}");
-                let client = Client::new(client_addr, client_stream);
-                Self::handle_client(client, executor.as_ref()).await?;
+                match &peer.cert {
+                    Some(cert) => tracing::info!("Handling connection from {client_addr}, {cert}"),
+                    None => tracing::info!("Handling connection from {client_addr}"),
+                }
+                let client = Client::new(peer, client_stream);
+                Self::handle_client(client, executor.as_ref(), config.as_ref()).await?;
                 tracing::info!("Closing connection to {client_addr}");
 
                 anyhow::Ok(())
@@ -31,7 +35,7 @@ where
         }
     }
 
-    async fn accept_conn(&mut self) -> anyhow::Result<Option<(L::Stream, std::net::SocketAddr)>> {
+    async fn accept_conn(&mut self) -> anyhow::Result<Option<(L::Stream, super::Peer)>> {
         loop {
             let accept = tokio::select! {
                 _ = tokio::signal::ctrl_c() => {
@@ -43,9 +47,9 @@ where
             };
 
             match accept {
-                Ok((stream, addr)) => {
-                    tracing::debug!("Accepted connection from {addr}");
-                    return Ok(Some((stream, addr)));
+                Ok((stream, peer)) => {
+                    tracing::debug!("Accepted connection from {}", peer.addr);
+                    return Ok(Some((stream, peer)));
                 }
                 Err(err) => {
                     tracing::debug!("Error accepting connection: {err}");
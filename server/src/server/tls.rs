@@ -1,19 +1,40 @@
-use std::sync::Arc;
-
-use super::Listener;
+use std::sync::{Arc, RwLock};
 
+use sha2::Digest;
 use tokio_rustls::{rustls, TlsAcceptor};
+use x509_parser::extensions::GeneralName;
+
+use super::{CertIdentity, Listener};
 
 pub struct TlsListener<L> {
     listener: L,
-    acceptor: tokio_rustls::TlsAcceptor,
+    config: TlsConfig,
 }
 
 impl<L> TlsListener<L> {
-    pub fn new(listener: L, config: rustls::ServerConfig) -> Self {
-        let acceptor = TlsAcceptor::from(Arc::new(config));
+    /// Listener using `config`, which can be replaced while it's running.
+    pub fn new(listener: L, config: TlsConfig) -> Self {
+        Self { listener, config }
+    }
+}
+
+/// TLS settings of a listener that can be replaced, e.g. to pick up a renewed certificate.
+/// Connections already accepted keep the settings they were accepted with.
+#[derive(Clone)]
+pub struct TlsConfig(Arc<RwLock<Arc<rustls::ServerConfig>>>);
+
+impl TlsConfig {
+    pub fn new(config: rustls::ServerConfig) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// Handshakes started from now on use `config`.
+    pub fn replace(&self, config: rustls::ServerConfig) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
 
-        Self { listener, acceptor }
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.0.read().unwrap().clone())
     }
 }
 
@@ -24,10 +45,171 @@ where
 {
     type Stream = tokio_rustls::server::TlsStream<L::Stream>;
 
-    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, std::net::SocketAddr)> {
-        let (stream, addr) = self.listener.accept_conn().await?;
-        let stream = self.acceptor.accept(stream).await?;
+    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, super::Peer)> {
+        let (stream, mut peer) = self.listener.accept_conn().await?;
+        let stream = match self.config.acceptor().accept(stream).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                if is_revoked(&err) {
+                    tracing::warn!("Refused client {}, its certificate is revoked", peer.addr);
+                }
+                return Err(err.into());
+            }
+        };
+
+        // Certificate has been verified by the acceptor, the client's own one comes first
+        peer.cert = match stream.get_ref().1.peer_certificates() {
+            Some([cert, ..]) => Some(cert_identity(cert)?),
+            _ => None,
+        };
+
+        Ok((stream, peer))
+    }
+}
+
+/// Whether the handshake failed because the client's certificate is on a CRL.
+fn is_revoked(err: &std::io::Error) -> bool {
+    matches!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::Revoked
+        ))
+    )
+}
+
+/// Reads the subject's names and the fingerprint of a DER-encoded certificate.
+fn cert_identity(der: &[u8]) -> anyhow::Result<CertIdentity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)
+        .map_err(|err| anyhow::anyhow!("invalid client certificate: {err}"))?;
+
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .map(|name| name.as_str())
+        .transpose()?
+        .map(ToString::to_string);
+
+    let alt_names = match cert.subject_alternative_name()? {
+        Some(extension) => extension
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => {
+                    Some(name.to_string())
+                }
+                _ => None,
+            })
+            .collect(),
+        None => vec![],
+    };
+
+    Ok(CertIdentity {
+        common_name,
+        alt_names,
+        fingerprint: hex::encode(sha2::Sha256::digest(der)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cert_identity() {
+        let pem = include_bytes!("../../../ssl/server-localhost.crt");
+        let der = rustls_pemfile::certs(&mut pem.as_slice())
+            .next()
+            .unwrap()
+            .unwrap();
+
+        let identity = cert_identity(&der).unwrap();
+
+        assert_eq!(identity.common_name.as_deref(), Some("localhost"));
+        assert_eq!(identity.alt_names, ["localhost"]);
+        assert_eq!(
+            identity.fingerprint,
+            "b3f7ff3e8da4db47ccf86388e05262efe0a3fb262456c027be57deb80bfb3ed9"
+        );
+        assert_eq!(identity.name(), Some("localhost"));
+    }
+
+    #[test]
+    fn test_revoked_cert() {
+        use rcgen::{
+            BasicConstraints, CertificateParams, CertificateRevocationListParams, IsCa,
+            KeyIdMethod, KeyPair, KeyUsagePurpose, RevokedCertParams,
+        };
+        use rustls_pki_types::{CertificateDer, UnixTime};
+
+        let now = time::OffsetDateTime::now_utc();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let client = |serial: u64| {
+            let mut params = CertificateParams::new(vec!["alice".to_string()]).unwrap();
+            params.serial_number = Some(serial.into());
+            let cert = params
+                .signed_by(&KeyPair::generate().unwrap(), &ca, &ca_key)
+                .unwrap();
+            CertificateDer::from(cert.der().to_vec())
+        };
+        let revoked = client(2);
+        let valid = client(3);
+
+        let crl = CertificateRevocationListParams {
+            this_update: now - time::Duration::hours(1),
+            next_update: now + time::Duration::days(1),
+            crl_number: 1.into(),
+            issuing_distribution_point: None,
+            revoked_certs: vec![RevokedCertParams {
+                serial_number: 2.into(),
+                revocation_time: now - time::Duration::hours(1),
+                reason_code: None,
+                invalidity_date: None,
+            }],
+            key_identifier_method: KeyIdMethod::Sha256,
+        }
+        .signed_by(&ca, &ca_key)
+        .unwrap();
+
+        // Loaded from PEM the same way as `--crl`
+        let path = std::env::temp_dir().join(format!("server-test-{}.crl", std::process::id()));
+        std::fs::write(&path, crl.pem().unwrap()).unwrap();
+        let crls = common::tls::load_crls(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        let verifier = rustls::server::WebPkiClientVerifier::builder(roots.into())
+            .with_crls(crls)
+            .build()
+            .unwrap();
+
+        verifier
+            .verify_client_cert(&valid, &[], UnixTime::now())
+            .unwrap();
+        let err = verifier
+            .verify_client_cert(&revoked, &[], UnixTime::now())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::Revoked)
+        );
 
-        Ok((stream, addr))
+        // Handshake errors reach the listener wrapped in an I/O error
+        assert!(is_revoked(&std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            err
+        )));
+        assert!(!is_revoked(&std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::Expired)
+        )));
     }
 }
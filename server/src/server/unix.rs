@@ -0,0 +1,117 @@
+use std::{
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::{Listener, Peer, PeerAddr, UnixCred};
+
+/// Accepts connections on a Unix domain socket. Clients are identified by their peer
+/// credentials, the socket file is removed once the listener is dropped.
+pub struct UnixListener {
+    listener: tokio::net::UnixListener,
+    path: path::PathBuf,
+    next_conn: AtomicU64,
+}
+
+impl UnixListener {
+    /// Creates the socket at `path` and sets its permissions to `mode`, e.g. `0o660` to let
+    /// only the owner and group connect. A socket left at `path` by a previous run is replaced.
+    pub fn bind(path: &path::Path, mode: u32) -> anyhow::Result<Self> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => anyhow::bail!("{} exists and isn't a socket", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let listener = tokio::net::UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+            next_conn: AtomicU64::new(0),
+        })
+    }
+}
+
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::warn!("Failed to remove socket {}: {err}", self.path.display());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Listener for UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, Peer)> {
+        let (stream, _) = self.listener.accept().await?;
+        let cred = stream.peer_cred()?;
+
+        let addr = PeerAddr::Unix {
+            cred: UnixCred {
+                uid: cred.uid(),
+                pid: cred.pid(),
+            },
+            conn: self.next_conn.fetch_add(1, Ordering::Relaxed),
+        };
+
+        Ok((stream, addr.into()))
+    }
+}
+
+/// Parses permissions of the socket as an octal number, e.g. `660` or `0o660`. Only permission
+/// bits are allowed, up to `777`.
+pub fn parse_mode(arg: &str) -> anyhow::Result<u32> {
+    let digits = arg.strip_prefix("0o").unwrap_or(arg);
+
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| anyhow::anyhow!("{arg} isn't an octal mode up to 777"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_peer_credentials() {
+        let path = std::env::temp_dir().join(format!("server-test-{}.sock", std::process::id()));
+        let listener = UnixListener::bind(&path, 0o600).unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        let _first = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let _second = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (_, first) = listener.accept_conn().await.unwrap();
+        let (_, second) = listener.accept_conn().await.unwrap();
+
+        // Socket is owned by the user of this process, which is also the peer
+        let cred = UnixCred {
+            uid: metadata.uid(),
+            pid: Some(std::process::id() as i32),
+        };
+        assert_eq!(first.addr, PeerAddr::Unix { cred, conn: 0 });
+        assert_eq!(second.addr, PeerAddr::Unix { cred, conn: 1 });
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660").unwrap(), 0o660);
+        assert_eq!(parse_mode("0o777").unwrap(), 0o777);
+        assert!(parse_mode("1777").is_err());
+        assert!(parse_mode("0o4755").is_err());
+        assert!(parse_mode("680").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use tokio::time::Instant;
+
+use super::Timeouts;
+
+/// Detects clients that stopped sending frames or send them too slowly.
+///
+/// Frames are read by [`tokio_util::codec::Framed`] which doesn't say when a frame starts
+/// arriving, so the watchdog is checked periodically with whether there's a partial frame
+/// buffered. A slow frame is therefore detected within twice [`Timeouts::frame_read`].
+pub(super) struct Watchdog {
+    timeouts: Timeouts,
+    last_frame: Instant,
+    frame_started: Option<Instant>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(super) enum Timeout {
+    #[error("client didn't send anything for {0:?}")]
+    Idle(std::time::Duration),
+    #[error("client didn't finish sending a frame in {0:?}")]
+    FrameRead(std::time::Duration),
+}
+
+impl Watchdog {
+    pub fn new(timeouts: Timeouts) -> Self {
+        Self {
+            timeouts,
+            last_frame: Instant::now(),
+            frame_started: None,
+        }
+    }
+
+    /// Client sent a complete frame.
+    pub fn frame_received(&mut self) {
+        self.last_frame = Instant::now();
+        self.frame_started = None;
+    }
+
+    /// When [`Self::check`] should be called next.
+    pub fn next_check(&self) -> Instant {
+        match self.frame_started {
+            Some(started) => started + self.timeouts.frame_read,
+            None => {
+                let idle = self.last_frame + self.timeouts.idle;
+                idle.min(Instant::now() + self.timeouts.frame_read)
+            }
+        }
+    }
+
+    /// Checks whether the client has timed out. `partial_frame` tells whether part of a frame
+    /// has been received.
+    pub fn check(&mut self, partial_frame: bool) -> Result<(), Timeout> {
+        let now = Instant::now();
+
+        if !partial_frame {
+            self.frame_started = None;
+
+            return match now.duration_since(self.last_frame) >= self.timeouts.idle {
+                true => Err(Timeout::Idle(self.timeouts.idle)),
+                false => Ok(()),
+            };
+        }
+
+        let started = *self.frame_started.get_or_insert(now);
+        match now.duration_since(started) >= self.timeouts.frame_read {
+            true => Err(Timeout::FrameRead(self.timeouts.frame_read)),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn watchdog() -> Watchdog {
+        Watchdog::new(Timeouts {
+            idle: Duration::from_secs(60),
+            frame_read: Duration::from_secs(10),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle() {
+        let mut watchdog = watchdog();
+
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert!(watchdog.check(false).is_ok());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(matches!(watchdog.check(false), Err(Timeout::Idle(_))));
+
+        watchdog.frame_received();
+        assert!(watchdog.check(false).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_frame_read() {
+        let mut watchdog = watchdog();
+
+        assert!(watchdog.check(true).is_ok());
+        assert_eq!(
+            watchdog.next_check(),
+            Instant::now() + Duration::from_secs(10)
+        );
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(matches!(watchdog.check(true), Err(Timeout::FrameRead(_))));
+    }
+}
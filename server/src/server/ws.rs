@@ -0,0 +1,172 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time,
+};
+
+use super::Listener;
+
+use common::ws::WsStream;
+
+/// Default for [`WsListener::with_handshake_timeout`].
+pub const DEFAULT_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Accepts WebSocket connections on top of connections accepted by `L`. The protocol's frames
+/// are carried in binary messages, see [`WsStream`].
+pub struct WsListener<L> {
+    listener: L,
+    handshake_timeout: time::Duration,
+}
+
+impl<L> WsListener<L> {
+    pub fn new(listener: L) -> Self {
+        Self {
+            listener,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Connections whose WebSocket handshake takes longer than `timeout` fail.
+    pub fn with_handshake_timeout(mut self, timeout: time::Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl<L> Listener for WsListener<L>
+where
+    L: Listener,
+    L::Stream: 'static,
+{
+    type Stream = WsConnection<L::Stream>;
+
+    async fn accept_conn(&self) -> anyhow::Result<(Self::Stream, super::Peer)> {
+        let (stream, peer) = self.listener.accept_conn().await?;
+
+        Ok((WsConnection::new(stream, self.handshake_timeout), peer))
+    }
+}
+
+type Handshake<S> = Pin<Box<dyn Future<Output = io::Result<WsStream<S>>> + Send>>;
+
+/// WebSocket connection accepted by [`WsListener`]. The handshake isn't done while accepting, so
+/// that a slow client doesn't hold up others, it's finished the first time the connection is used
+/// by the task handling the client.
+pub struct WsConnection<S> {
+    state: State<S>,
+}
+
+enum State<S> {
+    Handshake(Handshake<S>),
+    Open(Box<WsStream<S>>),
+    Failed,
+}
+
+impl<S> WsConnection<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    fn new(stream: S, timeout: time::Duration) -> Self {
+        let handshake = async move {
+            match tokio::time::timeout(timeout, common::ws::accept_async(stream)).await {
+                Ok(Ok(stream)) => Ok(WsStream::new(stream)),
+                Ok(Err(err)) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "WebSocket handshake timed out",
+                )),
+            }
+        };
+
+        Self {
+            state: State::Handshake(Box::pin(handshake)),
+        }
+    }
+}
+
+impl<S> WsConnection<S> {
+    /// Finishes the handshake if it hasn't been done yet.
+    fn poll_open(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<&mut WsStream<S>>> {
+        if let State::Handshake(handshake) = &mut self.state {
+            match ready!(handshake.as_mut().poll(cx)) {
+                Ok(stream) => self.state = State::Open(Box::new(stream)),
+                Err(err) => {
+                    self.state = State::Failed;
+                    return Poll::Ready(Err(err));
+                }
+            }
+        }
+
+        match &mut self.state {
+            State::Open(stream) => Poll::Ready(Ok(stream)),
+            _ => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "WebSocket handshake failed",
+            ))),
+        }
+    }
+}
+
+impl<S> tokio::io::AsyncRead for WsConnection<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_open(cx))?;
+
+        Pin::new(stream).poll_read(cx, buf)
+    }
+}
+
+impl<S> tokio::io::AsyncWrite for WsConnection<S>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let stream = ready!(self.get_mut().poll_open(cx))?;
+
+        Pin::new(stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_open(cx))?;
+
+        Pin::new(stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_open(cx))?;
+
+        Pin::new(stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_timeout() {
+        use tokio::io::AsyncReadExt;
+
+        let (_client, server) = tokio::io::duplex(1024);
+        let mut conn = WsConnection::new(server, time::Duration::from_secs(5));
+
+        let err = conn.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let err = conn.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+}
@@ -0,0 +1,338 @@
+#[cfg(feature = "mtls")]
+use std::{collections::BTreeMap, path, sync::Arc, time};
+
+#[cfg(feature = "mtls")]
+use tokio_rustls::rustls;
+
+#[cfg(feature = "mtls")]
+use common::tls::KeyPassword;
+
+#[cfg(feature = "mtls")]
+use crate::{args::MtlsArgs, server::TlsConfig};
+
+/// Reloads settings of TLS listeners from their files, e.g. when a certificate is renewed.
+/// New settings are loaded completely before they replace the old ones, so a listener never
+/// uses half of them. Connections already open keep their session, new handshakes use the new
+/// settings.
+#[derive(Clone, Default)]
+pub struct TlsReloader {
+    #[cfg(feature = "mtls")]
+    listeners: Vec<Reloadable>,
+    /// Password of encrypted keys, kept so reloading doesn't ask for it again.
+    #[cfg(feature = "mtls")]
+    password: Arc<KeyPassword>,
+}
+
+/// TLS listener and the arguments its settings are loaded from.
+#[cfg(feature = "mtls")]
+#[derive(Clone)]
+struct Reloadable {
+    name: String,
+    args: MtlsArgs,
+    config: TlsConfig,
+}
+
+impl TlsReloader {
+    /// Reloads settings of all listeners. Listeners whose settings fail to load keep the
+    /// current ones. Returns how many listeners were reloaded.
+    ///
+    /// Keys needing a password that wasn't given at startup fail to load, the password isn't
+    /// asked for while the server is running.
+    pub fn reload(&self) -> anyhow::Result<usize> {
+        #[cfg(feature = "mtls")]
+        {
+            self.password.disable_prompt();
+
+            let mut failed = vec![];
+
+            for listener in &self.listeners {
+                match load(&listener.args, &self.password) {
+                    Ok((config, expiry)) => {
+                        listener.config.replace(config);
+                        record_expiry(&listener.name, expiry);
+                        tracing::info!("Reloaded TLS settings of {}", listener.name);
+                    }
+                    Err(err) => {
+                        tracing::warn!("Failed to reload TLS settings of {}: {err}", listener.name);
+                        failed.push(listener.name.as_str());
+                    }
+                }
+            }
+
+            if !failed.is_empty() {
+                anyhow::bail!("failed to reload TLS settings of {}", failed.join(", "));
+            }
+
+            Ok(self.listeners.len())
+        }
+
+        #[cfg(not(feature = "mtls"))]
+        Ok(0)
+    }
+}
+
+#[cfg(feature = "mtls")]
+impl TlsReloader {
+    /// Loads settings of listener `name` from the files in `args`. They are reloaded along with
+    /// those of other listeners.
+    pub fn load(&mut self, name: impl ToString, args: MtlsArgs) -> anyhow::Result<TlsConfig> {
+        let name = name.to_string();
+        let (config, expiry) = load(&args, &self.password)?;
+        let config = TlsConfig::new(config);
+        record_expiry(&name, expiry);
+
+        self.listeners.push(Reloadable {
+            name,
+            args,
+            config: config.clone(),
+        });
+
+        Ok(config)
+    }
+
+    /// Reloads settings whenever the server receives SIGHUP.
+    #[cfg(unix)]
+    pub async fn reload_on_hangup(self) -> anyhow::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        while hangup.recv().await.is_some() {
+            tracing::info!("Received SIGHUP, reloading TLS settings");
+            // Failures have been logged
+            let _ = self.reload();
+        }
+
+        Ok(())
+    }
+
+    /// Reloads settings when the certificate, key, CA or CRL files change, checking every
+    /// `interval`. Files are often replaced one by one, so settings are only reloaded once the
+    /// files haven't changed for a whole interval.
+    pub async fn watch(self, interval: time::Duration) {
+        self.watch_files(interval, |reloader| {
+            // Failures have been logged, files are loaded again once they change
+            let _ = reloader.reload();
+        })
+        .await
+    }
+
+    /// Calls `reload` as [`Self::watch`] reloads settings.
+    async fn watch_files(&self, interval: time::Duration, mut reload: impl FnMut(&Self)) {
+        let mut loaded = self.modified();
+        let mut previous = loaded.clone();
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let current = self.modified();
+            if current == previous && current != loaded {
+                tracing::info!("TLS files changed, reloading TLS settings");
+                reload(self);
+                loaded = current.clone();
+            }
+            previous = current;
+        }
+    }
+
+    /// Modification times of all files the settings are loaded from, `None` for missing files.
+    fn modified(&self) -> BTreeMap<path::PathBuf, Option<time::SystemTime>> {
+        self.listeners
+            .iter()
+            .flat_map(|listener| {
+                let args = &listener.args;
+                [&args.cert, &args.key, &args.ca_cert]
+                    .into_iter()
+                    .chain(&args.crls)
+            })
+            .map(|path| {
+                let modified = std::fs::metadata(path).and_then(|meta| meta.modified());
+                (path.clone(), modified.ok())
+            })
+            .collect()
+    }
+}
+
+/// Loads TLS settings from the files in `args`, along with the Unix time at which the certificate
+/// expires. The expiry is only recorded once the settings are in use, see [`record_expiry`].
+#[cfg(feature = "mtls")]
+fn load(args: &MtlsArgs, password: &KeyPassword) -> anyhow::Result<(rustls::ServerConfig, i64)> {
+    let (certs, priv_key) = common::tls::load_identity(&args.cert, &args.key, password)?;
+    let roots_store = common::tls::load_root_certs(&args.ca_cert)?;
+
+    let mut crls = vec![];
+    for path in &args.crls {
+        let loaded = common::tls::load_crls(path)?;
+        if loaded.is_empty() {
+            anyhow::bail!("no CRL found in {}", path.display());
+        }
+        crls.extend(loaded);
+    }
+
+    // `load_identity` makes sure there's a certificate
+    let expiry = not_after(&certs[0])?;
+
+    let verifier = rustls::server::WebPkiClientVerifier::builder(roots_store.into())
+        .with_crls(crls)
+        .build()?;
+
+    let tls_config = rustls::ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, priv_key)?;
+
+    Ok((tls_config, expiry))
+}
+
+/// Records when the certificate listener `name` serves expires.
+#[cfg(feature = "mtls")]
+fn record_expiry(name: &str, expiry: i64) {
+    crate::metrics::TLS_CERTIFICATE_EXPIRY
+        .with_label_values(&[name])
+        .set(expiry);
+}
+
+/// Unix time at which a DER-encoded certificate expires.
+#[cfg(feature = "mtls")]
+fn not_after(der: &[u8]) -> anyhow::Result<i64> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)
+        .map_err(|err| anyhow::anyhow!("invalid certificate: {err}"))?;
+
+    Ok(cert.validity().not_after.timestamp())
+}
+
+#[cfg(all(test, feature = "mtls"))]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watch_waits_for_files_to_settle() {
+        let dir = std::env::temp_dir().join(format!("server-test-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in ["server-localhost.crt", "server-localhost.key", "ca.crt"] {
+            std::fs::copy(path::Path::new("../ssl").join(file), dir.join(file)).unwrap();
+        }
+        let args = MtlsArgs::parse_from([
+            "server".as_ref(),
+            "--cert".as_ref(),
+            dir.join("server-localhost.crt").as_os_str(),
+            "--key".as_ref(),
+            dir.join("server-localhost.key").as_os_str(),
+            "--ca-cert".as_ref(),
+            dir.join("ca.crt").as_os_str(),
+        ]);
+        let mut reloader = TlsReloader::default();
+        reloader.load("test", args).unwrap();
+
+        let interval = time::Duration::from_secs(10);
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let watcher = tokio::spawn(async move {
+            reloader
+                .watch_files(interval, |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .await
+        });
+        // Wakes up just after each check of the watcher
+        let check = || tokio::time::sleep(interval + time::Duration::from_millis(1));
+        let touch = |file: &str, secs: u64| {
+            let modified = time::SystemTime::UNIX_EPOCH + time::Duration::from_secs(secs);
+            std::fs::File::options()
+                .write(true)
+                .open(dir.join(file))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+
+        check().await;
+        assert_eq!(reloads.load(Ordering::SeqCst), 0);
+
+        // Files replaced one by one are reloaded once they stop changing
+        touch("server-localhost.key", 1_000);
+        check().await;
+        touch("server-localhost.crt", 1_000);
+        check().await;
+        assert_eq!(reloads.load(Ordering::SeqCst), 0);
+        check().await;
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+        check().await;
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+
+        touch("ca.crt", 2_000);
+        check().await;
+        check().await;
+        assert_eq!(reloads.load(Ordering::SeqCst), 2);
+
+        watcher.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_expiry() {
+        let dir = std::env::temp_dir().join(format!("server-test-expiry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = rcgen::KeyPair::generate().unwrap();
+        let write_cert = |not_after: i64| {
+            let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            params.not_after = ::time::OffsetDateTime::from_unix_timestamp(not_after).unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            std::fs::write(dir.join("server.crt"), cert.pem()).unwrap();
+        };
+        write_cert(1_900_000_000);
+        std::fs::copy(dir.join("server.crt"), dir.join("ca.crt")).unwrap();
+        std::fs::write(dir.join("server.key"), key.serialize_pem()).unwrap();
+        let crl = dir.join("ca.crl");
+
+        let args = MtlsArgs::parse_from([
+            "server".as_ref(),
+            "--cert".as_ref(),
+            dir.join("server.crt").as_os_str(),
+            "--key".as_ref(),
+            dir.join("server.key").as_os_str(),
+            "--ca-cert".as_ref(),
+            dir.join("ca.crt").as_os_str(),
+        ]);
+        let mut reloader = TlsReloader::default();
+        reloader.load("expiry-test", args.clone()).unwrap();
+        let expiry = || {
+            crate::metrics::TLS_CERTIFICATE_EXPIRY
+                .with_label_values(&["expiry-test"])
+                .get()
+        };
+        assert_eq!(expiry(), 1_900_000_000);
+
+        // The new certificate is read, but the CRL only fails once the settings are built
+        write_cert(2_000_000_000);
+        std::fs::write(
+            &crl,
+            "-----BEGIN X509 CRL-----\nAAAA\n-----END X509 CRL-----\n",
+        )
+        .unwrap();
+        reloader.listeners[0].args.crls = vec![crl];
+        assert!(reloader.reload().is_err());
+        assert_eq!(expiry(), 1_900_000_000);
+
+        reloader.listeners[0].args.crls.clear();
+        reloader.reload().unwrap();
+        assert_eq!(expiry(), 2_000_000_000);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_not_after() {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.not_after = ::time::OffsetDateTime::from_unix_timestamp(1_900_000_000).unwrap();
+        let cert = params
+            .self_signed(&rcgen::KeyPair::generate().unwrap())
+            .unwrap();
+
+        assert_eq!(not_after(cert.der()).unwrap(), 1_900_000_000);
+        assert!(not_after(b"not a certificate").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use std::{collections::HashMap, path, sync::Mutex, time};
+
+use common::proto::request::UploadId;
+
+use crate::{
+    msg_exec::Conversation,
+    receive_file::{Checkpoint, StreamFileError},
+    server::Principal,
+};
+
+/// Resumable uploads known to the server. Unlike transfers tracked by [`crate::Client`], uploads
+/// outlive connections so that a client can continue an upload after reconnecting.
+///
+/// Uploads are kept in memory only, they can't be resumed after the server restarts. Only the
+/// client that created an upload can query or resume it, other clients are told it doesn't exist.
+pub struct UploadRegistry<H> {
+    uploads: Mutex<HashMap<UploadId, Entry<H>>>,
+}
+
+struct Entry<H> {
+    owner: Principal,
+    filename: String,
+    /// Where the file is moved once received.
+    filepath: path::PathBuf,
+    size: u64,
+    /// Where the file is posted once received.
+    conversation: Conversation,
+    state: State<H>,
+    /// When the upload was created or last suspended.
+    touched: tokio::time::Instant,
+}
+
+enum State<H> {
+    /// Data is being received by a connection.
+    Active,
+    /// Waiting for a client to resume the upload.
+    Suspended(Option<Box<Checkpoint<H>>>),
+}
+
+/// Upload taken over by a connection.
+pub struct Activated<H> {
+    pub filename: String,
+    pub filepath: path::PathBuf,
+    pub size: u64,
+    pub conversation: Conversation,
+    pub checkpoint: Option<Checkpoint<H>>,
+}
+
+impl<H> Default for UploadRegistry<H> {
+    fn default() -> Self {
+        Self {
+            uploads: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<H> UploadRegistry<H> {
+    /// Registers a new upload of `size` bytes into `filepath` posted to `conversation` by `owner`.
+    /// The upload is received into a file of its own until it's finished.
+    pub fn create(
+        &self,
+        owner: Principal,
+        filename: String,
+        filepath: path::PathBuf,
+        size: u64,
+        conversation: Conversation,
+    ) -> UploadId {
+        let id = UploadId::new_v4();
+        let entry = Entry {
+            owner,
+            filename,
+            filepath,
+            size,
+            conversation,
+            state: State::Suspended(None),
+            touched: tokio::time::Instant::now(),
+        };
+
+        self.lock().insert(id, entry);
+
+        id
+    }
+
+    /// Announced size of upload `id` created by `owner`.
+    pub fn get(&self, id: UploadId, owner: &Principal) -> Result<u64, StreamFileError> {
+        self.lock()
+            .get(&id)
+            .filter(|entry| entry.owner == *owner)
+            .map(|entry| entry.size)
+            .ok_or(StreamFileError::UnknownUpload(id))
+    }
+
+    /// Marks upload `id` created by `owner` as being received so that no other connection can
+    /// resume it.
+    pub fn activate(
+        &self,
+        id: UploadId,
+        owner: &Principal,
+    ) -> Result<Activated<H>, StreamFileError> {
+        let mut uploads = self.lock();
+        let entry = uploads
+            .get_mut(&id)
+            .filter(|entry| entry.owner == *owner)
+            .ok_or(StreamFileError::UnknownUpload(id))?;
+
+        let State::Suspended(checkpoint) = std::mem::replace(&mut entry.state, State::Active)
+        else {
+            return Err(StreamFileError::UploadBusy(id));
+        };
+
+        Ok(Activated {
+            filename: entry.filename.clone(),
+            filepath: entry.filepath.clone(),
+            size: entry.size,
+            conversation: entry.conversation.clone(),
+            checkpoint: checkpoint.map(|checkpoint| *checkpoint),
+        })
+    }
+
+    /// Lets upload `id` be resumed again. Without a checkpoint, the persisted part of the file
+    /// is read again when resuming.
+    pub fn suspend(&self, id: UploadId, checkpoint: Option<Checkpoint<H>>) {
+        if let Some(entry) = self.lock().get_mut(&id) {
+            entry.state = State::Suspended(checkpoint.map(Box::new));
+            entry.touched = tokio::time::Instant::now();
+        }
+    }
+
+    /// Forgets uploads that haven't been resumed for `ttl`, returns their IDs so that their
+    /// partially received files can be removed. Uploads being received don't expire.
+    pub fn expire(&self, ttl: time::Duration) -> Vec<UploadId> {
+        let mut expired = Vec::new();
+
+        self.lock().retain(|id, entry| {
+            let keep = matches!(entry.state, State::Active) || entry.touched.elapsed() < ttl;
+            if !keep {
+                tracing::info!("Upload {id} of {} expired", entry.filename);
+                expired.push(*id);
+            }
+
+            keep
+        });
+
+        expired
+    }
+
+    /// Forgets upload `id` once it's finished or aborted.
+    pub fn remove(&self, id: UploadId) {
+        self.lock().remove(&id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<UploadId, Entry<H>>> {
+        self.uploads.lock().expect("poisoned lock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(registry: &UploadRegistry<sha2::Sha256>, owner: Principal) -> UploadId {
+        registry.create(
+            owner,
+            "file.txt".to_string(),
+            path::PathBuf::from("files/file.txt"),
+            6,
+            Conversation::default(),
+        )
+    }
+
+    #[test]
+    fn test_upload_owner() {
+        let registry = UploadRegistry::<sha2::Sha256>::default();
+        let owner = Principal::Cert("aa".to_string());
+        let other = Principal::Cert("bb".to_string());
+        let id = create(&registry, owner.clone());
+
+        assert!(matches!(
+            registry.get(id, &other),
+            Err(StreamFileError::UnknownUpload(_))
+        ));
+        assert!(matches!(
+            registry.activate(id, &other),
+            Err(StreamFileError::UnknownUpload(_))
+        ));
+
+        assert_eq!(registry.get(id, &owner).unwrap(), 6);
+        registry.activate(id, &owner).unwrap();
+        assert!(matches!(
+            registry.activate(id, &owner),
+            Err(StreamFileError::UploadBusy(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_upload_expire() {
+        let ttl = time::Duration::from_secs(60);
+        let registry = UploadRegistry::<sha2::Sha256>::default();
+        let owner = Principal::UnixUser(1000);
+        let suspended = create(&registry, owner.clone());
+        let active = create(&registry, owner.clone());
+        registry.activate(active, &owner).unwrap();
+
+        tokio::time::advance(ttl / 2).await;
+        assert!(registry.expire(ttl).is_empty());
+
+        tokio::time::advance(ttl / 2).await;
+        assert_eq!(registry.expire(ttl), vec![suspended]);
+        assert!(registry.get(suspended, &owner).is_err());
+
+        // Suspending restarts the countdown.
+        registry.suspend(active, None);
+        tokio::time::advance(ttl / 2).await;
+        assert!(registry.expire(ttl).is_empty());
+        assert!(registry.get(active, &owner).is_ok());
+    }
+}
@@ -55,7 +55,11 @@ async fn spec_json_handler() -> impl actix_web::Responder {
         endpoints::get_messages::handler,
         endpoints::delete_messages::handler,
         endpoints::download::handler,
+        endpoints::get_thread::handler,
+        endpoints::view_thread::handler,
+        endpoints::get_presence::handler,
         endpoints::get_metrics::handler,
+        endpoints::reload_tls::handler,
     ),
     components(schemas(DeleteParams))
 )]
@@ -14,6 +14,7 @@ use crate::web::Error;
 /// - `messages_received_bytes`: Total number of bytes received when handling messages.
 /// - `messages_sent_bytes`: Total number of bytes sent when handling messages.
 /// - `active_connections`: Number of active connections to the server.
+/// - `tls_certificate_expiry_timestamp_seconds`: Unix time at which the certificate of a TLS listener expires, labeled by `listener`.
 #[utoipa::path(
     responses(
         (
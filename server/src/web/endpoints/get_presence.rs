@@ -0,0 +1,95 @@
+use actix_web::get;
+
+use crate::presence::Presence;
+use crate::web::Error;
+
+/// Get who is online, idle or offline and when each nickname was last seen.
+#[utoipa::path(
+    responses(
+        (
+            status = actix_web::http::StatusCode::OK,
+            description = actix_web::http::StatusCode::OK.to_string(),
+            content_type = actix_web::http::header::ContentType::html(),
+        ),
+    ),
+    operation_id = "get_presence",
+)]
+#[tracing::instrument(skip(presence))]
+#[get("/presence")]
+pub async fn handler(
+    presence: actix_web::web::Data<Presence>,
+) -> Result<impl actix_web::Responder, Error> {
+    render(&presence).map_err(Error::internal)
+}
+
+fn render(presence: &Presence) -> anyhow::Result<actix_web::web::Html> {
+    let mut tera = tera::Tera::default();
+    tera.add_raw_template("presence.html", TEMPLATE)?;
+
+    let mut context = tera::Context::new();
+    context.insert("users", &presence.snapshot());
+    let result = tera.render("presence.html", &context)?;
+
+    Ok(actix_web::web::Html::new(result))
+}
+
+const TEMPLATE: &str = r#"
+<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta http-equiv="X-UA-Compatible" content="IE=edge">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Presence</title>
+    <style>
+        table {
+            width: 100%;
+            border-collapse: collapse;
+        }
+
+        th, td {
+            border: 1px solid #dddddd;
+            padding: 8px;
+            text-align: left;
+        }
+
+        th {
+            background-color: #f2f2f2;
+        }
+
+        .Online {
+            color: #2e7d32;
+        }
+
+        .Idle {
+            color: #b26a00;
+        }
+
+        .Offline {
+            color: #777777;
+        }
+    </style>
+</head>
+<body>
+    <h1>Presence ({{ users | filter(attribute="status", value="Online") | length }} online)</h1>
+    <a href="/">See messages</a>
+    <table>
+        <thead>
+            <tr>
+                <th>User</th>
+                <th>Status</th>
+                <th>Last seen</th>
+            </tr>
+        </thead>
+        <tbody>
+            {% for user in users %}
+            <tr>
+                <td>{{ user.nickname }}</td>
+                <td class="{{ user.status }}">{{ user.status }}</td>
+                <td>{{ user.last_seen }}</td>
+            </tr>
+            {% endfor %}
+        </tbody>
+    </table>
+</body>
+"#;
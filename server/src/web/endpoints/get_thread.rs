@@ -0,0 +1,62 @@
+use actix_web::get;
+use uuid::Uuid;
+
+use crate::db::{Message, MessageFile};
+use crate::web::Error;
+
+/// Get a thread of messages: the message it started with and all replies, oldest first.
+///
+/// Any message of the thread can be used to find it. Replies to a message say so in `reply_to`.
+#[utoipa::path(
+    responses(
+        (
+            status = actix_web::http::StatusCode::OK,
+            description = actix_web::http::StatusCode::OK.to_string(),
+            content_type = "application/json",
+        ),
+        (
+            status = actix_web::http::StatusCode::NOT_FOUND,
+            description = "Message doesn't exist",
+        ),
+    ),
+    params(
+        ("id" = Uuid, description = "ID of any message in the thread"),
+    ),
+    operation_id = "get_thread",
+)]
+#[tracing::instrument(skip(repo))]
+#[get("/thread/{id}")]
+pub async fn handler(
+    path: actix_web::web::Path<Uuid>,
+    repo: actix_web::web::Data<Box<dyn crate::web::Repository>>,
+) -> Result<impl actix_web::Responder, Error> {
+    let id = path.into_inner();
+
+    let thread = repo.get_thread(id).await.map_err(Error::internal)?;
+
+    let Some(thread) = thread else {
+        return Ok(actix_web::Either::Left((
+            "message doesn't exist",
+            actix_web::http::StatusCode::NOT_FOUND,
+        )));
+    };
+
+    let thread: Vec<_> = thread
+        .into_iter()
+        .map(|(message, text, file)| ThreadMessage {
+            message,
+            text: text.map(|text| text.text),
+            file,
+        })
+        .collect();
+
+    Ok(actix_web::Either::Right(actix_web::web::Json(thread)))
+}
+
+#[derive(serde::Serialize)]
+struct ThreadMessage {
+    #[serde(flatten)]
+    message: Message,
+    text: Option<String>,
+    file: Option<MessageFile>,
+}
@@ -6,6 +6,10 @@ pub mod delete_messages;
 pub mod download;
 pub mod get_messages;
 pub mod get_metrics;
+pub mod get_presence;
+pub mod get_thread;
+pub mod reload_tls;
+pub mod view_thread;
 
 pub async fn render_table(
     repo: &dyn Repository,
@@ -13,15 +17,18 @@ pub async fn render_table(
     args: &ServerArgs,
 ) -> anyhow::Result<actix_web::web::Html> {
     let username = query.username.clone().filter(|s| !s.is_empty());
+    let room = query.room.clone().filter(|s| !s.is_empty());
     let messages = repo
-        .get_messages(username, query.offset, query.limit)
+        .get_messages(username, room, query.offset, query.limit)
         .await?;
+    let revisions = get_revisions(repo, &messages).await?;
 
     let mut tera = tera::Tera::default();
     tera.add_raw_template("index.html", TEMPLATE)?;
 
     let mut context = tera::Context::new();
     context.insert("messages", &messages);
+    context.insert("revisions", &revisions);
     context.insert("last_query", &query);
     context.insert("docs_enabled", &!args.web.disable_docs);
     let result = tera.render("index.html", &context)?;
@@ -29,11 +36,34 @@ pub async fn render_table(
     Ok(actix_web::web::Html::new(result))
 }
 
-use std::num::NonZeroUsize;
+/// Previous texts of each of `messages`, in the same order.
+async fn get_revisions(
+    repo: &dyn Repository,
+    messages: &[super::FullMessage],
+) -> anyhow::Result<Vec<Vec<crate::db::MessageTextRevision>>> {
+    let ids = messages
+        .iter()
+        .map(|(message, _, _)| message.public_id)
+        .collect();
+
+    let mut revisions = HashMap::<_, Vec<_>>::new();
+    for (id, revision) in repo.get_revisions(ids).await? {
+        revisions.entry(id).or_default().push(revision);
+    }
+
+    Ok(messages
+        .iter()
+        .map(|(message, _, _)| revisions.remove(&message.public_id).unwrap_or_default())
+        .collect())
+}
+
+use std::{collections::HashMap, num::NonZeroUsize};
 
 #[derive(Debug, serde::Deserialize, serde::Serialize, utoipa::IntoParams)]
 pub struct SearchParams {
     pub username: Option<String>,
+    /// Only messages sent to this room.
+    pub room: Option<String>,
     // `utoipa` doesn't handle non zero types yet
     #[param(default = 20, value_type = usize, minimum = 1)]
     #[serde(default = "get_default_limit")]
@@ -47,6 +77,7 @@ impl Default for SearchParams {
     fn default() -> Self {
         Self {
             username: None,
+            room: None,
             limit: get_default_limit(),
             offset: 0,
         }
@@ -81,6 +112,11 @@ const TEMPLATE: &str = r#"
             background-color: #f2f2f2;
         }
         
+        .edited {
+            color: #777777;
+            font-size: small;
+        }
+
         .hash {
             font-family: monospace;
             max-width: 100px; 
@@ -91,6 +127,7 @@ const TEMPLATE: &str = r#"
 </head>
 <body>
     <h1>Messages ({{ messages | length }})</h1>
+    <a href="/presence">See who is online</a>
     {% if docs_enabled %}
         <a href="/_docs/redoc">See API documentation</a>
     {% endif %}
@@ -103,6 +140,14 @@ const TEMPLATE: &str = r#"
             {% if last_query.username %} value="{{ last_query.username }}" {% endif %}
         >
 
+        <label for="room">Room:</label>
+        <input
+            type="text"
+            id="room"
+            name="room"
+            {% if last_query.room %} value="{{ last_query.room }}" {% endif %}
+        >
+
         <label for="limit">Limit:</label>
         <input type="number" id="limit" name="limit" min="1" value="{{ last_query.limit }}">
 
@@ -116,13 +161,15 @@ const TEMPLATE: &str = r#"
             <tr>
                 <th>Timestamp</th>
                 <th>User</th>
-                <th>IP</th>
+                <th>Room</th>
+                <th>Peer</th>
                 <th>Message</th>
                 <th>Filename</th>
                 <th>Filesize</th>
                 <th>Mime</th>
                 <th>SHA256</th>
                 <th>Filelink</th>
+                <th>Thread</th>
                 <th>Actions</th>
             </tr>
         </thead>
@@ -131,10 +178,30 @@ const TEMPLATE: &str = r#"
             <tr>
                 <td>{{ message.0.timestamp }}</td>
                 <td>{{ message.0.user_nickname }}</td>
-                <td>{{ message.0.user_ip }}</td>
+                <td>
+                    {% if message.0.room %}
+                        #{{ message.0.room }}
+                    {% endif %}
+                </td>
+                <td
+                    {% if message.0.cert_fingerprint %}
+                        title="Certificate SHA-256 {{ message.0.cert_fingerprint }}"
+                    {% endif %}
+                >{{ message.0.peer }}</td>
                 <td>
                     {% if message.1 %}
                         {{ message.1.text }}
+                        {% set message_revisions = revisions[loop.index0] %}
+                        {% if message_revisions %}
+                            <details class="edited">
+                                <summary>edited</summary>
+                                <ol>
+                                    {% for revision in message_revisions %}
+                                        <li>{{ revision.text }} (replaced {{ revision.replaced_at }})</li>
+                                    {% endfor %}
+                                </ol>
+                            </details>
+                        {% endif %}
                     {% endif %}
                 </td>
                 <td>
@@ -164,6 +231,17 @@ const TEMPLATE: &str = r#"
                         <a href="/download/{{ message.0.public_id }}" target="_blank">Download</a>
                     {% endif %}
                 </td>
+                <td>
+                    {% if message.0.reply_to %}
+                        <a
+                            href="/thread/{{ message.0.public_id }}/view"
+                            target="_blank"
+                            title="Reply to {{ message.0.reply_to }}"
+                        >&#8627; Reply</a>
+                    {% else %}
+                        <a href="/thread/{{ message.0.public_id }}/view" target="_blank">Thread</a>
+                    {% endif %}
+                </td>
                 <td>
                     <form action="delete" method="post">
                         <input type="hidden" name="id" value="{{ message.0.public_id }}">
@@ -186,6 +264,7 @@ mod tests {
         let params = serde_json::from_str::<SearchParams>("{}").unwrap();
 
         assert_eq!(params.username, None);
+        assert_eq!(params.room, None);
         assert_eq!(params.limit.get(), get_default_limit().get());
         assert_eq!(params.limit.get(), 20);
         assert_eq!(params.offset, 0);
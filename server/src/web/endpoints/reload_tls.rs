@@ -0,0 +1,81 @@
+use actix_web::post;
+
+use crate::tls_reload::TlsReloader;
+use crate::web::Error;
+
+/// Reload certificates, keys, CAs and CRLs of TLS listeners from their files.
+///
+/// Connections already open keep their session, new handshakes use the reloaded settings.
+/// Listeners whose settings fail to load keep the current ones. Only requests from the machine
+/// the server runs on are allowed.
+///
+/// Behind a reverse proxy on the same machine, every request comes from a loopback address, so
+/// the check can't tell who sent it. Don't pass this endpoint through such a proxy.
+#[utoipa::path(
+    responses(
+        (
+            status = actix_web::http::StatusCode::OK,
+            description = "Settings have been reloaded. Returning how many listeners were reloaded.",
+        ),
+        (
+            status = actix_web::http::StatusCode::FORBIDDEN,
+            description = "Request didn't come from a loopback address.",
+        ),
+        (
+            status = actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            description = "Settings of some listeners failed to load.",
+        )
+    ),
+    operation_id = "reload_tls",
+)]
+#[tracing::instrument(skip(req, reloader))]
+#[post("/tls/reload")]
+pub async fn handler(
+    req: actix_web::HttpRequest,
+    reloader: actix_web::web::Data<TlsReloader>,
+) -> Result<impl actix_web::Responder, Error> {
+    // The web server has no authentication, only local administrators may reload
+    if !req.peer_addr().is_some_and(|addr| addr.ip().is_loopback()) {
+        return Ok(actix_web::Either::Left((
+            "TLS settings can only be reloaded from localhost",
+            actix_web::http::StatusCode::FORBIDDEN,
+        )));
+    }
+
+    // Files are read and parsed synchronously
+    let reloaded = actix_web::web::block(move || reloader.reload())
+        .await
+        .map_err(Error::internal)?
+        .map_err(Error::internal)?;
+
+    Ok(actix_web::Either::Right(format!(
+        "Reloaded TLS settings of {reloaded} listeners"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_reload_from_localhost_only() {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(actix_web::web::Data::new(TlsReloader::default()))
+                .service(handler),
+        )
+        .await;
+        let reload = |peer: &str| {
+            actix_web::test::TestRequest::post()
+                .uri("/tls/reload")
+                .peer_addr(peer.parse().unwrap())
+                .to_request()
+        };
+
+        let local = actix_web::test::call_service(&app, reload("127.0.0.1:40000")).await;
+        let remote = actix_web::test::call_service(&app, reload("10.0.0.1:40000")).await;
+
+        assert_eq!(local.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(remote.status(), actix_web::http::StatusCode::FORBIDDEN);
+    }
+}
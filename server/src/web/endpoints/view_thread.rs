@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::get;
+use uuid::Uuid;
+
+use crate::web::{Error, FullMessage};
+
+/// View a thread of messages as a page, replies are nested under the message they reply to.
+///
+/// Any message of the thread can be used to find it, see `get_thread` for the same thread as JSON.
+#[utoipa::path(
+    responses(
+        (
+            status = actix_web::http::StatusCode::OK,
+            description = actix_web::http::StatusCode::OK.to_string(),
+            content_type = actix_web::http::header::ContentType::html(),
+        ),
+        (
+            status = actix_web::http::StatusCode::NOT_FOUND,
+            description = "Message doesn't exist",
+        ),
+    ),
+    params(
+        ("id" = Uuid, description = "ID of any message in the thread"),
+    ),
+    operation_id = "view_thread",
+)]
+#[tracing::instrument(skip(repo))]
+#[get("/thread/{id}/view")]
+pub async fn handler(
+    path: actix_web::web::Path<Uuid>,
+    repo: actix_web::web::Data<Box<dyn crate::web::Repository>>,
+) -> Result<impl actix_web::Responder, Error> {
+    let id = path.into_inner();
+
+    let thread = repo.get_thread(id).await.map_err(Error::internal)?;
+
+    let Some(thread) = thread else {
+        return Ok(actix_web::Either::Left((
+            "message doesn't exist",
+            actix_web::http::StatusCode::NOT_FOUND,
+        )));
+    };
+
+    let page = render(id, thread).map_err(Error::internal)?;
+
+    Ok(actix_web::Either::Right(page))
+}
+
+fn render(id: Uuid, thread: Vec<FullMessage>) -> anyhow::Result<actix_web::web::Html> {
+    let links: Vec<_> = thread
+        .iter()
+        .map(|(message, _, _)| (message.public_id, message.reply_to))
+        .collect();
+    let mut thread: Vec<_> = thread.into_iter().map(Some).collect();
+    let entries: Vec<_> = nest(&links)
+        .into_iter()
+        .filter_map(|(index, depth)| Some((depth, thread[index].take()?)))
+        .collect();
+
+    let mut tera = tera::Tera::default();
+    tera.add_raw_template("thread.html", TEMPLATE)?;
+
+    let mut context = tera::Context::new();
+    context.insert("id", &id);
+    context.insert("entries", &entries);
+    let result = tera.render("thread.html", &context)?;
+
+    Ok(actix_web::web::Html::new(result))
+}
+
+/// Orders messages of a thread given as `(id, reply_to)` so that replies follow the message they
+/// reply to, oldest first. Returns indices into `links` with how deep each message is nested.
+/// Replies whose parent isn't in the thread, e.g. because it was retracted, start at the top.
+fn nest(links: &[(Uuid, Option<Uuid>)]) -> Vec<(usize, usize)> {
+    let ids: HashSet<_> = links.iter().map(|(id, _)| *id).collect();
+
+    let mut roots = Vec::new();
+    let mut replies = HashMap::<_, Vec<_>>::new();
+    for (index, (_, parent)) in links.iter().enumerate() {
+        match parent.filter(|parent| ids.contains(parent)) {
+            Some(parent) => replies.entry(parent).or_default().push(index),
+            None => roots.push(index),
+        }
+    }
+
+    let mut nested = Vec::with_capacity(links.len());
+    let mut stack: Vec<_> = roots.into_iter().rev().map(|index| (index, 0)).collect();
+    while let Some((index, depth)) = stack.pop() {
+        nested.push((index, depth));
+
+        let children = replies.remove(&links[index].0).unwrap_or_default();
+        stack.extend(children.into_iter().rev().map(|child| (child, depth + 1)));
+    }
+
+    nested
+}
+
+const TEMPLATE: &str = r#"
+<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta http-equiv="X-UA-Compatible" content="IE=edge">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Thread</title>
+    <style>
+        .message {
+            border-left: 2px solid #dddddd;
+            margin: 8px 0;
+            padding: 4px 8px;
+        }
+
+        .selected {
+            border-left-color: #1565c0;
+        }
+
+        .meta {
+            color: #777777;
+            font-size: small;
+        }
+    </style>
+</head>
+<body>
+    <h1>Thread ({{ entries | length }})</h1>
+    <a href="/">See messages</a>
+    <a href="/thread/{{ id }}">See as JSON</a>
+    {% for entry in entries %}
+        {% set message = entry.1 %}
+        <div
+            class="message {% if message.0.public_id == id %}selected{% endif %}"
+            style="margin-left: {{ entry.0 * 2 }}em"
+        >
+            <div class="meta">
+                {{ message.0.user_nickname }}
+                {% if message.0.room %}
+                    in #{{ message.0.room }}
+                {% endif %}
+                at {{ message.0.timestamp }}
+            </div>
+            {% if message.1 %}
+                <div>{{ message.1.text }}</div>
+            {% endif %}
+            {% if message.2 %}
+                <div>
+                    <a href="/download/{{ message.0.public_id }}" target="_blank">{{ message.2.filename }}</a>
+                    ({{ message.2.length | filesizeformat }})
+                </div>
+            {% endif %}
+        </div>
+    {% endfor %}
+</body>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nest() {
+        let ids: Vec<_> = (0..5).map(|_| Uuid::new_v4()).collect();
+        let missing = Uuid::new_v4();
+        // Oldest first, as the repository returns them
+        let links = [
+            (ids[0], None),
+            (ids[1], Some(ids[0])),
+            (ids[2], Some(ids[0])),
+            (ids[3], Some(ids[1])),
+            (ids[4], Some(missing)),
+        ];
+
+        assert_eq!(nest(&links), [(0, 0), (1, 1), (3, 2), (2, 1), (4, 0)]);
+    }
+}
@@ -1,4 +1,5 @@
 mod config;
+#[allow(unused_imports)]
 pub use config::{Config, DEFAULT_WEB_SERVER_ADDRESS};
 
 mod docs;
@@ -8,11 +9,16 @@ mod error;
 pub use error::Error;
 
 mod repo;
-pub use repo::{FullMessage, Repository};
+pub use repo::{FullMessage, MessageNotFound, Repository};
 
-use crate::args::ServerArgs;
+use crate::{args::ServerArgs, presence::Presence, tls_reload::TlsReloader};
 
-pub async fn run(args: &ServerArgs, repo: impl Repository) -> anyhow::Result<()> {
+pub async fn run(
+    args: &ServerArgs,
+    repo: impl Repository,
+    presence: Presence,
+    tls_reloader: TlsReloader,
+) -> anyhow::Result<()> {
     let arc_args = std::sync::Arc::new(args.clone());
     let arc_repo: std::sync::Arc<Box<dyn Repository>> = std::sync::Arc::new(Box::new(repo));
 
@@ -35,9 +41,15 @@ pub async fn run(args: &ServerArgs, repo: impl Repository) -> anyhow::Result<()>
             })
             .app_data(actix_web::web::Data::from(repo))
             .app_data(actix_web::web::Data::from(arc_args.clone()))
+            .app_data(actix_web::web::Data::new(presence.clone()))
+            .app_data(actix_web::web::Data::new(tls_reloader.clone()))
             .service(endpoints::get_messages::handler)
             .service(endpoints::download::handler)
+            .service(endpoints::get_thread::handler)
+            .service(endpoints::view_thread::handler)
+            .service(endpoints::get_presence::handler)
             .service(endpoints::delete_messages::handler)
+            .service(endpoints::reload_tls::handler)
             .service(endpoints::get_metrics::handler);
 
         if !arc_args.web.disable_docs {
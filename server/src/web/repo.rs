@@ -1,23 +1,99 @@
 use std::num::NonZeroUsize;
 
-use crate::db::{Message, MessageFile, MessageText};
+use common::proto::request::HistoryPosition;
+
+use crate::db::{Message, MessageFile, MessageText, MessageTextRevision};
 
 pub type FullMessage = (Message, Option<MessageText>, Option<MessageFile>);
 
+/// Message a lookup is relative to doesn't exist.
+#[derive(Debug, thiserror::Error)]
+#[error("Message {0} doesn't exist")]
+pub struct MessageNotFound(pub uuid::Uuid);
+
 #[async_trait::async_trait]
 pub trait Repository: Sync + Send + 'static {
+    /// Messages other than direct ones, newest first. Only messages sent to `room` if it's given.
     async fn get_messages(
         &self,
         username: Option<String>,
+        room: Option<String>,
         offset: usize,
         limit: NonZeroUsize,
     ) -> anyhow::Result<Vec<FullMessage>>;
 
+    /// Up to `limit` messages at `position` in the history of `room`, or of messages sent to
+    /// everyone, oldest first. Fails with [`MessageNotFound`] if the message `position` refers to
+    /// doesn't exist.
+    async fn get_history(
+        &self,
+        username: Option<String>,
+        room: Option<String>,
+        position: HistoryPosition,
+        limit: NonZeroUsize,
+    ) -> anyhow::Result<Vec<FullMessage>>;
+
+    /// Message that started the thread `public_id` is in and all replies in the thread, oldest
+    /// first. Direct and retracted messages are left out. `None` if the message doesn't exist.
+    async fn get_thread(&self, public_id: uuid::Uuid) -> anyhow::Result<Option<Vec<FullMessage>>>;
+
+    /// Whether any stored message was sent by `username`.
+    async fn has_nickname(&self, username: &str) -> anyhow::Result<bool>;
+
+    /// Message `public_id`, `None` if it doesn't exist or was retracted.
     async fn get_message_by_public_id(
         &self,
         public_id: uuid::Uuid,
     ) -> anyhow::Result<Option<FullMessage>>;
 
+    /// Replaces the text of message `public_id`, its previous text is kept as a revision
+    /// replaced at `edited_at`. Returns `false` if the message doesn't exist or has no text.
+    async fn edit_text(
+        &self,
+        public_id: uuid::Uuid,
+        text: String,
+        edited_at: chrono::NaiveDateTime,
+    ) -> anyhow::Result<bool>;
+
+    /// Previous texts of messages `public_ids`, oldest first.
+    async fn get_revisions(
+        &self,
+        public_ids: Vec<uuid::Uuid>,
+    ) -> anyhow::Result<Vec<(uuid::Uuid, MessageTextRevision)>>;
+
+    /// Remembers that `nickname` was last seen at `at`.
+    async fn record_presence(
+        &self,
+        nickname: String,
+        at: chrono::NaiveDateTime,
+    ) -> anyhow::Result<()>;
+
+    /// When each recorded nickname was last seen.
+    async fn get_last_seen(&self) -> anyhow::Result<Vec<(String, chrono::NaiveDateTime)>>;
+
+    /// Records that direct message `public_id` reached its recipient at `at`.
+    async fn mark_delivered(
+        &self,
+        public_id: uuid::Uuid,
+        at: chrono::NaiveDateTime,
+    ) -> anyhow::Result<()>;
+
+    /// Direct messages for `nickname` that haven't reached it yet, oldest first. They are marked
+    /// as delivered at `at`.
+    async fn take_undelivered(
+        &self,
+        nickname: String,
+        at: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Vec<FullMessage>>;
+
+    /// Hides message `public_id` as retracted at `at`, keeping it with its revisions and file.
+    /// Returns whether it wasn't retracted already.
+    async fn retract(
+        &self,
+        public_id: uuid::Uuid,
+        at: chrono::NaiveDateTime,
+    ) -> anyhow::Result<bool>;
+
     async fn delete_by_ids(&self, ids: Vec<uuid::Uuid>) -> anyhow::Result<()>;
 
     async fn delete_by_username(&self, username: String) -> anyhow::Result<()>;